@@ -0,0 +1,58 @@
+#!/bin/sh
+# Times `backup` creating archives of a generated tree.
+#
+#   scripts/bench-create.sh <backup-binary> <work-dir> [size-gib]
+#
+# The tree holds many small files and a few large ones, half random and half
+# compressible data. Make it larger than RAM to measure reads from disk
+# rather than from the page cache.
+set -eu
+
+bin=$1
+work=$2
+size_gib=${3:-6}
+tree=$work/tree
+out=$work/out
+
+if [ ! -d "$tree" ]; then
+    mkdir -p "$tree"
+    # 20000 small files of 4-68 KiB in 200 directories
+    i=0
+    while [ $i -lt 200 ]; do
+        mkdir -p "$tree/small/$i"
+        j=0
+        while [ $j -lt 100 ]; do
+            head -c $(( (i * 100 + j) % 64 * 1024 + 4096 )) /dev/urandom > "$tree/small/$i/$j"
+            j=$((j + 1))
+        done
+        i=$((i + 1))
+    done
+    # the rest in files of 256 MiB
+    mkdir -p "$tree/large"
+    n=$(( size_gib * 4 ))
+    i=0
+    while [ $i -lt $n ]; do
+        if [ $((i % 2)) -eq 0 ]; then
+            head -c 268435456 /dev/urandom > "$tree/large/$i"
+        else
+            yes "backup benchmark line $i" | head -c 268435456 > "$tree/large/$i"
+        fi
+        i=$((i + 1))
+    done
+fi
+
+run() {
+    rm -rf "$out"
+    mkdir -p "$out"
+    sync
+    # dropping the page cache needs root; without it later runs read cached data
+    echo 3 > /proc/sys/vm/drop_caches 2>/dev/null || true
+    start=$(date +%s.%N)
+    "$bin" "$tree" "$out" "$@" > /dev/null
+    end=$(date +%s.%N)
+    awk -v s="$start" -v e="$end" -v m="${*:-no compression}" 'BEGIN { printf "%-24s %6.1fs\n", m, e - s }'
+}
+
+run
+run --compress zstd
+run --compress lz4
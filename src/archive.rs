@@ -0,0 +1,670 @@
+use crate::compress::{self, BLOCK_SIZE, Codec, Compression};
+use crate::crypto::{self, Credentials, DecryptReader};
+use ring::digest;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const MAGIC: &[u8; 4] = b"BKUP";
+pub const INDEX_MAGIC: &[u8; 4] = b"BKIX";
+pub const SIGNATURE_MAGIC: &[u8; 4] = b"BKSG";
+pub const FORMAT_VERSION: u32 = 4;
+
+/// index offset (u64) + entry count (u64) + index magic
+pub const TRAILER_LEN: u64 = 8 + 8 + 4;
+
+/// signature magic + Ed25519 public key + signature
+pub const SIGNATURE_LEN: u64 = 4 + 32 + 64;
+
+#[derive(Debug, Clone)]
+pub struct EntryHeader {
+    pub path: String,
+    pub size: u64,
+    pub hash: [u8; 32],
+    pub codec: Codec,
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub path: String,
+    pub offset: u64,
+    pub size: u64,
+    pub hash: [u8; 32],
+}
+
+/// Ed25519 signature over the manifest and every entry header, stored
+/// between the index and the trailer of a signed archive.
+#[derive(Debug, Clone)]
+pub struct ArchiveSignature {
+    pub public_key: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(msg: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_path<R: Read>(reader: &mut R, len: usize) -> io::Result<String> {
+    let mut path_bytes = vec![0u8; len];
+    reader.read_exact(&mut path_bytes)?;
+    String::from_utf8(path_bytes)
+        .map_err(|e| invalid_data(format!("invalid UTF-8 path in archive: {e}")))
+}
+
+/// Writes the archive header. Since version 4 the manifest follows the
+/// entries, so the header is only the magic and the format version.
+pub fn write_header<W: Write>(writer: &mut W) -> io::Result<u64> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    Ok(4 + 4)
+}
+
+/// Reads until `buf` is full or the reader is exhausted.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn write_block<W: Write>(writer: &mut W, raw: &[u8], compressed: &[u8]) -> io::Result<u64> {
+    // A block that did not get smaller is stored raw, which readers detect
+    // from the stored length being equal to the raw length.
+    let stored = if compressed.len() < raw.len() {
+        compressed
+    } else {
+        raw
+    };
+    writer.write_all(&(raw.len() as u32).to_le_bytes())?;
+    writer.write_all(&(stored.len() as u32).to_le_bytes())?;
+    writer.write_all(stored)?;
+    Ok(4 + 4 + stored.len() as u64)
+}
+
+/// Part of an entry's data as it is stored, see [`encode_entry`].
+pub enum EntryPiece {
+    /// how the data is stored, always the first piece
+    Codec(Codec),
+    /// stored bytes holding `raw_len` bytes of the file
+    Data { raw_len: usize, bytes: Vec<u8> },
+}
+
+/// Reads exactly `size` bytes from `reader` and encodes them as entry data,
+/// handing the pieces to `emit` as they are ready. Returns the SHA-256 of the
+/// data, computed in the same pass.
+///
+/// With compression enabled the data is split into blocks compressed
+/// independently. If the first block does not get smaller the whole entry is
+/// stored raw instead, so already-compressed files cost nothing extra.
+pub fn encode_entry<R: Read>(
+    reader: &mut R,
+    size: u64,
+    compression: Compression,
+    mut emit: impl FnMut(EntryPiece) -> io::Result<()>,
+) -> io::Result<[u8; 32]> {
+    let mut block = vec![0u8; BLOCK_SIZE.min(size as usize)];
+    let mut reader = reader.take(size);
+    let mut ctx = digest::Context::new(&digest::SHA256);
+
+    let first_len = read_full(&mut reader, &mut block)?;
+    ctx.update(&block[..first_len]);
+    let mut codec = compression.codec;
+    let mut compressed = Vec::new();
+    if codec != Codec::None {
+        compressed = compress::compress_block(compression, &block[..first_len])?;
+        if compressed.len() >= first_len {
+            codec = Codec::None;
+        }
+    }
+    emit(EntryPiece::Codec(codec))?;
+
+    let mut len = first_len;
+    while len > 0 {
+        let bytes = if codec == Codec::None {
+            block[..len].to_vec()
+        } else {
+            let mut framed = Vec::with_capacity(8 + compressed.len().min(len));
+            write_block(&mut framed, &block[..len], &compressed)?;
+            framed
+        };
+        emit(EntryPiece::Data {
+            raw_len: len,
+            bytes,
+        })?;
+
+        len = read_full(&mut reader, &mut block)?;
+        ctx.update(&block[..len]);
+        if codec != Codec::None && len > 0 {
+            compressed = compress::compress_block(compression, &block[..len])?;
+        }
+    }
+
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(ctx.finish().as_ref());
+    Ok(hash)
+}
+
+/// Writes the header of an entry whose data, encoded by [`encode_entry`],
+/// follows. Returns the number of bytes written.
+pub fn write_entry_header<W: Write>(
+    writer: &mut W,
+    path: &str,
+    size: u64,
+    codec: Codec,
+) -> io::Result<u64> {
+    let path_bytes = path.as_bytes();
+    writer.write_all(&(path_bytes.len() as u16).to_le_bytes())?;
+    writer.write_all(path_bytes)?;
+    writer.write_all(&size.to_le_bytes())?;
+    writer.write_all(&[codec.to_byte()])?;
+    Ok(2 + path_bytes.len() as u64 + 8 + 1)
+}
+
+/// Writes everything after the entries: the manifest, the index, the
+/// signature of a signed archive and the trailer. `index_offset` is where
+/// the manifest starts.
+pub fn write_index<W: Write>(
+    writer: &mut W,
+    index_offset: u64,
+    manifest_json: &str,
+    entries: &[IndexEntry],
+    signature: Option<&ArchiveSignature>,
+) -> io::Result<()> {
+    let manifest_bytes = manifest_json.as_bytes();
+    writer.write_all(&(manifest_bytes.len() as u64).to_le_bytes())?;
+    writer.write_all(manifest_bytes)?;
+
+    for e in entries {
+        let path_bytes = e.path.as_bytes();
+        writer.write_all(&(path_bytes.len() as u16).to_le_bytes())?;
+        writer.write_all(path_bytes)?;
+        writer.write_all(&e.offset.to_le_bytes())?;
+        writer.write_all(&e.size.to_le_bytes())?;
+        writer.write_all(&e.hash)?;
+    }
+
+    if let Some(sig) = signature {
+        writer.write_all(SIGNATURE_MAGIC)?;
+        writer.write_all(&sig.public_key)?;
+        writer.write_all(&sig.signature)?;
+    }
+
+    writer.write_all(&index_offset.to_le_bytes())?;
+    writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+    writer.write_all(INDEX_MAGIC)?;
+    Ok(())
+}
+
+trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Read side of a `.backup` archive. Handles the original v1 layout, which
+/// has to be streamed end to end, v2, which carries a trailing index so
+/// single entries can be located without reading the data region, v3,
+/// which adds per-entry compression, and v4, which moves the manifest and
+/// the entry hashes behind the data so an archive is written in one pass.
+/// Any of them may be wrapped in an encryption envelope, which is removed
+/// transparently on open.
+pub struct ArchiveReader {
+    reader: BufReader<Box<dyn ReadSeek>>,
+    pub version: u32,
+    /// Key slot summary of an encrypted archive, `None` when unencrypted.
+    pub encryption: Option<String>,
+    pub manifest_bytes: Vec<u8>,
+    data_start: u64,
+    data_end: u64,
+    pub index: Option<Vec<IndexEntry>>,
+    pub signature: Option<ArchiveSignature>,
+}
+
+impl ArchiveReader {
+    pub fn open(path: &Path, credentials: &Credentials) -> io::Result<Self> {
+        let PlainArchive {
+            mut reader,
+            encryption,
+            len: file_len,
+            version,
+        } = open_plain(path, credentials, false)?;
+
+        let (mut manifest_bytes, data_start) = if version >= 4 {
+            (Vec::new(), 4 + 4)
+        } else {
+            let manifest = read_manifest(&mut reader, file_len)?;
+            let data_start = 4 + 4 + 8 + manifest.len() as u64;
+            (manifest, data_start)
+        };
+
+        let (data_end, index, signature) = if version >= 2 {
+            let region = read_index(&mut reader, version, data_start, file_len)?;
+            if let Some(manifest) = region.manifest {
+                manifest_bytes = manifest;
+            }
+            reader.seek(SeekFrom::Start(data_start))?;
+            (region.offset, Some(region.entries), region.signature)
+        } else {
+            (file_len, None, None)
+        };
+
+        Ok(Self {
+            reader,
+            version,
+            encryption,
+            manifest_bytes,
+            data_start,
+            data_end,
+            index,
+            signature,
+        })
+    }
+
+    /// Opens an archive that was interrupted while it was written. It has
+    /// neither manifest nor index yet, so `index` lists the entries known to
+    /// be complete, as recorded in a checkpoint.
+    pub fn open_partial(
+        path: &Path,
+        credentials: &Credentials,
+        index: Vec<IndexEntry>,
+    ) -> io::Result<Self> {
+        let PlainArchive {
+            reader,
+            encryption,
+            len: file_len,
+            version,
+        } = open_plain(path, credentials, true)?;
+        if version != FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "partial archive has format version {version}, only {FORMAT_VERSION} can be resumed"
+            )));
+        }
+
+        Ok(Self {
+            reader,
+            version,
+            encryption,
+            manifest_bytes: Vec::new(),
+            data_start: 4 + 4,
+            data_end: file_len,
+            index: Some(index),
+            signature: None,
+        })
+    }
+
+    /// Current position in the plaintext archive.
+    pub fn position(&mut self) -> io::Result<u64> {
+        self.reader.stream_position()
+    }
+
+    /// Copies the first `len` bytes of the plaintext archive to `writer`.
+    pub fn copy_prefix<W: Write>(&mut self, len: u64, writer: &mut W) -> io::Result<()> {
+        self.reader.seek(SeekFrom::Start(0))?;
+        let copied = io::copy(&mut (&mut self.reader).take(len), writer)?;
+        if copied != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "archive is shorter than the part to copy",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Size of the entry region on disk, headers and compression included.
+    pub fn stored_bytes(&self) -> u64 {
+        self.data_end - self.data_start
+    }
+
+    pub fn manifest<T: serde::de::DeserializeOwned>(&self) -> io::Result<T> {
+        serde_json::from_slice(&self.manifest_bytes)
+            .map_err(|e| invalid_data(format!("failed to parse embedded manifest: {e}")))
+    }
+
+    /// Reads the next entry header in archive order, leaving the reader
+    /// positioned at the start of that entry's data. Returns `None` once the
+    /// data region is exhausted.
+    pub fn next_entry(&mut self) -> io::Result<Option<EntryHeader>> {
+        let offset = self.reader.stream_position()?;
+        if self.version >= 2 && offset >= self.data_end {
+            return Ok(None);
+        }
+
+        let path_len = match read_u16(&mut self.reader) {
+            Ok(n) => n as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let path = read_path(&mut self.reader, path_len)?;
+        let size = read_u64(&mut self.reader)?;
+        let hash = if self.version >= 4 {
+            self.indexed_hash(offset, &path)?
+        } else {
+            let mut hash = [0u8; 32];
+            self.reader.read_exact(&mut hash)?;
+            hash
+        };
+        let codec = if self.version >= 3 {
+            let mut codec = [0u8; 1];
+            self.reader.read_exact(&mut codec)?;
+            Codec::from_byte(codec[0])?
+        } else {
+            Codec::None
+        };
+        // Stored data is never larger than the file, except for the block
+        // headers of compressed data, so this only bounds raw entries.
+        let data_pos = self.reader.stream_position()?;
+        if codec == Codec::None && size > self.data_end.saturating_sub(data_pos) {
+            return Err(invalid_data(format!(
+                "entry {path} is larger than the archive data"
+            )));
+        }
+
+        Ok(Some(EntryHeader {
+            path,
+            size,
+            hash,
+            codec,
+        }))
+    }
+
+    /// Looks up the hash of the entry at `offset`, which v4 archives keep in
+    /// the index only.
+    fn indexed_hash(&self, offset: u64, path: &str) -> io::Result<[u8; 32]> {
+        let index = self.index.as_deref().unwrap_or_default();
+        index
+            .binary_search_by_key(&offset, |e| e.offset)
+            .ok()
+            .map(|i| &index[i])
+            .filter(|e| e.path == path)
+            .map(|e| e.hash)
+            .ok_or_else(|| invalid_data(format!("entry {path} is missing from the index")))
+    }
+
+    /// Positions the reader at an entry found through the index and returns
+    /// its header, leaving the reader at the start of the entry's data.
+    pub fn seek_entry(&mut self, entry: &IndexEntry) -> io::Result<EntryHeader> {
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        let header = self
+            .next_entry()?
+            .ok_or_else(|| invalid_data("index points past end of archive data"))?;
+        if header.path != entry.path || header.size != entry.size {
+            return Err(invalid_data(format!(
+                "index entry for {} does not match archive contents",
+                entry.path
+            )));
+        }
+        Ok(header)
+    }
+
+    /// Moves past the data of `entry` without decoding it.
+    pub fn skip_data(&mut self, entry: &EntryHeader) -> io::Result<()> {
+        if entry.codec == Codec::None {
+            return self.reader.seek_relative(entry.size as i64);
+        }
+
+        let mut remaining = entry.size;
+        while remaining > 0 {
+            let (raw_len, stored_len) = read_block_header(&mut self.reader)?;
+            self.reader.seek_relative(stored_len as i64)?;
+            remaining = remaining
+                .checked_sub(raw_len as u64)
+                .ok_or_else(|| invalid_data("compressed entry longer than its size"))?;
+        }
+        Ok(())
+    }
+
+    /// Returns a reader over the decoded data of `entry`. It has to be read
+    /// to the end to leave the archive positioned at the next entry.
+    pub fn entry_data(&mut self, entry: &EntryHeader) -> EntryData<'_> {
+        EntryData {
+            reader: &mut self.reader,
+            codec: entry.codec,
+            remaining: entry.size,
+            block: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+/// An opened archive with its encryption envelope removed, positioned
+/// behind the format version.
+struct PlainArchive {
+    reader: BufReader<Box<dyn ReadSeek>>,
+    /// key slot summary of an encrypted archive
+    encryption: Option<String>,
+    len: u64,
+    version: u32,
+}
+
+/// Opens the archive at `path`, removing an encryption envelope, and reads
+/// the magic and format version.
+fn open_plain(path: &Path, credentials: &Credentials, partial: bool) -> io::Result<PlainArchive> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic)?;
+
+    let mut encryption = None;
+    let (inner, file_len): (Box<dyn ReadSeek>, u64) = if &magic == crypto::ENVELOPE_MAGIC {
+        let mut decrypted = if partial {
+            DecryptReader::open_partial(file, credentials)?
+        } else {
+            DecryptReader::open(file, credentials)?
+        };
+        let len = decrypted.plain_len();
+        decrypted.read_exact(&mut magic)?;
+        encryption = Some(decrypted.key_slots.clone());
+        (Box::new(decrypted), len)
+    } else {
+        let len = file.metadata()?.len();
+        (Box::new(file), len)
+    };
+    let mut reader = BufReader::new(inner);
+
+    if &magic != MAGIC {
+        return Err(invalid_data("invalid magic, not a .backup file"));
+    }
+
+    let mut ver_bytes = [0u8; 4];
+    reader.read_exact(&mut ver_bytes)?;
+    let version = u32::from_le_bytes(ver_bytes);
+    if !(1..=FORMAT_VERSION).contains(&version) {
+        return Err(invalid_data(format!(
+            "unsupported backup version: {version}"
+        )));
+    }
+    Ok(PlainArchive {
+        reader,
+        encryption,
+        len: file_len,
+        version,
+    })
+}
+
+fn read_block_header<R: Read>(reader: &mut R) -> io::Result<(usize, usize)> {
+    let raw_len = read_u32(reader)? as usize;
+    let stored_len = read_u32(reader)? as usize;
+    if raw_len == 0 || raw_len > BLOCK_SIZE || stored_len > raw_len {
+        return Err(invalid_data("corrupt compressed block header"));
+    }
+    Ok((raw_len, stored_len))
+}
+
+/// Decoded view of one entry's data, see [`ArchiveReader::entry_data`].
+pub struct EntryData<'a> {
+    reader: &'a mut BufReader<Box<dyn ReadSeek>>,
+    codec: Codec,
+    remaining: u64,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for EntryData<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.codec == Codec::None {
+            if self.remaining == 0 || buf.is_empty() {
+                return Ok(0);
+            }
+            let len = buf.len().min(self.remaining as usize);
+            let n = self.reader.read(&mut buf[..len])?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated file data in archive",
+                ));
+            }
+            self.remaining -= n as u64;
+            return Ok(n);
+        }
+
+        if self.pos == self.block.len() {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            let (raw_len, stored_len) = read_block_header(self.reader)?;
+            if raw_len as u64 > self.remaining {
+                return Err(invalid_data("compressed entry longer than its size"));
+            }
+            let mut stored = vec![0u8; stored_len];
+            self.reader.read_exact(&mut stored)?;
+            self.block = if stored_len == raw_len {
+                stored
+            } else {
+                compress::decompress_block(self.codec, &stored, raw_len)?
+            };
+            self.pos = 0;
+            self.remaining -= raw_len as u64;
+        }
+
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn read_manifest<R: Read>(reader: &mut R, file_len: u64) -> io::Result<Vec<u8>> {
+    let manifest_len = read_u64(reader)?;
+    if manifest_len > file_len {
+        return Err(invalid_data("manifest length exceeds archive size"));
+    }
+    let mut manifest_bytes = vec![0u8; manifest_len as usize];
+    reader.read_exact(&mut manifest_bytes)?;
+    Ok(manifest_bytes)
+}
+
+/// Everything after the entries of a v2 or later archive.
+struct IndexRegion {
+    /// where the index, or in v4 the manifest before it, starts
+    offset: u64,
+    manifest: Option<Vec<u8>>,
+    entries: Vec<IndexEntry>,
+    signature: Option<ArchiveSignature>,
+}
+
+fn read_index<R: Read + Seek>(
+    reader: &mut R,
+    version: u32,
+    data_start: u64,
+    file_len: u64,
+) -> io::Result<IndexRegion> {
+    if file_len < data_start + TRAILER_LEN {
+        return Err(invalid_data(
+            "archive too short to contain an index trailer",
+        ));
+    }
+
+    reader.seek(SeekFrom::Start(file_len - TRAILER_LEN))?;
+    let index_offset = read_u64(reader)?;
+    let count = read_u64(reader)?;
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != INDEX_MAGIC {
+        return Err(invalid_data(
+            "missing index trailer, archive may be truncated",
+        ));
+    }
+    if index_offset < data_start || index_offset > file_len - TRAILER_LEN {
+        return Err(invalid_data("index offset out of range"));
+    }
+
+    reader.seek(SeekFrom::Start(index_offset))?;
+    let manifest = if version >= 4 {
+        Some(read_manifest(reader, file_len - index_offset)?)
+    } else {
+        None
+    };
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let path_len = read_u16(reader)? as usize;
+        let path = read_path(reader, path_len)?;
+        let offset = read_u64(reader)?;
+        let size = read_u64(reader)?;
+        let mut hash = [0u8; 32];
+        reader.read_exact(&mut hash)?;
+
+        if offset < data_start || offset >= index_offset {
+            return Err(invalid_data(format!(
+                "index offset out of range for {path}"
+            )));
+        }
+
+        entries.push(IndexEntry {
+            path,
+            offset,
+            size,
+            hash,
+        });
+    }
+
+    // A signed archive carries its signature between the index and the
+    // trailer; anything else there means the index is damaged.
+    let index_end = reader.stream_position()?;
+    let trailer_start = file_len - TRAILER_LEN;
+    let signature = if index_end == trailer_start {
+        None
+    } else if index_end + SIGNATURE_LEN == trailer_start {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != SIGNATURE_MAGIC {
+            return Err(invalid_data("invalid signature block in archive"));
+        }
+        let mut public_key = [0u8; 32];
+        reader.read_exact(&mut public_key)?;
+        let mut signature = [0u8; 64];
+        reader.read_exact(&mut signature)?;
+        Some(ArchiveSignature {
+            public_key,
+            signature,
+        })
+    } else {
+        return Err(invalid_data("index does not end at the archive trailer"));
+    };
+
+    Ok(IndexRegion {
+        offset: index_offset,
+        manifest,
+        entries,
+        signature,
+    })
+}
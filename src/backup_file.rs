@@ -1,80 +1,593 @@
-use crate::pipeline::HashedFile;
+use crate::archive::{self, EntryPiece, IndexEntry};
+use crate::compress::Compression;
+use crate::crypto::{EncryptWriter, Envelope};
+use crate::pipeline::{self, HashedFile, Piece};
+use crate::resume::{self, CheckpointEntry, CheckpointHeader, Checkpointer, Prefix};
+use crate::signing::{self, EntryDigest};
+use crate::types::{self, FileEntry, SourceRoot};
 use indicatif::ProgressBar;
-use std::fs::File;
-use std::io::{self, BufReader, BufWriter, Read, Write};
-use std::path::Path;
+use ring::signature::Ed25519KeyPair;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
+/// Appended to the name of an archive while it is written. The archive only
+/// gets its final name once complete, so an interrupted run never leaves a
+/// truncated archive that looks like a finished one.
+pub const PARTIAL_SUFFIX: &str = ".partial";
+
+pub fn partial_path(backup_file: &Path) -> PathBuf {
+    let mut path = backup_file.as_os_str().to_owned();
+    path.push(PARTIAL_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Makes renames and new files in `dir` durable.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+fn create_locked(path: &Path) -> io::Result<File> {
+    let file = File::create_new(path)?;
+    // `cleanup` leaves partial archives alone while they are locked.
+    file.lock()?;
+    Ok(file)
+}
+
+/// An archive writer that can make what it was given durable, for a
+/// checkpoint.
+trait Durable: Write {
+    /// Syncs the archive to disk and returns how many of the `written`
+    /// bytes of the plaintext archive are on disk.
+    fn sync(&mut self, written: u64) -> io::Result<u64>;
+}
+
+impl Durable for BufWriter<File> {
+    fn sync(&mut self, written: u64) -> io::Result<u64> {
+        self.flush()?;
+        self.get_ref().sync_data()?;
+        Ok(written)
+    }
+}
+
+impl Durable for EncryptWriter<BufWriter<File>> {
+    fn sync(&mut self, _written: u64) -> io::Result<u64> {
+        // The chunk being filled is only sealed once it is full.
+        let sealed = self.sealed_len();
+        self.get_mut().sync(sealed)
+    }
+}
+
+/// How an archive is written: compression, optionally encryption and a
+/// signature, and what to do about files that change while they are read.
+pub struct ArchiveOptions<'a> {
+    pub compression: Compression,
+    pub envelope: Option<&'a Envelope>,
+    pub signing_key: Option<&'a Ed25519KeyPair>,
+    pub change_retries: u32,
+    pub fail_on_change: bool,
+    /// recorded in the checkpoint, from which an interrupted run is resumed
+    pub checkpoint: &'a CheckpointHeader,
+}
+
+/// Builds the manifest once the hashes of the stored files are known.
+pub type ManifestBuilder<'a> = dyn Fn(&[HashedFile]) -> io::Result<String> + 'a;
+
+/// Writes the archive to `backup_file`, reading each of `files` once to hash
+/// and store it. With an envelope the whole archive, manifest included, is
+/// encrypted; offsets in the index always refer to the plaintext archive.
+/// Returns the files stored, with their hashes.
+///
+/// The archive is written to a partial file next to `backup_file`, synced
+/// and renamed once complete. While it is written, a checkpoint records the
+/// entries that are on disk so a run that fails or is killed can be resumed
+/// from `resume`, the part of the partial file that is kept. On failure both
+/// are left in place for `backup resume` or `backup cleanup`.
 pub fn create_backup_file(
     backup_file: &Path,
-    source_root: &Path,
-    files: &[HashedFile],
-    manifest_json: &str,
+    roots: &[SourceRoot],
+    files: &[FileEntry],
+    manifest: &ManifestBuilder,
+    options: &ArchiveOptions,
+    resume: Option<Prefix>,
     pb: &ProgressBar,
-) -> io::Result<()> {
-    let file = File::create(backup_file)?;
-    let mut writer = BufWriter::new(file);
+) -> io::Result<Vec<HashedFile>> {
+    let partial = partial_path(backup_file);
+    let checkpoint = resume::checkpoint_path(backup_file);
+    let stored = write_partial(backup_file, roots, files, manifest, options, resume, pb)
+        .and_then(|stored| {
+            fs::rename(&partial, backup_file)?;
+            if let Err(e) = fs::remove_file(&checkpoint) {
+                eprintln!("warning: failed to remove checkpoint {:?}: {e}", checkpoint);
+            }
+            if let Some(dir) = backup_file.parent() {
+                sync_dir(dir)?;
+            }
+            Ok(stored)
+        })
+        .inspect_err(|_| {
+            if partial.exists() && checkpoint.exists() {
+                eprintln!(
+                    "the partial archive is kept: continue with `backup resume {}` or remove it with `backup cleanup`",
+                    partial.display()
+                );
+            }
+        })?;
 
-    writer.write_all(b"BKUP")?;
-    writer.write_all(&1u32.to_le_bytes())?;
+    pb.finish_with_message(".backup archive write complete");
+    Ok(stored)
+}
 
-    let manifest_bytes = manifest_json.as_bytes();
-    let manifest_len = manifest_bytes.len() as u64;
-    writer.write_all(&manifest_len.to_le_bytes())?;
-    writer.write_all(manifest_bytes)?;
+/// The entries of an archive written so far and where the next one starts,
+/// 0 before the archive header.
+struct Written {
+    offset: u64,
+    index: Vec<IndexEntry>,
+    stored: Vec<HashedFile>,
+    checkpoint: Checkpointer,
+}
 
-    let total_bytes: u64 = files.iter().map(|h| h.entry.size).sum();
-    pb.set_length(total_bytes);
+fn write_partial(
+    backup_file: &Path,
+    roots: &[SourceRoot],
+    files: &[FileEntry],
+    manifest: &ManifestBuilder,
+    options: &ArchiveOptions,
+    resume: Option<Prefix>,
+    pb: &ProgressBar,
+) -> io::Result<Vec<HashedFile>> {
+    let partial = partial_path(backup_file);
+    let (file, kept, end, copy_from) = match resume {
+        None => (create_locked(&partial)?, Vec::new(), 0, None),
+        Some(Prefix {
+            mut file,
+            entries,
+            end,
+            ..
+        }) if options.envelope.is_none() => {
+            file.set_len(end)?;
+            file.seek(SeekFrom::End(0))?;
+            (file, entries, end, None)
+        }
+        // Chunks past the kept part may already have been sealed with other
+        // data, so an encrypted archive gets a new data key to never reuse a
+        // nonce. The kept part is copied from the old file, which stays
+        // open after it is replaced.
+        Some(Prefix {
+            archive,
+            entries,
+            end,
+            ..
+        }) => {
+            fs::remove_file(&partial)?;
+            (create_locked(&partial)?, entries, end, archive)
+        }
+    };
+    let checkpoint = Checkpointer::create(
+        &resume::checkpoint_path(backup_file),
+        options.checkpoint,
+        &kept,
+    )?;
+    if let Some(dir) = backup_file.parent() {
+        sync_dir(dir)?;
+    }
+    let written = Written {
+        offset: end,
+        index: kept
+            .iter()
+            .map(CheckpointEntry::index_entry)
+            .collect::<io::Result<_>>()?,
+        stored: kept
+            .iter()
+            .map(|e| e.hashed_file(roots))
+            .collect::<io::Result<_>>()?,
+        checkpoint,
+    };
 
-    let mut buf = [0u8; 8192];
-
-    for h in files {
-        let rel = h
-            .entry
-            .path
-            .strip_prefix(source_root)
-            .unwrap_or(&h.entry.path);
-        let path_str = rel.to_string_lossy();
-        let path_bytes = path_str.as_bytes();
-
-        if path_bytes.len() > u16::MAX as usize {
-            eprintln!(
-                "warning: path too long for backup format, skipping: {}",
-                path_str
-            );
-            continue;
+    let writer = BufWriter::new(file);
+    let (writer, stored) = match options.envelope {
+        Some(envelope) => {
+            let mut writer = EncryptWriter::new(writer, envelope)?;
+            if let Some(mut archive) = copy_from {
+                archive.copy_prefix(end, &mut writer)?;
+            }
+            let stored = write_archive(&mut writer, roots, files, manifest, options, written, pb)?;
+            (writer.finish()?, stored)
+        }
+        None => {
+            let mut writer = writer;
+            let stored = write_archive(&mut writer, roots, files, manifest, options, written, pb)?;
+            (writer, stored)
         }
+    };
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(stored)
+}
+
+fn write_archive<W: Durable>(
+    writer: &mut W,
+    roots: &[SourceRoot],
+    files: &[FileEntry],
+    manifest: &ManifestBuilder,
+    options: &ArchiveOptions,
+    written: Written,
+    pb: &ProgressBar,
+) -> io::Result<Vec<HashedFile>> {
+    let Written {
+        mut offset,
+        mut index,
+        mut stored,
+        mut checkpoint,
+    } = written;
+    if offset == 0 {
+        offset = archive::write_header(writer)?;
+    }
 
-        let path_len = path_bytes.len() as u16;
+    let files: Vec<FileEntry> = files
+        .iter()
+        .filter(|f| {
+            let rel = types::archive_path(roots, &f.path);
+            let fits = rel.as_os_str().len() <= u16::MAX as usize;
+            if !fits {
+                eprintln!(
+                    "warning: path too long for backup format, skipping: {}",
+                    rel.to_string_lossy()
+                );
+            }
+            fits
+        })
+        .cloned()
+        .collect();
 
-        writer.write_all(&path_len.to_le_bytes())?;
-        writer.write_all(path_bytes)?;
-        writer.write_all(&h.entry.size.to_le_bytes())?;
-        writer.write_all(&h.hash)?;
+    let total_bytes: u64 = files.iter().map(|f| f.size).sum();
+    pb.set_length(total_bytes);
 
-        let src_file = match File::open(&h.entry.path) {
-            Ok(f) => f,
-            Err(e) => {
-                eprintln!("warning: failed to reopen file {:?}: {e}", h.entry.path);
-                continue;
+    let retries = options.change_retries;
+    pipeline::read_files_ordered(&files, options.compression, retries, |f, pieces| {
+        let stopped = || io::Error::other(format!("reader of {:?} stopped", f.path));
+        let (stamp, codec) = match (pieces.recv(), pieces.recv()) {
+            (Ok(Piece::Opened(stamp)), Ok(Piece::Entry(EntryPiece::Codec(codec)))) => {
+                (stamp, codec)
             }
+            (Ok(Piece::Failed(e)), _) => {
+                eprintln!("warning: failed to read file {:?}: {e}", f.path);
+                pb.inc(f.size);
+                return Ok(());
+            }
+            _ => return Err(stopped()),
         };
 
-        let mut reader = BufReader::new(src_file);
-        let mut remaining = h.entry.size;
+        // The file is stored as it was when opened, which may differ from
+        // the scan.
+        let mut entry = f.clone();
+        entry.size = stamp.size;
+        entry.meta.mtime = stamp.mtime;
+        entry.meta.mtime_nsec = stamp.mtime_nsec;
+
+        let path = types::archive_path(roots, &f.path)
+            .to_string_lossy()
+            .to_string();
+        let entry_offset = offset;
+        offset += archive::write_entry_header(writer, &path, entry.size, codec)?;
 
-        while remaining > 0 {
-            let read_len = std::cmp::min(remaining, buf.len() as u64) as usize;
-            let n = reader.read(&mut buf[..read_len])?;
-            if n == 0 {
-                break;
+        for piece in pieces {
+            match piece {
+                Piece::Entry(EntryPiece::Data { raw_len, bytes }) => {
+                    writer.write_all(&bytes)?;
+                    offset += bytes.len() as u64;
+                    pb.inc(raw_len as u64);
+                }
+                Piece::Done {
+                    hash,
+                    changed,
+                    streamed,
+                } => {
+                    if changed {
+                        if options.fail_on_change {
+                            return Err(io::Error::other(format!(
+                                "{:?} changed while it was read",
+                                f.path
+                            )));
+                        }
+                        if streamed {
+                            eprintln!(
+                                "warning: file changed during backup, not retried (larger than the retry buffer): {:?}",
+                                f.path
+                            );
+                        } else {
+                            eprintln!("warning: file changed during backup: {:?}", f.path);
+                        }
+                    }
+                    let indexed = IndexEntry {
+                        path,
+                        offset: entry_offset,
+                        size: entry.size,
+                        hash,
+                    };
+                    let hashed = HashedFile {
+                        entry,
+                        hash,
+                        chunks: Vec::new(),
+                        changed,
+                    };
+                    checkpoint.add(CheckpointEntry::new(&indexed, &hashed, offset));
+                    index.push(indexed);
+                    stored.push(hashed);
+                    if checkpoint.due() {
+                        let durable = writer.sync(offset)?;
+                        checkpoint.record(durable)?;
+                    }
+                    return Ok(());
+                }
+                Piece::Failed(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("failed to read {:?}: {e}", f.path),
+                    ));
+                }
+                Piece::Opened(_) | Piece::Entry(EntryPiece::Codec(_)) => return Err(stopped()),
             }
-            writer.write_all(&buf[..n])?;
-            remaining -= n as u64;
-            pb.inc(n as u64);
         }
+        Err(stopped())
+    })?;
+
+    let manifest_json = manifest(&stored)?;
+    let signature = options.signing_key.map(|key| {
+        let mut entries = EntryDigest::new();
+        for e in &index {
+            entries.add(&e.path, e.size, &e.hash);
+        }
+        signing::sign(key, manifest_json.as_bytes(), &entries.finish())
+    });
+
+    archive::write_index(writer, offset, &manifest_json, &index, signature.as_ref())?;
+    Ok(stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::ArchiveReader;
+    use crate::compress::Codec;
+    use crate::crypto::{self, Credentials};
+    use crate::fs_scan;
+    use crate::manifest::build_manifest_json;
+    use crate::resume::Checkpoint;
+    use crate::types::EntryKind;
+    use crate::validation;
+    use std::collections::HashSet;
+
+    const NAME: &str = "src-1700000000.backup";
+    const CREATED_AT: u64 = 1_700_000_000;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("backup-file-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
     }
 
-    writer.flush()?;
-    pb.finish_with_message(".backup archive write complete");
-    Ok(())
+    /// Reproducible pseudo-random bytes (xorshift64), which do not compress.
+    fn data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64 ^ seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// A source directory of five files, scanned.
+    fn source(dir: &Path) -> (Vec<SourceRoot>, Vec<FileEntry>) {
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        for (i, name) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            fs::write(src.join(name), data(100_000 + i * 1000, i as u64)).unwrap();
+        }
+        let roots = validation::source_roots(&[src]).unwrap();
+        let mut files: Vec<FileEntry> = fs_scan::scan_roots(&roots, None)
+            .into_iter()
+            .filter(|f| f.kind == EntryKind::File)
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        (roots, files)
+    }
+
+    fn header() -> CheckpointHeader {
+        CheckpointHeader {
+            backup_file: NAME.to_string(),
+            created_at: CREATED_AT,
+            profile: toml::from_str(r#"destination = "/backups""#).unwrap(),
+        }
+    }
+
+    /// Writes `files` to `dir/NAME`, continuing `resume` if given.
+    fn write(
+        dir: &Path,
+        roots: &[SourceRoot],
+        files: &[FileEntry],
+        envelope: Option<&Envelope>,
+        resume: Option<Prefix>,
+    ) -> Vec<HashedFile> {
+        let manifest = |stored: &[HashedFile]| {
+            build_manifest_json(roots, Path::new(NAME), CREATED_AT, stored, &[], None)
+                .map_err(io::Error::other)
+        };
+        let options = ArchiveOptions {
+            compression: Compression {
+                codec: Codec::Zstd,
+                level: 3,
+            },
+            envelope,
+            signing_key: None,
+            change_retries: 0,
+            fail_on_change: false,
+            checkpoint: &header(),
+        };
+        fs::create_dir_all(dir).unwrap();
+        let pb = ProgressBar::hidden();
+        create_backup_file(
+            &dir.join(NAME),
+            roots,
+            files,
+            &manifest,
+            &options,
+            resume,
+            &pb,
+        )
+        .unwrap()
+    }
+
+    /// Leaves in `dir` what a run interrupted while writing the archive
+    /// `complete` would: a checkpoint recording its first `kept` entries
+    /// and the archive cut short at `len` bytes.
+    fn interrupt(
+        complete: &Path,
+        credentials: &Credentials,
+        stored: &[HashedFile],
+        kept: usize,
+        dir: &Path,
+        len: impl FnOnce(&[IndexEntry]) -> usize,
+    ) {
+        let index = ArchiveReader::open(complete, credentials)
+            .unwrap()
+            .index
+            .unwrap();
+        let entries: Vec<CheckpointEntry> = (0..kept)
+            .map(|i| CheckpointEntry::new(&index[i], &stored[i], index[i + 1].offset))
+            .collect();
+        fs::create_dir_all(dir).unwrap();
+        let backup_file = dir.join(NAME);
+        Checkpointer::create(&resume::checkpoint_path(&backup_file), &header(), &entries).unwrap();
+        let mut bytes = fs::read(complete).unwrap();
+        bytes.truncate(len(&index));
+        fs::write(partial_path(&backup_file), bytes).unwrap();
+    }
+
+    /// Continues the interrupted run in `dir` like `backup resume` does.
+    /// Returns how many entries were kept.
+    fn resume(
+        dir: &Path,
+        roots: &[SourceRoot],
+        files: &[FileEntry],
+        credentials: &Credentials,
+        envelope: Option<&Envelope>,
+    ) -> usize {
+        let checkpoint = Checkpoint::load(&partial_path(&dir.join(NAME))).unwrap();
+        let prefix = resume::verify_prefix(&checkpoint, credentials).unwrap();
+        let kept: HashSet<String> = prefix.entries.iter().map(|e| e.path.clone()).collect();
+        let rest: Vec<FileEntry> = files
+            .iter()
+            .filter(|f| !kept.contains(types::archive_path(roots, &f.path).to_str().unwrap()))
+            .cloned()
+            .collect();
+        write(dir, roots, &rest, envelope, Some(prefix));
+        assert!(!partial_path(&dir.join(NAME)).exists());
+        assert!(!resume::checkpoint_path(&dir.join(NAME)).exists());
+        kept.len()
+    }
+
+    /// The manifest and every entry with its data.
+    fn contents(path: &Path, credentials: &Credentials) -> (Vec<u8>, Vec<(String, Vec<u8>)>) {
+        let mut archive = ArchiveReader::open(path, credentials).unwrap();
+        let mut entries = Vec::new();
+        while let Some(entry) = archive.next_entry().unwrap() {
+            let mut data = Vec::new();
+            io::Read::read_to_end(&mut archive.entry_data(&entry), &mut data).unwrap();
+            entries.push((entry.path, data));
+        }
+        (archive.manifest_bytes, entries)
+    }
+
+    #[test]
+    fn resumed_archive_matches_an_uninterrupted_one() {
+        let dir = scratch_dir("resume");
+        let (roots, files) = source(&dir);
+        let plain = Credentials::default();
+        let stored = write(&dir.join("clean"), &roots, &files, None, None);
+        let clean = dir.join("clean").join(NAME);
+
+        // cut in the middle of the entry after the recorded ones
+        let resumed = dir.join("resumed");
+        interrupt(&clean, &plain, &stored, 2, &resumed, |index| {
+            index[2].offset as usize + 500
+        });
+        assert_eq!(resume(&resumed, &roots, &files, &plain, None), 2);
+        assert_eq!(
+            fs::read(resumed.join(NAME)).unwrap(),
+            fs::read(&clean).unwrap()
+        );
+    }
+
+    #[test]
+    fn damaged_entries_are_written_again() {
+        let dir = scratch_dir("damaged");
+        let (roots, files) = source(&dir);
+        let plain = Credentials::default();
+        let stored = write(&dir.join("clean"), &roots, &files, None, None);
+        let clean = dir.join("clean").join(NAME);
+
+        let resumed = dir.join("resumed");
+        let mut damaged_at = 0;
+        interrupt(&clean, &plain, &stored, 3, &resumed, |index| {
+            damaged_at = index[1].offset as usize + 1000;
+            index[3].offset as usize
+        });
+        let partial = partial_path(&resumed.join(NAME));
+        let mut bytes = fs::read(&partial).unwrap();
+        bytes[damaged_at] ^= 0x01;
+        fs::write(&partial, bytes).unwrap();
+
+        // only the entry before the damaged one is kept
+        assert_eq!(resume(&resumed, &roots, &files, &plain, None), 1);
+        assert_eq!(
+            fs::read(resumed.join(NAME)).unwrap(),
+            fs::read(&clean).unwrap()
+        );
+    }
+
+    #[test]
+    fn encrypted_archive_is_resumed_under_a_new_key() {
+        let dir = scratch_dir("encrypted");
+        let (roots, files) = source(&dir);
+        let public = crypto::write_identity_file(&dir.join("identity")).unwrap();
+        let recipients = [crypto::parse_public_key(&public).unwrap()];
+        let with_key = |envelope: &Envelope| Credentials {
+            data_key: Some(envelope.data_key()),
+            ..Credentials::default()
+        };
+
+        let first = Envelope::new(None, &recipients).unwrap();
+        let stored = write(&dir.join("first"), &roots, &files, Some(&first), None);
+        let interrupted = dir.join("first").join(NAME);
+
+        // the sealed chunks of the first three quarters are kept
+        let resumed = dir.join("resumed");
+        let len = fs::metadata(&interrupted).unwrap().len() as usize * 3 / 4;
+        interrupt(
+            &interrupted,
+            &with_key(&first),
+            &stored,
+            2,
+            &resumed,
+            |_| len,
+        );
+        let second = Envelope::new(None, &recipients).unwrap();
+        assert_eq!(
+            resume(&resumed, &roots, &files, &with_key(&first), Some(&second)),
+            2
+        );
+
+        let resumed = resumed.join(NAME);
+        assert!(ArchiveReader::open(&resumed, &with_key(&first)).is_err());
+        write(&dir.join("clean"), &roots, &files, None, None);
+        assert_eq!(
+            contents(&resumed, &with_key(&second)),
+            contents(&dir.join("clean").join(NAME), &Credentials::default())
+        );
+    }
 }
@@ -0,0 +1,262 @@
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+
+/// Random values the rolling hash adds for each byte value. Any fixed table
+/// works, but changing it moves every chunk boundary, so it is generated
+/// from a fixed seed rather than at runtime.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6261_636b_7570_6364;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+pub const DEFAULT_MIN_SIZE: usize = 512 * 1024;
+pub const DEFAULT_AVG_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_MAX_SIZE: usize = 8 * 1024 * 1024;
+const MIN_SIZE_LIMIT: usize = 64;
+const MAX_SIZE_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Chunk size bounds of content-defined chunking. Chunk boundaries depend on
+/// them, so all snapshots of a repository must use the same parameters for
+/// their chunks to deduplicate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkParams {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for ChunkParams {
+    fn default() -> Self {
+        Self {
+            min_size: DEFAULT_MIN_SIZE,
+            avg_size: DEFAULT_AVG_SIZE,
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+}
+
+impl ChunkParams {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.min_size < self.avg_size && self.avg_size < self.max_size) {
+            return Err(format!(
+                "chunk sizes must satisfy min < avg < max, got {}/{}/{}",
+                self.min_size, self.avg_size, self.max_size
+            ));
+        }
+        if self.min_size < MIN_SIZE_LIMIT || self.max_size > MAX_SIZE_LIMIT {
+            return Err(format!(
+                "chunk sizes must be between {MIN_SIZE_LIMIT} bytes and {} MiB",
+                MAX_SIZE_LIMIT / (1024 * 1024)
+            ));
+        }
+        if !self.avg_size.is_power_of_two() {
+            return Err(format!(
+                "average chunk size must be a power of two, got {}",
+                self.avg_size
+            ));
+        }
+        Ok(())
+    }
+
+    /// Length of the chunk at the start of `data`, following FastCDC with
+    /// normalized chunking: below the average size a boundary needs two more
+    /// zero bits of the rolling hash, above it two fewer, which keeps chunk
+    /// sizes close to the average.
+    fn cut(&self, data: &[u8]) -> usize {
+        let mut end = data.len();
+        if end <= self.min_size {
+            return end;
+        }
+        end = end.min(self.max_size);
+        let center = end.min(self.avg_size);
+
+        let bits = self.avg_size.trailing_zeros();
+        let mask_small = !0u64 << (64 - (bits + 2));
+        let mask_large = !0u64 << (64 - (bits - 2));
+
+        let mut hash = 0u64;
+        for (i, byte) in data.iter().enumerate().take(end).skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            let mask = if i < center { mask_small } else { mask_large };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/// Splits a stream into content-defined chunks. An insertion or deletion
+/// only changes the chunks around it; later boundaries realign with the
+/// ones found before the change.
+pub struct Chunker<R> {
+    reader: R,
+    params: ChunkParams,
+    buf: Vec<u8>,
+    start: usize,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R, params: ChunkParams) -> Self {
+        Self {
+            reader,
+            params,
+            buf: Vec::new(),
+            start: 0,
+            eof: false,
+        }
+    }
+
+    /// Returns the next chunk, or `None` at the end of the stream.
+    pub fn next_chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        // Refill once less than a maximum-size chunk is buffered. Reading up
+        // to twice that keeps the bytes moved by the refill below the bytes
+        // read, and the buffer only grows as large as a small file needs.
+        if !self.eof && self.buf.len() - self.start < self.params.max_size {
+            self.buf.drain(..self.start);
+            self.start = 0;
+            let want = 2 * self.params.max_size - self.buf.len();
+            let n = (&mut self.reader)
+                .take(want as u64)
+                .read_to_end(&mut self.buf)?;
+            self.eof = n < want;
+        }
+
+        if self.start == self.buf.len() {
+            return Ok(None);
+        }
+        let len = self.params.cut(&self.buf[self.start..]);
+        let chunk = &self.buf[self.start..self.start + len];
+        self.start += len;
+        Ok(Some(chunk))
+    }
+}
+
+pub fn chunk_id(chunk: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(digest::digest(&digest::SHA256, chunk).as_ref());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: ChunkParams = ChunkParams {
+        min_size: 256,
+        avg_size: 1024,
+        max_size: 4096,
+    };
+
+    /// Reproducible pseudo-random bytes (xorshift64).
+    fn data(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// Hands out at most a few bytes per read, so refills happen at
+    /// arbitrary points of the stream.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(7).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn chunks<R: Read>(reader: R) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new(reader, PARAMS);
+        let mut out = Vec::new();
+        while let Some(chunk) = chunker.next_chunk().unwrap() {
+            out.push(chunk.to_vec());
+        }
+        out
+    }
+
+    #[test]
+    fn chunks_respect_the_size_bounds() {
+        let input = data(1 << 20);
+        let chunks = chunks(&input[..]);
+
+        assert_eq!(chunks.concat(), input);
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(last.len() <= PARAMS.max_size);
+        for chunk in rest {
+            assert!(
+                (PARAMS.min_size..=PARAMS.max_size).contains(&chunk.len()),
+                "chunk of {} bytes",
+                chunk.len()
+            );
+        }
+        let average = input.len() / chunks.len();
+        assert!(
+            (PARAMS.avg_size / 2..=PARAMS.avg_size * 2).contains(&average),
+            "average chunk of {average} bytes"
+        );
+    }
+
+    #[test]
+    fn short_input_is_one_chunk() {
+        assert_eq!(PARAMS.cut(&data(PARAMS.min_size)), PARAMS.min_size);
+        assert_eq!(chunks(&data(100)[..]), [data(100)]);
+        assert!(chunks(&[][..]).is_empty());
+    }
+
+    #[test]
+    fn boundaries_do_not_depend_on_reads() {
+        let input = data(256 * 1024);
+        let whole = chunks(&input[..]);
+
+        assert_eq!(chunks(&input[..]), whole);
+        assert_eq!(chunks(Trickle(&input)), whole);
+    }
+
+    #[test]
+    fn boundaries_resynchronise_after_an_insertion() {
+        let input = data(256 * 1024);
+        let mut edited = input.clone();
+        edited.insert(100, 0x42);
+
+        let before: Vec<[u8; 32]> = chunks(&input[..]).iter().map(|c| chunk_id(c)).collect();
+        let after: Vec<[u8; 32]> = chunks(&edited[..]).iter().map(|c| chunk_id(c)).collect();
+
+        assert_ne!(before[0], after[0]);
+        let common = before
+            .iter()
+            .rev()
+            .zip(after.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        // only the chunks around the insertion differ
+        assert!(
+            common >= before.len() - 2,
+            "{common} of {} chunks shared",
+            before.len()
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use crate::backup_file::PARTIAL_SUFFIX;
+use crate::config::CleanupConfig;
+use crate::resume::CHECKPOINT_SUFFIX;
+use std::collections::HashSet;
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::path::Path;
+
+fn print_section(title: &str) {
+    println!();
+    println!("--- {title} ---");
+}
+
+fn print_kv<K: AsRef<str>, V: AsRef<str>>(k: K, v: V) {
+    println!("  {:12} {}", format!("{}:", k.as_ref()), v.as_ref());
+}
+
+/// Removes `path` unless this is a dry run, returning whether it is gone.
+fn remove(path: &Path, dry_run: bool) -> bool {
+    if dry_run {
+        return true;
+    }
+    match fs::remove_file(path) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("cleanup: failed to remove {:?}: {e}", path);
+            false
+        }
+    }
+}
+
+/// Removes the partial archives interrupted runs left in the backup
+/// directory, along with their checkpoints, so they can no longer be
+/// resumed. A partial archive that is still locked belongs to a backup that
+/// is running and is left alone.
+pub fn cleanup(config: &CleanupConfig) -> io::Result<()> {
+    println!("==================== backup cleanup ====================");
+    print_kv("backup dir", config.backup_dir.to_string_lossy());
+    if config.dry_run {
+        print_kv("mode", "dry-run");
+    }
+
+    let mut partials = Vec::new();
+    let mut checkpoints = Vec::new();
+    for dent in fs::read_dir(&config.backup_dir)? {
+        let dent = dent?;
+        let file_name = dent.file_name().to_string_lossy().to_string();
+        if !dent.file_type()?.is_file() {
+            continue;
+        }
+        if file_name.ends_with(&format!(".backup{PARTIAL_SUFFIX}")) {
+            partials.push((file_name, dent.path(), dent.metadata()?.len()));
+        } else if file_name.ends_with(&format!(".backup{CHECKPOINT_SUFFIX}")) {
+            checkpoints.push((file_name, dent.path(), dent.metadata()?.len()));
+        }
+    }
+    partials.sort();
+    checkpoints.sort();
+
+    let mut removed = HashSet::new();
+    let mut in_use = 0usize;
+    let mut freed = 0u64;
+    print_section("partial archives");
+    for (file_name, path, size) in &partials {
+        let file = File::open(path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                println!("  skip    {file_name}  (still being written)");
+                in_use += 1;
+                continue;
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+
+        if !remove(path, config.dry_run) {
+            continue;
+        }
+        println!(
+            "  remove  {file_name}{}",
+            if config.dry_run { "  (dry-run)" } else { "" }
+        );
+        removed.insert(file_name.clone());
+        freed += size;
+    }
+
+    // A checkpoint is of no use without its partial archive.
+    let mut checkpoints_removed = 0usize;
+    for (file_name, path, size) in &checkpoints {
+        let backup_file = file_name
+            .strip_suffix(CHECKPOINT_SUFFIX)
+            .unwrap_or(file_name);
+        let partial = format!("{backup_file}{PARTIAL_SUFFIX}");
+        let orphaned = removed.contains(&partial) || !config.backup_dir.join(&partial).exists();
+        if !orphaned || !remove(path, config.dry_run) {
+            continue;
+        }
+        println!(
+            "  remove  {file_name}{}",
+            if config.dry_run { "  (dry-run)" } else { "" }
+        );
+        checkpoints_removed += 1;
+        freed += size;
+    }
+    if partials.is_empty() && checkpoints_removed == 0 {
+        println!("  none found");
+    }
+
+    print_section("summary");
+    print_kv(
+        if config.dry_run {
+            "to remove"
+        } else {
+            "removed"
+        },
+        removed.len().to_string(),
+    );
+    if checkpoints_removed > 0 {
+        print_kv("checkpoints", checkpoints_removed.to_string());
+    }
+    if in_use > 0 {
+        print_kv("in use", in_use.to_string());
+    }
+    print_kv("freed bytes", freed.to_string());
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use std::io;
+
+/// Amount of file data compressed as one unit. Blocks are independent, so
+/// memory use stays bounded no matter how large a file is.
+pub const BLOCK_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "none" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            "lz4" => Ok(Self::Lz4),
+            other => Err(format!(
+                "unknown compression {other:?} (expected zstd, lz4 or none)"
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Zstd => "zstd",
+            Self::Lz4 => "lz4",
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Zstd => 1,
+            Self::Lz4 => 2,
+        }
+    }
+
+    pub fn from_byte(b: u8) -> io::Result<Self> {
+        match b {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Zstd),
+            2 => Ok(Self::Lz4),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown compression codec {other} in archive"),
+            )),
+        }
+    }
+
+    pub fn default_level(self) -> i32 {
+        match self {
+            Self::None | Self::Lz4 => 0,
+            Self::Zstd => zstd::DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Compression {
+    pub codec: Codec,
+    pub level: i32,
+}
+
+/// Compresses one block. For lz4 a level above 0 selects the
+/// high-compression mode.
+pub fn compress_block(compression: Compression, data: &[u8]) -> io::Result<Vec<u8>> {
+    match compression.codec {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Zstd => zstd::bulk::compress(data, compression.level),
+        Codec::Lz4 => {
+            let mode = if compression.level > 0 {
+                lz4::block::CompressionMode::HIGHCOMPRESSION(compression.level)
+            } else {
+                lz4::block::CompressionMode::DEFAULT
+            };
+            lz4::block::compress(data, Some(mode), false)
+        }
+    }
+}
+
+pub fn decompress_block(codec: Codec, data: &[u8], raw_len: usize) -> io::Result<Vec<u8>> {
+    let out = match codec {
+        Codec::None => data.to_vec(),
+        Codec::Zstd => zstd::bulk::decompress(data, raw_len)?,
+        Codec::Lz4 => lz4::block::decompress(data, Some(raw_len as i32))?,
+    };
+
+    if out.len() != raw_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "decompressed block has unexpected length",
+        ));
+    }
+    Ok(out)
+}
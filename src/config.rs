@@ -1,30 +1,633 @@
-use std::path::PathBuf;
+use crate::chunker::ChunkParams;
+use crate::compress::{Codec, Compression};
+use crate::crypto::{self, Credentials};
+use crate::profile::Profile;
+use crate::prune::{self, RetentionPolicy};
+use crate::restore::Overwrite;
+use std::path::{self, PathBuf};
 
 #[derive(Debug, Clone)]
 pub struct BackupConfig {
-    pub source: PathBuf,
+    pub sources: Vec<PathBuf>,
     pub destination: PathBuf,
     pub threads: Option<usize>,
     pub verify: bool,
     pub includes: Vec<String>,
     pub excludes: Vec<String>,
     pub dry_run: bool,
+    pub compression: Compression,
+    pub encrypt: bool,
+    pub recipients: Vec<[u8; 32]>,
+    pub recipients_files: Vec<PathBuf>,
+    pub credentials: Credentials,
+    pub sign_key: Option<PathBuf>,
+    pub incremental: bool,
+    pub incremental_from: Option<PathBuf>,
+    pub differential_from: Option<PathBuf>,
+    /// times a file that changed while it was read is read again; files
+    /// larger than the retry buffer of the pipeline are never read again
+    pub change_retries: u32,
+    pub fail_on_change: bool,
 }
 
+/// How often a file that changed while it was read is read again by default.
+pub const DEFAULT_CHANGE_RETRIES: u32 = 2;
+
 impl BackupConfig {
-    pub fn from_args<I>(first_source: String, mut args: I) -> Result<Self, String>
+    pub fn from_args<I>(first_source: String, args: I) -> Result<Self, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        // Every path before the first flag is a source, except the last one.
+        let mut args = args.peekable();
+        let mut sources = vec![PathBuf::from(first_source)];
+        while let Some(path) = args.next_if(|a| !a.starts_with('-')) {
+            sources.push(PathBuf::from(path));
+        }
+        let Some(destination) = sources.pop().filter(|_| !sources.is_empty()) else {
+            return Err("missing <backup-dir> path".to_string());
+        };
+
+        let mut config = Self::new(sources, destination);
+        config.apply_flags(args)?;
+        Ok(config)
+    }
+
+    /// Builds the configuration of a profile, with `overrides` (command-line
+    /// flags) replacing the profile's values for the settings they name.
+    pub fn from_profile<I>(profile: &Profile, overrides: I) -> Result<Self, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let sources: Vec<PathBuf> = profile
+            .source
+            .iter()
+            .chain(&profile.sources)
+            .cloned()
+            .collect();
+        if sources.is_empty() {
+            return Err("no source or sources given".to_string());
+        }
+        let mut config = Self::new(sources, profile.destination.clone());
+        config.threads = profile.threads;
+        config.verify = profile.verify;
+        config.includes = profile.includes.clone();
+        config.excludes = profile.excludes.clone();
+        if let Some(name) = &profile.compress {
+            let codec = Codec::parse(name)?;
+            config.compression = Compression {
+                codec,
+                level: profile.compress_level.unwrap_or(codec.default_level()),
+            };
+        } else if profile.compress_level.is_some() {
+            return Err("compress_level requires compress".to_string());
+        }
+        config.encrypt = profile.encrypt;
+        config.recipients = profile
+            .recipients
+            .iter()
+            .map(|k| crypto::parse_public_key(k))
+            .collect::<Result<_, _>>()?;
+        config.recipients_files = profile.recipients_files.clone();
+        config.credentials.passphrase_file = profile.passphrase_file.clone();
+        config.credentials.identity_files = profile.identities.clone();
+        config.sign_key = profile.sign_key.clone();
+        config.incremental = profile.incremental;
+        config.incremental_from = profile.incremental_from.clone();
+        config.differential_from = profile.differential_from.clone();
+        if let Some(n) = profile.change_retries {
+            config.change_retries = n;
+        }
+        config.fail_on_change = profile.fail_on_change;
+        config.check()?;
+
+        config.apply_flags(overrides)?;
+        Ok(config)
+    }
+
+    /// The profile that reproduces this configuration, with every path made
+    /// absolute so it still applies when run from another directory.
+    pub fn to_profile(&self) -> Profile {
+        let absolute = |p: &PathBuf| path::absolute(p).unwrap_or_else(|_| p.clone());
+        let compressed = self.compression.codec != Codec::None;
+        Profile {
+            source: None,
+            sources: self.sources.iter().map(absolute).collect(),
+            destination: absolute(&self.destination),
+            includes: self.includes.clone(),
+            excludes: self.excludes.clone(),
+            threads: self.threads,
+            verify: self.verify,
+            compress: compressed.then(|| self.compression.codec.name().to_string()),
+            compress_level: compressed.then_some(self.compression.level),
+            encrypt: self.encrypt,
+            passphrase_file: self.credentials.passphrase_file.as_ref().map(absolute),
+            recipients: self
+                .recipients
+                .iter()
+                .map(crypto::public_key_string)
+                .collect(),
+            recipients_files: self.recipients_files.iter().map(absolute).collect(),
+            identities: self
+                .credentials
+                .identity_files
+                .iter()
+                .map(absolute)
+                .collect(),
+            sign_key: self.sign_key.as_ref().map(absolute),
+            incremental: self.incremental,
+            incremental_from: self.incremental_from.as_ref().map(absolute),
+            differential_from: self.differential_from.as_ref().map(absolute),
+            change_retries: Some(self.change_retries),
+            fail_on_change: self.fail_on_change,
+            retention: None,
+        }
+    }
+
+    fn new(sources: Vec<PathBuf>, destination: PathBuf) -> Self {
+        Self {
+            sources,
+            destination,
+            threads: None,
+            verify: false,
+            includes: Vec::new(),
+            excludes: Vec::new(),
+            dry_run: false,
+            compression: Compression {
+                codec: Codec::None,
+                level: Codec::None.default_level(),
+            },
+            encrypt: false,
+            recipients: Vec::new(),
+            recipients_files: Vec::new(),
+            credentials: Credentials::default(),
+            sign_key: None,
+            incremental: false,
+            incremental_from: None,
+            differential_from: None,
+            change_retries: DEFAULT_CHANGE_RETRIES,
+            fail_on_change: false,
+        }
+    }
+
+    /// Applies command-line flags. A repeatable flag replaces the current
+    /// list rather than adding to it, and choosing an incremental or
+    /// differential mode replaces the current one.
+    fn apply_flags<I>(&mut self, mut args: I) -> Result<(), String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let mut level = None;
+        let mut includes = Vec::new();
+        let mut excludes = Vec::new();
+        let mut recipients = Vec::new();
+        let mut recipients_files = Vec::new();
+        let mut modes = 0;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--threads" | "-j" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| "missing value for --threads".to_string())?;
+                    self.threads = Some(
+                        value
+                            .parse()
+                            .map_err(|_| "invalid value for --threads".to_string())?,
+                    );
+                }
+                "--verify" => self.verify = true,
+                "--include" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --include".to_string())?;
+                    includes.push(v);
+                }
+                "--exclude" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --exclude".to_string())?;
+                    excludes.push(v);
+                }
+                "--dry-run" => self.dry_run = true,
+                "--compress" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --compress".to_string())?;
+                    let codec = Codec::parse(&v)?;
+                    self.compression = Compression {
+                        codec,
+                        level: codec.default_level(),
+                    };
+                }
+                "--compress-level" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --compress-level".to_string())?;
+                    level = Some(
+                        v.parse()
+                            .map_err(|_| "invalid value for --compress-level".to_string())?,
+                    );
+                }
+                "--encrypt" => self.encrypt = true,
+                "--passphrase-file" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --passphrase-file".to_string())?;
+                    self.credentials.passphrase_file = Some(PathBuf::from(v));
+                }
+                "--recipient" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --recipient".to_string())?;
+                    recipients.push(crypto::parse_public_key(&v)?);
+                }
+                "--recipients-file" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --recipients-file".to_string())?;
+                    recipients_files.push(PathBuf::from(v));
+                }
+                "--sign-key" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --sign-key".to_string())?;
+                    self.sign_key = Some(PathBuf::from(v));
+                }
+                mode @ ("--incremental" | "--incremental-from" | "--differential-from") => {
+                    let path = match mode {
+                        "--incremental" => None,
+                        _ => Some(PathBuf::from(
+                            args.next()
+                                .ok_or_else(|| format!("missing value for {mode}"))?,
+                        )),
+                    };
+                    self.incremental = mode == "--incremental";
+                    self.incremental_from = None;
+                    self.differential_from = None;
+                    match mode {
+                        "--incremental-from" => self.incremental_from = path,
+                        "--differential-from" => self.differential_from = path,
+                        _ => {}
+                    }
+                    modes += 1;
+                }
+                "--change-retries" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --change-retries".to_string())?;
+                    self.change_retries = v
+                        .parse()
+                        .map_err(|_| "invalid value for --change-retries".to_string())?;
+                }
+                "--fail-on-change" => self.fail_on_change = true,
+                other => {
+                    return Err(format!("unknown argument: {other}"));
+                }
+            }
+        }
+
+        if modes > 1 {
+            return Err(
+                "--incremental, --incremental-from and --differential-from are mutually exclusive"
+                    .to_string(),
+            );
+        }
+        if let Some(level) = level {
+            self.compression.level = level;
+        }
+        for (list, given) in [
+            (&mut self.includes, includes),
+            (&mut self.excludes, excludes),
+        ] {
+            if !given.is_empty() {
+                *list = given;
+            }
+        }
+        if !recipients.is_empty() {
+            self.recipients = recipients;
+        }
+        if !recipients_files.is_empty() {
+            self.recipients_files = recipients_files;
+        }
+        self.check()
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.credentials.passphrase_file.is_some() && !self.encrypt {
+            return Err("--passphrase-file requires --encrypt".to_string());
+        }
+        if [
+            self.incremental,
+            self.incremental_from.is_some(),
+            self.differential_from.is_some(),
+        ]
+        .iter()
+        .filter(|set| **set)
+        .count()
+            > 1
+        {
+            return Err(
+                "--incremental, --incremental-from and --differential-from are mutually exclusive"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Flags of a backup that take a value, which `run` passes on along with it.
+const BACKUP_VALUE_FLAGS: &[&str] = &[
+    "--threads",
+    "-j",
+    "--include",
+    "--exclude",
+    "--compress",
+    "--compress-level",
+    "--passphrase-file",
+    "--recipient",
+    "--recipients-file",
+    "--sign-key",
+    "--incremental-from",
+    "--differential-from",
+    "--change-retries",
+];
+
+/// Arguments of `run`: a profile name, the configuration file and flags
+/// overriding the profile's settings.
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    pub profile: String,
+    pub config_file: Option<PathBuf>,
+    pub overrides: Vec<String>,
+}
+
+impl RunConfig {
+    pub fn from_args<I>(mut args: I) -> Result<Self, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let mut profile = None;
+        let mut config_file = None;
+        let mut overrides = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --config".to_string())?;
+                    config_file = Some(PathBuf::from(v));
+                }
+                flag if BACKUP_VALUE_FLAGS.contains(&flag) => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| format!("missing value for {flag}"))?;
+                    overrides.push(arg);
+                    overrides.push(v);
+                }
+                _ if profile.is_none() && !arg.starts_with('-') => profile = Some(arg),
+                _ => overrides.push(arg),
+            }
+        }
+
+        Ok(Self {
+            profile: profile.ok_or_else(|| "missing <profile> name".to_string())?,
+            config_file,
+            overrides,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RestoreConfig {
+    pub archive: PathBuf,
+    /// `None` restores every source to the location it was backed up from
+    pub destination: Option<PathBuf>,
+    pub paths: Vec<String>,
+    pub includes: Vec<String>,
+    pub excludes: Vec<String>,
+    pub allow_unsafe_paths: bool,
+    pub no_owner: bool,
+    pub credentials: Credentials,
+    /// base archive of a differential backup, if not next to it
+    pub base: Option<PathBuf>,
+    /// snapshot to restore when `archive` is a repository
+    pub snapshot: Option<String>,
+    /// leave files that already match their entry untouched, so an
+    /// interrupted restore can be continued
+    pub skip_identical: bool,
+    /// what happens to files already where entries are restored
+    pub overwrite: Overwrite,
+    /// restore to the original locations without asking first
+    pub yes: bool,
+    /// create original locations that no longer exist
+    pub create_root: bool,
+    /// report what would be restored without writing anything
+    pub dry_run: bool,
+    /// write the dry-run report as JSON
+    pub json: bool,
+}
+
+impl RestoreConfig {
+    pub fn from_args<I>(mut args: I) -> Result<Self, String>
     where
         I: Iterator<Item = String>,
     {
-        let destination = args
+        let mut positional = Vec::new();
+        let mut includes = Vec::new();
+        let mut excludes = Vec::new();
+        let mut allow_unsafe_paths = false;
+        let mut no_owner = false;
+        let mut credentials = Credentials::default();
+        let mut base = None;
+        let mut snapshot = None;
+        let mut to_original = false;
+        let mut skip_identical = false;
+        let mut overwrite = Overwrite::default();
+        let mut yes = false;
+        let mut create_root = false;
+        let mut dry_run = false;
+        let mut json = false;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--allow-unsafe-paths" => allow_unsafe_paths = true,
+                "--to-original" => to_original = true,
+                "--yes" => yes = true,
+                "--create-root" => create_root = true,
+                "--dry-run" => dry_run = true,
+                "--json" => json = true,
+                "--resume" | "--skip-existing-identical" => skip_identical = true,
+                "--overwrite" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --overwrite".to_string())?;
+                    overwrite = Overwrite::parse(&v)?;
+                }
+                "--snapshot" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --snapshot".to_string())?;
+                    snapshot = Some(v);
+                }
+                "--base" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --base".to_string())?;
+                    base = Some(PathBuf::from(v));
+                }
+                "--no-owner" => no_owner = true,
+                "--include" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --include".to_string())?;
+                    includes.push(v);
+                }
+                "--exclude" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --exclude".to_string())?;
+                    excludes.push(v);
+                }
+                "--passphrase-file" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --passphrase-file".to_string())?;
+                    credentials.passphrase_file = Some(PathBuf::from(v));
+                }
+                "--identity" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --identity".to_string())?;
+                    credentials.identity_files.push(PathBuf::from(v));
+                }
+                other if other.starts_with("--") => {
+                    return Err(format!("unknown argument: {other}"));
+                }
+                _ => positional.push(arg),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let archive = positional
             .next()
-            .ok_or_else(|| "missing <backup-dir> path".to_string())?;
+            .ok_or_else(|| "missing <backup-file> path".to_string())?;
+        let destination = match to_original {
+            true => None,
+            false => Some(PathBuf::from(positional.next().ok_or_else(|| {
+                "missing <restore-dir> path (or --to-original)".to_string()
+            })?)),
+        };
+        if json && !dry_run {
+            return Err("--json requires --dry-run".to_string());
+        }
+        if yes && !to_original {
+            return Err("--yes requires --to-original".to_string());
+        }
+        if create_root && !to_original {
+            return Err("--create-root requires --to-original".to_string());
+        }
+
+        Ok(Self {
+            archive: PathBuf::from(archive),
+            destination,
+            paths: positional.collect(),
+            includes,
+            excludes,
+            allow_unsafe_paths,
+            no_owner,
+            credentials,
+            base,
+            snapshot,
+            skip_identical,
+            overwrite,
+            yes,
+            create_root,
+            dry_run,
+            json,
+        })
+    }
+}
+
+/// Parses a byte size with an optional binary `K`, `M` or `G` suffix.
+fn parse_size(flag: &str, value: &str) -> Result<usize, String> {
+    let (digits, unit) = match value.trim_end_matches(['B', 'i']).char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 1024),
+        Some((i, 'M' | 'm')) => (&value[..i], 1024 * 1024),
+        Some((i, 'G' | 'g')) => (&value[..i], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("invalid value for {flag}: {value}"))
+}
+
+/// Arguments of `init`, which creates a repository.
+#[derive(Debug, Clone)]
+pub struct InitConfig {
+    pub repository: PathBuf,
+    pub chunking: ChunkParams,
+}
 
+impl InitConfig {
+    pub fn from_args<I>(mut args: I) -> Result<Self, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let mut repository = None;
+        let mut chunking = ChunkParams::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                flag @ ("--chunk-min" | "--chunk-avg" | "--chunk-max") => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| format!("missing value for {flag}"))?;
+                    let size = parse_size(flag, &v)?;
+                    match flag {
+                        "--chunk-min" => chunking.min_size = size,
+                        "--chunk-avg" => chunking.avg_size = size,
+                        _ => chunking.max_size = size,
+                    }
+                }
+                other if other.starts_with("--") => {
+                    return Err(format!("unknown argument: {other}"));
+                }
+                _ if repository.is_none() => repository = Some(PathBuf::from(arg)),
+                _ => return Err(format!("unexpected argument: {arg}")),
+            }
+        }
+        chunking.validate()?;
+
+        Ok(Self {
+            repository: repository.ok_or_else(|| "missing <repository> path".to_string())?,
+            chunking,
+        })
+    }
+}
+
+/// Arguments of `snapshot`, which stores a source tree in a repository.
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    pub source: PathBuf,
+    pub repository: PathBuf,
+    pub threads: Option<usize>,
+    pub includes: Vec<String>,
+    pub excludes: Vec<String>,
+}
+
+impl SnapshotConfig {
+    pub fn from_args<I>(mut args: I) -> Result<Self, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let mut positional = Vec::new();
         let mut threads = None;
-        let mut verify = false;
         let mut includes = Vec::new();
         let mut excludes = Vec::new();
-        let mut dry_run = false;
 
         while let Some(arg) = args.next() {
             match arg.as_str() {
@@ -38,7 +641,6 @@ impl BackupConfig {
                             .map_err(|_| "invalid value for --threads".to_string())?,
                     );
                 }
-                "--verify" => verify = true,
                 "--include" => {
                     let v = args
                         .next()
@@ -51,21 +653,406 @@ impl BackupConfig {
                         .ok_or_else(|| "missing value for --exclude".to_string())?;
                     excludes.push(v);
                 }
-                "--dry-run" => dry_run = true,
-                other => {
+                other if other.starts_with("--") => {
                     return Err(format!("unknown argument: {other}"));
                 }
+                _ => positional.push(arg),
             }
         }
 
+        let mut positional = positional.into_iter();
+        let source = positional
+            .next()
+            .ok_or_else(|| "missing <source-dir> path".to_string())?;
+        let repository = positional
+            .next()
+            .ok_or_else(|| "missing <repository> path".to_string())?;
+        if let Some(extra) = positional.next() {
+            return Err(format!("unexpected argument: {extra}"));
+        }
+
         Ok(Self {
-            source: PathBuf::from(first_source),
-            destination: PathBuf::from(destination),
+            source: PathBuf::from(source),
+            repository: PathBuf::from(repository),
             threads,
-            verify,
             includes,
             excludes,
+        })
+    }
+}
+
+/// Arguments of `prune`, which removes old archives from a backup directory.
+#[derive(Debug, Clone)]
+pub struct PruneConfig {
+    pub backup_dir: PathBuf,
+    /// only prune the archives of this source name
+    pub source: Option<String>,
+    pub policy: RetentionPolicy,
+    pub dry_run: bool,
+    pub credentials: Credentials,
+}
+
+impl PruneConfig {
+    pub fn from_args<I>(mut args: I) -> Result<Self, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let mut backup_dir = None;
+        let mut policy = RetentionPolicy::default();
+        let mut dry_run = false;
+        let mut credentials = Credentials::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dry-run" => dry_run = true,
+                flag @ ("--keep-last" | "--keep-hourly" | "--keep-daily" | "--keep-weekly"
+                | "--keep-monthly" | "--keep-yearly") => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| format!("missing value for {flag}"))?;
+                    let n: usize = v
+                        .parse()
+                        .map_err(|_| format!("invalid value for {flag}: {v}"))?;
+                    let field = match flag {
+                        "--keep-last" => &mut policy.keep_last,
+                        "--keep-hourly" => &mut policy.keep_hourly,
+                        "--keep-daily" => &mut policy.keep_daily,
+                        "--keep-weekly" => &mut policy.keep_weekly,
+                        "--keep-monthly" => &mut policy.keep_monthly,
+                        _ => &mut policy.keep_yearly,
+                    };
+                    *field = n;
+                }
+                "--keep-within" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --keep-within".to_string())?;
+                    policy.keep_within = Some(prune::parse_duration(&v)?);
+                }
+                "--passphrase-file" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --passphrase-file".to_string())?;
+                    credentials.passphrase_file = Some(PathBuf::from(v));
+                }
+                "--identity" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --identity".to_string())?;
+                    credentials.identity_files.push(PathBuf::from(v));
+                }
+                other if other.starts_with("--") => {
+                    return Err(format!("unknown argument: {other}"));
+                }
+                _ if backup_dir.is_none() => backup_dir = Some(PathBuf::from(arg)),
+                _ => return Err(format!("unexpected argument: {arg}")),
+            }
+        }
+
+        if policy.is_empty() {
+            return Err("no --keep-* option given; refusing to remove every archive".to_string());
+        }
+
+        Ok(Self {
+            backup_dir: backup_dir.ok_or_else(|| "missing <backup-dir> path".to_string())?,
+            source: None,
+            policy,
             dry_run,
+            credentials,
         })
     }
 }
+
+/// Arguments of `resume`: the partial archive of an interrupted backup and
+/// what is needed to unlock it.
+#[derive(Debug, Clone)]
+pub struct ResumeConfig {
+    pub partial: PathBuf,
+    pub threads: Option<usize>,
+    pub credentials: Credentials,
+}
+
+impl ResumeConfig {
+    pub fn from_args<I>(mut args: I) -> Result<Self, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let mut partial = None;
+        let mut threads = None;
+        let mut credentials = Credentials::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--threads" | "-j" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| "missing value for --threads".to_string())?;
+                    threads = Some(
+                        value
+                            .parse()
+                            .map_err(|_| "invalid value for --threads".to_string())?,
+                    );
+                }
+                "--passphrase-file" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --passphrase-file".to_string())?;
+                    credentials.passphrase_file = Some(PathBuf::from(v));
+                }
+                "--identity" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --identity".to_string())?;
+                    credentials.identity_files.push(PathBuf::from(v));
+                }
+                other if other.starts_with("--") => {
+                    return Err(format!("unknown argument: {other}"));
+                }
+                _ if partial.is_none() => partial = Some(PathBuf::from(arg)),
+                _ => return Err(format!("unexpected argument: {arg}")),
+            }
+        }
+
+        Ok(Self {
+            partial: partial.ok_or_else(|| "missing <partial-archive> path".to_string())?,
+            threads,
+            credentials,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CleanupConfig {
+    pub backup_dir: PathBuf,
+    pub dry_run: bool,
+}
+
+impl CleanupConfig {
+    pub fn from_args<I>(args: I) -> Result<Self, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let mut backup_dir = None;
+        let mut dry_run = false;
+
+        for arg in args {
+            match arg.as_str() {
+                "--dry-run" => dry_run = true,
+                other if other.starts_with("--") => {
+                    return Err(format!("unknown argument: {other}"));
+                }
+                _ if backup_dir.is_none() => backup_dir = Some(PathBuf::from(arg)),
+                _ => return Err(format!("unexpected argument: {arg}")),
+            }
+        }
+
+        Ok(Self {
+            backup_dir: backup_dir.ok_or_else(|| "missing <backup-dir> path".to_string())?,
+            dry_run,
+        })
+    }
+}
+
+/// Arguments of `inspect`, which only reads an archive.
+#[derive(Debug, Clone)]
+pub struct ArchiveConfig {
+    pub archive: PathBuf,
+    pub credentials: Credentials,
+}
+
+impl ArchiveConfig {
+    pub fn from_args<I>(mut args: I) -> Result<Self, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let mut archive = None;
+        let mut credentials = Credentials::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--passphrase-file" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --passphrase-file".to_string())?;
+                    credentials.passphrase_file = Some(PathBuf::from(v));
+                }
+                "--identity" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --identity".to_string())?;
+                    credentials.identity_files.push(PathBuf::from(v));
+                }
+                other if other.starts_with("--") => {
+                    return Err(format!("unknown argument: {other}"));
+                }
+                _ if archive.is_none() => archive = Some(PathBuf::from(arg)),
+                other => return Err(format!("unexpected argument: {other}")),
+            }
+        }
+
+        Ok(Self {
+            archive: archive.ok_or_else(|| "missing <backup-file> path".to_string())?,
+            credentials,
+        })
+    }
+}
+
+/// Arguments of `verify`. Every `--trusted-key` is a signing public key or a
+/// file listing such keys; with at least one given, the archive must carry a
+/// valid signature by one of them.
+#[derive(Debug, Clone)]
+pub struct VerifyConfig {
+    pub archive: PathBuf,
+    pub credentials: Credentials,
+    pub trusted_keys: Vec<String>,
+}
+
+impl VerifyConfig {
+    pub fn from_args<I>(mut args: I) -> Result<Self, String>
+    where
+        I: Iterator<Item = String>,
+    {
+        let mut archive = None;
+        let mut credentials = Credentials::default();
+        let mut trusted_keys = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--passphrase-file" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --passphrase-file".to_string())?;
+                    credentials.passphrase_file = Some(PathBuf::from(v));
+                }
+                "--identity" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --identity".to_string())?;
+                    credentials.identity_files.push(PathBuf::from(v));
+                }
+                "--trusted-key" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| "missing value for --trusted-key".to_string())?;
+                    trusted_keys.push(v);
+                }
+                other if other.starts_with("--") => {
+                    return Err(format!("unknown argument: {other}"));
+                }
+                _ if archive.is_none() => archive = Some(PathBuf::from(arg)),
+                other => return Err(format!("unexpected argument: {other}")),
+            }
+        }
+
+        Ok(Self {
+            archive: archive.ok_or_else(|| "missing <backup-file> path".to_string())?,
+            credentials,
+            trusted_keys,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> impl Iterator<Item = String> + use<> {
+        list.iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn profile(toml: &str) -> Profile {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn run_takes_flag_values_before_the_profile_name() {
+        let run = RunConfig::from_args(args(&["--threads", "4", "nightly", "--include", "*.rs"]))
+            .unwrap();
+        assert_eq!(run.profile, "nightly");
+        assert_eq!(run.overrides, ["--threads", "4", "--include", "*.rs"]);
+
+        let run =
+            RunConfig::from_args(args(&["--verify", "--config", "c.toml", "nightly"])).unwrap();
+        assert_eq!(run.profile, "nightly");
+        assert_eq!(run.config_file, Some(PathBuf::from("c.toml")));
+        assert_eq!(run.overrides, ["--verify"]);
+
+        let err = RunConfig::from_args(args(&["nightly", "--exclude"])).unwrap_err();
+        assert_eq!(err, "missing value for --exclude");
+        let err = RunConfig::from_args(args(&["-j", "4"])).unwrap_err();
+        assert_eq!(err, "missing <profile> name");
+    }
+
+    #[test]
+    fn command_line_overrides_profile_settings() {
+        let profile = profile(
+            r#"
+            source = "/data"
+            destination = "/backups"
+            threads = 2
+            includes = ["*.txt"]
+            excludes = ["*.tmp"]
+            compress = "zstd"
+            compress_level = 3
+            incremental_from = "/backups/base.backup"
+            "#,
+        );
+
+        let config = BackupConfig::from_profile(&profile, std::iter::empty()).unwrap();
+        assert_eq!(config.sources, [PathBuf::from("/data")]);
+        assert_eq!(config.threads, Some(2));
+        assert_eq!(config.compression.codec, Codec::Zstd);
+        assert_eq!(config.compression.level, 3);
+
+        let overrides = args(&[
+            "--threads",
+            "8",
+            "--include",
+            "*.rs",
+            "--compress-level",
+            "9",
+            "--incremental",
+        ]);
+        let config = BackupConfig::from_profile(&profile, overrides).unwrap();
+        assert_eq!(config.threads, Some(8));
+        assert_eq!(config.includes, ["*.rs"]);
+        assert_eq!(config.excludes, ["*.tmp"]);
+        assert_eq!(config.compression.codec, Codec::Zstd);
+        assert_eq!(config.compression.level, 9);
+        // one mode replaces the other instead of conflicting with it
+        assert!(config.incremental);
+        assert_eq!(config.incremental_from, None);
+    }
+
+    #[test]
+    fn invalid_profile_settings_are_rejected() {
+        let err = BackupConfig::from_profile(&profile(r#"destination = "/b""#), std::iter::empty())
+            .unwrap_err();
+        assert_eq!(err, "no source or sources given");
+
+        let levelled = profile(
+            r#"
+            source = "/data"
+            destination = "/backups"
+            compress_level = 3
+            "#,
+        );
+        let err = BackupConfig::from_profile(&levelled, std::iter::empty()).unwrap_err();
+        assert_eq!(err, "compress_level requires compress");
+
+        let plain = profile(
+            r#"
+            source = "/data"
+            destination = "/backups"
+            "#,
+        );
+        let err =
+            BackupConfig::from_profile(&plain, args(&["--passphrase-file", "pw"])).unwrap_err();
+        assert_eq!(err, "--passphrase-file requires --encrypt");
+        let err = BackupConfig::from_profile(&plain, args(&["--bogus"])).unwrap_err();
+        assert_eq!(err, "unknown argument: --bogus");
+    }
+}
@@ -0,0 +1,840 @@
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, UnboundKey};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const ENVELOPE_MAGIC: &[u8; 4] = b"BKEN";
+pub const ENVELOPE_VERSION: u32 = 1;
+
+/// Plaintext bytes sealed per chunk. Every chunk carries its own tag, so
+/// readers can authenticate and decrypt any part of the archive on its own.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+
+const SLOT_PASSPHRASE: u8 = 1;
+const SLOT_X25519: u8 = 2;
+
+pub const PUBLIC_KEY_PREFIX: &str = "backup-pub-";
+pub const SECRET_KEY_PREFIX: &str = "backup-secret-";
+const X25519_INFO: &[u8] = b"backup x25519 key wrap v1";
+
+const ARGON2_M_COST: u32 = 64 * 1024;
+const ARGON2_T_COST: u32 = 3;
+const ARGON2_P_COST: u32 = 1;
+/// Upper bound accepted from an archive header, to keep a crafted header
+/// from making us allocate unbounded memory before the passphrase is checked.
+const ARGON2_MAX_M_COST: u32 = 4 * 1024 * 1024;
+
+/// What can unlock an encrypted archive: identity files holding X25519
+/// secret keys, or a passphrase. Without a passphrase file the user is
+/// prompted on the terminal, but only once an archive that needs a
+/// passphrase is actually opened.
+#[derive(Clone, Default)]
+pub struct Credentials {
+    pub passphrase_file: Option<PathBuf>,
+    pub identity_files: Vec<PathBuf>,
+    /// Passphrase that was already entered, e.g. for the new archive of a
+    /// resumed backup, so the partial one is unlocked without asking again.
+    pub passphrase: Option<String>,
+    /// Data key of an archive that was just written, so it can be verified
+    /// without asking for the passphrase again or holding an identity.
+    pub data_key: Option<[u8; KEY_LEN]>,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("passphrase_file", &self.passphrase_file)
+            .field("identity_files", &self.identity_files)
+            .field(
+                "passphrase",
+                &self.passphrase.as_ref().map(|_| "<redacted>"),
+            )
+            .field("data_key", &self.data_key.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl Credentials {
+    /// Returns the passphrase, reading it from the passphrase file or
+    /// prompting for it. With `confirm` set, a prompted passphrase has to be
+    /// entered twice.
+    pub fn passphrase(&self, confirm: bool) -> io::Result<String> {
+        let passphrase = if let Some(passphrase) = &self.passphrase {
+            passphrase.clone()
+        } else if let Some(path) = &self.passphrase_file {
+            let raw = fs::read_to_string(path).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("failed to read passphrase file {:?}: {e}", path),
+                )
+            })?;
+            let line = raw.lines().next().unwrap_or("");
+            line.to_string()
+        } else {
+            let first = rpassword::prompt_password("passphrase: ")?;
+            if confirm {
+                let second = rpassword::prompt_password("confirm passphrase: ")?;
+                if first != second {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "passphrases do not match",
+                    ));
+                }
+            }
+            first
+        };
+
+        if passphrase.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "passphrase must not be empty",
+            ));
+        }
+        Ok(passphrase)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn key_from_hex(s: &str) -> Option<[u8; KEY_LEN]> {
+    if s.len() != KEY_LEN * 2 {
+        return None;
+    }
+    let mut out = [0u8; KEY_LEN];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+/// Parses a recipient public key as printed by `backup keygen`.
+pub fn parse_public_key(s: &str) -> Result<[u8; KEY_LEN], String> {
+    s.trim()
+        .strip_prefix(PUBLIC_KEY_PREFIX)
+        .and_then(key_from_hex)
+        .ok_or_else(|| format!("invalid recipient public key {s:?}"))
+}
+
+/// Formats a recipient public key the way `backup keygen` prints it.
+pub fn public_key_string(key: &[u8; KEY_LEN]) -> String {
+    format!("{PUBLIC_KEY_PREFIX}{}", to_hex(key))
+}
+
+/// Reads recipient public keys, one per line. Blank lines and lines
+/// starting with `#` are ignored.
+pub fn read_recipients_file(path: &Path) -> io::Result<Vec<[u8; KEY_LEN]>> {
+    let raw = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed to read recipients file {:?}: {e}", path),
+        )
+    })?;
+
+    raw.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            parse_public_key(l)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {e}", path)))
+        })
+        .collect()
+}
+
+fn read_identity_file(path: &Path) -> io::Result<Vec<StaticSecret>> {
+    let raw = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed to read identity file {:?}: {e}", path),
+        )
+    })?;
+
+    let keys: Vec<StaticSecret> = raw
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix(SECRET_KEY_PREFIX))
+        .map(|hex| {
+            key_from_hex(hex).map(StaticSecret::from).ok_or_else(|| {
+                invalid_data(format!("invalid secret key in identity file {:?}", path))
+            })
+        })
+        .collect::<io::Result<_>>()?;
+
+    if keys.is_empty() {
+        return Err(invalid_data(format!(
+            "no secret key found in identity file {:?}",
+            path
+        )));
+    }
+    Ok(keys)
+}
+
+/// Creates a new identity file holding a fresh X25519 secret key and
+/// returns the matching public key. The file is only readable by its owner
+/// and an existing file is never overwritten.
+pub fn write_identity_file(path: &Path) -> io::Result<String> {
+    let secret = StaticSecret::from(random_bytes::<KEY_LEN>()?);
+    let public = public_key_string(PublicKey::from(&secret).as_bytes());
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "# created: {created} (unix seconds)")?;
+    writeln!(file, "# public key: {public}")?;
+    writeln!(file, "{SECRET_KEY_PREFIX}{}", to_hex(secret.as_bytes()))?;
+    file.sync_all()?;
+    Ok(public)
+}
+
+fn random_bytes<const N: usize>() -> io::Result<[u8; N]> {
+    let mut out = [0u8; N];
+    SystemRandom::new()
+        .fill(&mut out)
+        .map_err(|_| io::Error::other("system random number generator failed"))?;
+    Ok(out)
+}
+
+fn aead_key(bytes: &[u8; KEY_LEN]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, bytes).expect("key length matches"))
+}
+
+fn derive_kek(
+    passphrase: &str,
+    salt: &[u8; SALT_LEN],
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+) -> io::Result<[u8; KEY_LEN]> {
+    let params = argon2::Params::new(m_cost, t_cost, p_cost, Some(KEY_LEN)).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid KDF parameters: {e}"),
+        )
+    })?;
+    let argon = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+
+    let mut out = [0u8; KEY_LEN];
+    argon
+        .hash_password_into(passphrase.as_bytes(), salt, &mut out)
+        .map_err(|e| io::Error::other(format!("key derivation failed: {e}")))?;
+    Ok(out)
+}
+
+/// Derives the key-encryption key for an X25519 slot from the shared secret,
+/// bound to both public keys involved.
+fn x25519_kek(shared: &[u8; 32], ephemeral: &[u8; 32], recipient: &[u8; 32]) -> [u8; KEY_LEN] {
+    let mut salt = Vec::with_capacity(64);
+    salt.extend_from_slice(ephemeral);
+    salt.extend_from_slice(recipient);
+
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(shared);
+    let okm = prk
+        .expand(&[X25519_INFO], hkdf::HKDF_SHA256)
+        .expect("output length is valid for HKDF-SHA256");
+    let mut out = [0u8; KEY_LEN];
+    okm.fill(&mut out)
+        .expect("output length is valid for HKDF-SHA256");
+    out
+}
+
+/// Encrypts a data key with a key-encryption key. Every KEK is derived from
+/// a fresh salt or ephemeral key and used exactly once, so a fixed nonce is
+/// safe here.
+fn wrap_key(kek: &[u8; KEY_LEN], data_key: &[u8; KEY_LEN]) -> io::Result<Vec<u8>> {
+    let mut sealed = data_key.to_vec();
+    aead_key(kek)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key([0u8; 12]),
+            Aad::empty(),
+            &mut sealed,
+        )
+        .map_err(|_| io::Error::other("failed to wrap data key"))?;
+    Ok(sealed)
+}
+
+fn unwrap_key(kek: &[u8; KEY_LEN], wrapped: &[u8]) -> Option<[u8; KEY_LEN]> {
+    let mut buf = wrapped.to_vec();
+    let plain = aead_key(kek)
+        .open_in_place(
+            Nonce::assume_unique_for_key([0u8; 12]),
+            Aad::empty(),
+            &mut buf,
+        )
+        .ok()?;
+    plain.try_into().ok()
+}
+
+/// Associated data of a chunk. The first chunk is bound to the envelope
+/// header, so swapping or editing key slots or the chunk size fails
+/// authentication like a damaged chunk does.
+fn chunk_aad(header: &[u8], index: u64) -> Aad<&[u8]> {
+    Aad::from(if index == 0 { header } else { &[] })
+}
+
+fn chunk_nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[3..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Header and data key of an archive that is about to be encrypted.
+pub struct Envelope {
+    header: Vec<u8>,
+    data_key: [u8; KEY_LEN],
+}
+
+impl Envelope {
+    /// Creates a fresh random data key with one key slot for `passphrase`,
+    /// if given, and one for each recipient public key.
+    pub fn new(passphrase: Option<&str>, recipients: &[[u8; KEY_LEN]]) -> io::Result<Self> {
+        let slot_count = passphrase.iter().count() + recipients.len();
+        if slot_count == 0 || slot_count > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid number of encryption key slots",
+            ));
+        }
+
+        let data_key = random_bytes::<KEY_LEN>()?;
+
+        let mut header = Vec::new();
+        header.extend_from_slice(ENVELOPE_MAGIC);
+        header.extend_from_slice(&ENVELOPE_VERSION.to_le_bytes());
+        header.extend_from_slice(&(CHUNK_SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&(slot_count as u16).to_le_bytes());
+
+        if let Some(passphrase) = passphrase {
+            let salt = random_bytes::<SALT_LEN>()?;
+            let kek = derive_kek(
+                passphrase,
+                &salt,
+                ARGON2_M_COST,
+                ARGON2_T_COST,
+                ARGON2_P_COST,
+            )?;
+            header.push(SLOT_PASSPHRASE);
+            header.extend_from_slice(&ARGON2_M_COST.to_le_bytes());
+            header.extend_from_slice(&ARGON2_T_COST.to_le_bytes());
+            header.extend_from_slice(&ARGON2_P_COST.to_le_bytes());
+            header.extend_from_slice(&salt);
+            header.extend_from_slice(&wrap_key(&kek, &data_key)?);
+        }
+
+        for recipient in recipients {
+            let ephemeral = StaticSecret::from(random_bytes::<KEY_LEN>()?);
+            let ephemeral_public = PublicKey::from(&ephemeral);
+            let shared = ephemeral.diffie_hellman(&PublicKey::from(*recipient));
+            let kek = x25519_kek(shared.as_bytes(), ephemeral_public.as_bytes(), recipient);
+            header.push(SLOT_X25519);
+            header.extend_from_slice(ephemeral_public.as_bytes());
+            header.extend_from_slice(&wrap_key(&kek, &data_key)?);
+        }
+
+        Ok(Self { header, data_key })
+    }
+
+    pub fn data_key(&self) -> [u8; KEY_LEN] {
+        self.data_key
+    }
+}
+
+/// Seals everything written to it in fixed-size chunks. [`finish`] must be
+/// called to write the final chunk, which is marked so that a truncated
+/// archive is detected on read.
+///
+/// [`finish`]: EncryptWriter::finish
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    key: LessSafeKey,
+    header: Vec<u8>,
+    buf: Vec<u8>,
+    index: u64,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut inner: W, envelope: &Envelope) -> io::Result<Self> {
+        inner.write_all(&envelope.header)?;
+        Ok(Self {
+            inner,
+            key: aead_key(&envelope.data_key),
+            header: envelope.header.clone(),
+            buf: Vec::with_capacity(CHUNK_SIZE + TAG_LEN),
+            index: 0,
+        })
+    }
+
+    fn seal_chunk(&mut self, last: bool) -> io::Result<()> {
+        let aad = chunk_aad(&self.header, self.index);
+        self.key
+            .seal_in_place_append_tag(chunk_nonce(self.index, last), aad, &mut self.buf)
+            .map_err(|_| io::Error::other("failed to encrypt archive chunk"))?;
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        self.index += 1;
+        Ok(())
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Bytes of plaintext sealed and handed to the inner writer so far. The
+    /// chunk still being filled is not included.
+    pub fn sealed_len(&self) -> u64 {
+        self.index * CHUNK_SIZE as u64
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal_chunk(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // A full chunk is only sealed once more data arrives, so the chunk
+        // left over at `finish` is always the one marked as last.
+        if self.buf.len() == CHUNK_SIZE && !data.is_empty() {
+            self.seal_chunk(false)?;
+        }
+        let n = data.len().min(CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Random-access plaintext view of an encrypted archive.
+pub struct DecryptReader<R: Read + Seek> {
+    inner: R,
+    /// Human-readable summary of the key slots, e.g. "1 passphrase".
+    pub key_slots: String,
+    key: LessSafeKey,
+    /// the envelope header, authenticated with the first chunk
+    header: Vec<u8>,
+    body_start: u64,
+    chunk_size: usize,
+    chunk_count: u64,
+    plain_len: u64,
+    /// a partial archive has no final chunk, see [`DecryptReader::open_partial`]
+    partial: bool,
+    pos: u64,
+    cached: Option<(u64, Vec<u8>)>,
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(msg: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<R: Read + Seek> DecryptReader<R> {
+    /// Parses the envelope header of `inner`, whose magic has already been
+    /// checked, and unlocks the data key. Identities are tried first; the
+    /// passphrase is only asked for when none of them is a recipient.
+    pub fn open(inner: R, credentials: &Credentials) -> io::Result<Self> {
+        Self::unlock(inner, credentials, false)
+    }
+
+    /// Like [`open`], for an archive that is still being written or was
+    /// interrupted. Only the complete chunks are readable and none of them
+    /// is expected to be the final one.
+    ///
+    /// [`open`]: DecryptReader::open
+    pub fn open_partial(inner: R, credentials: &Credentials) -> io::Result<Self> {
+        Self::unlock(inner, credentials, true)
+    }
+
+    fn unlock(mut inner: R, credentials: &Credentials, partial: bool) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(ENVELOPE_MAGIC.len() as u64))?;
+        let version = u32::from_le_bytes(read_array(&mut inner)?);
+        if version != ENVELOPE_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported encryption envelope version: {version}"
+            )));
+        }
+        let chunk_size = u32::from_le_bytes(read_array(&mut inner)?) as usize;
+        if chunk_size == 0 || chunk_size > 16 * 1024 * 1024 {
+            return Err(invalid_data("invalid encryption chunk size"));
+        }
+
+        let slot_count = u16::from_le_bytes(read_array(&mut inner)?);
+        let mut passphrase_slots = Vec::new();
+        let mut x25519_slots = Vec::new();
+        for _ in 0..slot_count {
+            let [kind] = read_array::<_, 1>(&mut inner)?;
+            match kind {
+                SLOT_PASSPHRASE => {
+                    let m_cost = u32::from_le_bytes(read_array(&mut inner)?);
+                    let t_cost = u32::from_le_bytes(read_array(&mut inner)?);
+                    let p_cost = u32::from_le_bytes(read_array(&mut inner)?);
+                    let salt: [u8; SALT_LEN] = read_array(&mut inner)?;
+                    let wrapped: [u8; KEY_LEN + TAG_LEN] = read_array(&mut inner)?;
+                    if m_cost > ARGON2_MAX_M_COST {
+                        return Err(invalid_data("KDF memory cost in header is too large"));
+                    }
+                    passphrase_slots.push((m_cost, t_cost, p_cost, salt, wrapped));
+                }
+                SLOT_X25519 => {
+                    let ephemeral: [u8; 32] = read_array(&mut inner)?;
+                    let wrapped: [u8; KEY_LEN + TAG_LEN] = read_array(&mut inner)?;
+                    x25519_slots.push((ephemeral, wrapped));
+                }
+                other => {
+                    return Err(invalid_data(format!("unknown key slot type {other}")));
+                }
+            }
+        }
+
+        if passphrase_slots.is_empty() && x25519_slots.is_empty() {
+            return Err(invalid_data("encrypted archive has no usable key slot"));
+        }
+
+        let mut key_slots = Vec::new();
+        if !passphrase_slots.is_empty() {
+            key_slots.push(format!("{} passphrase", passphrase_slots.len()));
+        }
+        if !x25519_slots.is_empty() {
+            key_slots.push(format!("{} recipient(s)", x25519_slots.len()));
+        }
+
+        let mut data_key = credentials.data_key;
+
+        if data_key.is_none() && !x25519_slots.is_empty() && !credentials.identity_files.is_empty()
+        {
+            let mut identities = Vec::new();
+            for path in &credentials.identity_files {
+                identities.extend(read_identity_file(path)?);
+            }
+            data_key = identities.iter().find_map(|secret| {
+                let public = PublicKey::from(secret);
+                x25519_slots.iter().find_map(|(ephemeral, wrapped)| {
+                    let shared = secret.diffie_hellman(&PublicKey::from(*ephemeral));
+                    let kek = x25519_kek(shared.as_bytes(), ephemeral, public.as_bytes());
+                    unwrap_key(&kek, wrapped)
+                })
+            });
+            if data_key.is_none() && passphrase_slots.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "wrong identity: none of the given identities is a recipient of this archive",
+                ));
+            }
+        }
+
+        if data_key.is_none() {
+            if passphrase_slots.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "archive is encrypted to public-key recipients only, pass --identity",
+                ));
+            }
+
+            let passphrase = credentials.passphrase(false)?;
+            for (m_cost, t_cost, p_cost, salt, wrapped) in &passphrase_slots {
+                let kek = derive_kek(&passphrase, salt, *m_cost, *t_cost, *p_cost)?;
+                if let Some(k) = unwrap_key(&kek, wrapped) {
+                    data_key = Some(k);
+                    break;
+                }
+            }
+        }
+
+        let Some(data_key) = data_key else {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "wrong passphrase: the archive key could not be unlocked",
+            ));
+        };
+
+        let body_start = inner.stream_position()?;
+        let mut header = vec![0u8; body_start as usize];
+        inner.seek(SeekFrom::Start(0))?;
+        inner.read_exact(&mut header)?;
+        let body_len = inner.seek(SeekFrom::End(0))? - body_start;
+        let sealed = (chunk_size + TAG_LEN) as u64;
+        let (chunk_count, plain_len) = if partial {
+            let chunk_count = body_len / sealed;
+            (chunk_count, chunk_count * chunk_size as u64)
+        } else {
+            let chunk_count = body_len.div_ceil(sealed);
+            let last_len = body_len - chunk_count.saturating_sub(1) * sealed;
+            if chunk_count == 0 || last_len < TAG_LEN as u64 {
+                return Err(invalid_data(
+                    "encrypted archive is truncated: missing final chunk",
+                ));
+            }
+            let plain_len = (chunk_count - 1) * chunk_size as u64 + last_len - TAG_LEN as u64;
+            (chunk_count, plain_len)
+        };
+
+        let mut reader = Self {
+            inner,
+            key_slots: key_slots.join(", "),
+            key: aead_key(&data_key),
+            header,
+            body_start,
+            chunk_size,
+            chunk_count,
+            plain_len,
+            partial,
+            pos: 0,
+            cached: None,
+        };
+        // Authenticating the final chunk up front catches truncation at a
+        // chunk boundary before anything is restored.
+        if !partial {
+            reader.load_chunk(chunk_count - 1)?;
+        }
+        Ok(reader)
+    }
+
+    pub fn plain_len(&self) -> u64 {
+        self.plain_len
+    }
+
+    fn load_chunk(&mut self, index: u64) -> io::Result<&[u8]> {
+        if self.cached.as_ref().is_none_or(|(i, _)| *i != index) {
+            let sealed = (self.chunk_size + TAG_LEN) as u64;
+            let start = self.body_start + index * sealed;
+            let len = if index + 1 == self.chunk_count {
+                self.plain_len - index * self.chunk_size as u64 + TAG_LEN as u64
+            } else {
+                sealed
+            };
+
+            let mut buf = vec![0u8; len as usize];
+            self.inner.seek(SeekFrom::Start(start))?;
+            self.inner.read_exact(&mut buf)?;
+
+            let last = !self.partial && index + 1 == self.chunk_count;
+            let plain_len = self
+                .key
+                .open_in_place(
+                    chunk_nonce(index, last),
+                    chunk_aad(&self.header, index),
+                    &mut buf,
+                )
+                .map_err(|_| {
+                    invalid_data(format!(
+                        "encrypted archive chunk {index} failed authentication: \
+                         the archive was tampered with, truncated or corrupted"
+                    ))
+                })?
+                .len();
+            buf.truncate(plain_len);
+            self.cached = Some((index, buf));
+        }
+
+        Ok(&self.cached.as_ref().expect("chunk just loaded").1)
+    }
+}
+
+impl<R: Read + Seek> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.plain_len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let chunk_size = self.chunk_size as u64;
+        let index = self.pos / chunk_size;
+        let offset = (self.pos % chunk_size) as usize;
+        let chunk = self.load_chunk(index)?;
+        let n = buf.len().min(chunk.len() - offset);
+        buf[..n].copy_from_slice(&chunk[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for DecryptReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.plain_len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        let Some(new_pos) = new_pos else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        };
+        self.pos = new_pos;
+        Ok(new_pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const SEALED: usize = CHUNK_SIZE + TAG_LEN;
+
+    fn seal(envelope: &Envelope, plain: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), envelope).unwrap();
+        writer.write_all(plain).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn open(sealed: Vec<u8>, credentials: &Credentials) -> io::Result<Vec<u8>> {
+        let mut reader = DecryptReader::open(Cursor::new(sealed), credentials)?;
+        let mut plain = Vec::new();
+        reader.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    fn with_key(envelope: &Envelope) -> Credentials {
+        Credentials {
+            data_key: Some(envelope.data_key()),
+            ..Credentials::default()
+        }
+    }
+
+    fn with_passphrase(passphrase: &str) -> Credentials {
+        Credentials {
+            passphrase: Some(passphrase.to_string()),
+            ..Credentials::default()
+        }
+    }
+
+    /// Three full chunks and a short final one.
+    fn plain() -> Vec<u8> {
+        (0..3 * CHUNK_SIZE + 100).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// An envelope with a single recipient slot, cheap to create.
+    fn envelope() -> Envelope {
+        let recipient = PublicKey::from(&StaticSecret::from([7u8; KEY_LEN]));
+        Envelope::new(None, &[*recipient.as_bytes()]).unwrap()
+    }
+
+    fn assert_tampered(result: io::Result<Vec<u8>>) {
+        let err = result.expect_err("damaged archive was accepted");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{err}");
+    }
+
+    #[test]
+    fn passphrase_round_trip() {
+        let envelope = Envelope::new(Some("correct horse"), &[]).unwrap();
+        let sealed = seal(&envelope, &plain());
+
+        assert_eq!(
+            open(sealed.clone(), &with_passphrase("correct horse")).unwrap(),
+            plain()
+        );
+        let err = open(sealed, &with_passphrase("wrong horse")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("wrong passphrase"), "{err}");
+    }
+
+    #[test]
+    fn recipient_archive_needs_a_matching_identity() {
+        let dir = std::env::temp_dir().join(format!("backup-crypto-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let recipient = dir.join("recipient.key");
+        let other = dir.join("other.key");
+        let public = parse_public_key(&write_identity_file(&recipient).unwrap()).unwrap();
+        write_identity_file(&other).unwrap();
+
+        let envelope = Envelope::new(None, &[public]).unwrap();
+        let sealed = seal(&envelope, &plain());
+        let identity = |path: &Path| Credentials {
+            identity_files: vec![path.to_path_buf()],
+            ..Credentials::default()
+        };
+
+        assert_eq!(
+            open(sealed.clone(), &identity(&recipient)).unwrap(),
+            plain()
+        );
+        let err = open(sealed, &identity(&other)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("wrong identity"), "{err}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty_archive_round_trip() {
+        let envelope = envelope();
+        let sealed = seal(&envelope, &[]);
+        assert_eq!(sealed.len(), envelope.header.len() + TAG_LEN);
+        assert!(open(sealed, &with_key(&envelope)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn tampered_chunk_fails() {
+        let envelope = envelope();
+        let mut sealed = seal(&envelope, &plain());
+        sealed[envelope.header.len() + SEALED + 10] ^= 0x01;
+        assert_tampered(open(sealed, &with_key(&envelope)));
+    }
+
+    #[test]
+    fn truncated_archive_fails() {
+        let envelope = envelope();
+        let sealed = seal(&envelope, &plain());
+
+        let mut short = sealed.clone();
+        short.truncate(sealed.len() - 1);
+        assert_tampered(open(short, &with_key(&envelope)));
+
+        // Cut at a chunk boundary, the remaining chunks are all intact but
+        // none of them is marked as the last one.
+        let mut short = sealed;
+        short.truncate(envelope.header.len() + 3 * SEALED);
+        assert_tampered(open(short, &with_key(&envelope)));
+    }
+
+    #[test]
+    fn reordered_chunks_fail() {
+        let envelope = envelope();
+        let mut sealed = seal(&envelope, &plain());
+        let body = &mut sealed[envelope.header.len()..];
+        let (first, second) = body[SEALED..3 * SEALED].split_at_mut(SEALED);
+        first.swap_with_slice(second);
+        assert_tampered(open(sealed, &with_key(&envelope)));
+    }
+
+    #[test]
+    fn header_is_authenticated() {
+        let envelope = envelope();
+        let mut sealed = seal(&envelope, b"short archive");
+        assert_eq!(
+            open(sealed.clone(), &with_key(&envelope)).unwrap(),
+            b"short archive"
+        );
+
+        // The data key is given directly, so the edited key slot is never
+        // used to unwrap it; only the first chunk's associated data notices.
+        let slot = ENVELOPE_MAGIC.len() + 4 + 4 + 2 + 1;
+        sealed[slot] ^= 0x01;
+        assert_tampered(open(sealed, &with_key(&envelope)));
+    }
+}
@@ -43,15 +43,17 @@ impl PathFilter {
         Ok(Self { include, exclude })
     }
 
-    pub fn allow(&self, rel: &Path) -> bool {
+    pub fn is_excluded(&self, rel: &Path) -> bool {
         let s = rel.to_string_lossy().replace('\\', "/");
+        self.exclude.as_ref().is_some_and(|ex| ex.is_match(&s))
+    }
 
-        if let Some(ex) = &self.exclude {
-            if ex.is_match(&s) {
-                return false;
-            }
+    pub fn allow(&self, rel: &Path) -> bool {
+        if self.is_excluded(rel) {
+            return false;
         }
 
+        let s = rel.to_string_lossy().replace('\\', "/");
         if let Some(inc) = &self.include {
             inc.is_match(&s)
         } else {
@@ -1,30 +1,81 @@
 use crate::filter::PathFilter;
-use crate::types::FileEntry;
-use std::path::Path;
+use crate::metadata;
+use crate::types::{EntryKind, FileEntry, SourceRoot};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
 use walkdir::{DirEntry, WalkDir};
 
-fn is_file(entry: &DirEntry) -> bool {
-    entry.file_type().is_file()
+fn entry_kind(entry: &DirEntry) -> Option<EntryKind> {
+    let ft = entry.file_type();
+    if ft.is_file() {
+        Some(EntryKind::File)
+    } else if ft.is_dir() {
+        Some(EntryKind::Dir)
+    } else if ft.is_symlink() {
+        Some(EntryKind::Symlink)
+    } else if ft.is_fifo() {
+        Some(EntryKind::Fifo)
+    } else if ft.is_char_device() {
+        Some(EntryKind::CharDevice)
+    } else if ft.is_block_device() {
+        Some(EntryKind::BlockDevice)
+    } else {
+        None
+    }
 }
 
+/// Scans `root` for regular files, directories, symlinks, FIFOs and device
+/// nodes. Sockets are skipped since they cannot be meaningfully restored, and
+/// symlinks are recorded rather than followed. Further paths of a file that
+/// is already part of the scan (same device and inode) become hard link
+/// entries pointing at the first one.
 pub fn scan_dir_with_filter(root: &Path, filter: Option<&PathFilter>) -> Vec<FileEntry> {
+    let root = SourceRoot {
+        name: String::new(),
+        path: root.to_path_buf(),
+    };
+    scan_roots(&[root], filter)
+}
+
+/// Scans every root like [`scan_dir_with_filter`]. Filters apply to archive
+/// paths, so below a named root they start with the root's name, and a named
+/// root's directory itself is an entry too.
+pub fn scan_roots(roots: &[SourceRoot], filter: Option<&PathFilter>) -> Vec<FileEntry> {
     let mut out = Vec::new();
+    let mut inodes: HashMap<(u64, u64), String> = HashMap::new();
+    for root in roots {
+        scan_root(root, filter, &mut inodes, &mut out);
+    }
+    out
+}
 
-    for entry in WalkDir::new(root)
+fn scan_root(
+    root: &SourceRoot,
+    filter: Option<&PathFilter>,
+    inodes: &mut HashMap<(u64, u64), String>,
+    out: &mut Vec<FileEntry>,
+) {
+    for entry in WalkDir::new(&root.path)
+        .min_depth(if root.name.is_empty() { 1 } else { 0 })
         .follow_links(false)
         .into_iter()
         .filter_map(|e| e.ok())
-        .filter(|e| is_file(e))
     {
-        let rel = entry
-            .path()
-            .strip_prefix(root)
-            .unwrap_or_else(|_| entry.path());
-
-        if let Some(f) = filter {
-            if !f.allow(rel) {
-                continue;
-            }
+        let Some(kind) = entry_kind(&entry) else {
+            continue;
+        };
+
+        let rel = root
+            .archive_path(entry.path())
+            .unwrap_or_else(|| PathBuf::from(entry.path()));
+
+        if let Some(f) = filter
+            && !f.allow(&rel)
+        {
+            continue;
         }
 
         let md = match entry.metadata() {
@@ -32,11 +83,37 @@ pub fn scan_dir_with_filter(root: &Path, filter: Option<&PathFilter>) -> Vec<Fil
             Err(_) => continue,
         };
 
+        let (kind, link_target) = if kind == EntryKind::File && md.nlink() > 1 {
+            let rel_str = rel.to_string_lossy().to_string();
+            match inodes.entry((md.dev(), md.ino())) {
+                Entry::Occupied(first) => (EntryKind::HardLink, Some(first.get().clone())),
+                Entry::Vacant(slot) => {
+                    slot.insert(rel_str);
+                    (kind, None)
+                }
+            }
+        } else if kind == EntryKind::Symlink {
+            match fs::read_link(entry.path()) {
+                Ok(t) => (kind, Some(t.to_string_lossy().to_string())),
+                Err(e) => {
+                    eprintln!("warning: failed to read symlink {:?}: {e}", entry.path());
+                    continue;
+                }
+            }
+        } else {
+            (kind, None)
+        };
+
         out.push(FileEntry {
             path: entry.path().to_path_buf(),
-            size: md.len(),
+            size: if kind == EntryKind::File { md.len() } else { 0 },
+            kind,
+            meta: metadata::capture(entry.path(), &md),
+            link_target,
+            rdev: match kind {
+                EntryKind::CharDevice | EntryKind::BlockDevice => md.rdev(),
+                _ => 0,
+            },
         });
     }
-
-    out
 }
@@ -0,0 +1,203 @@
+use crate::archive::ArchiveReader;
+use crate::crypto::Credentials;
+use crate::metadata::FileMeta;
+use crate::types::{self, BackupType, EntryKind, FileEntry, ParentRef, SourceRoot};
+use ring::digest;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+struct ManifestFile {
+    pub path: String,
+    #[serde(default)]
+    pub kind: EntryKind,
+    pub size: u64,
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub meta: Option<FileMeta>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BackupManifest {
+    pub source: String,
+    #[serde(default)]
+    pub backup_type: BackupType,
+    pub files: Vec<ManifestFile>,
+}
+
+pub fn manifest_sha256(manifest_bytes: &[u8]) -> String {
+    digest::digest(&digest::SHA256, manifest_bytes)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn hash_from_hex(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+fn rel_path(roots: &[SourceRoot], entry: &FileEntry) -> String {
+    types::archive_path(roots, &entry.path)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Finds the newest archive of `source_name` in `backup_dir`, going by the
+/// timestamp in its `<source>-<unix seconds>.backup` file name.
+pub fn find_latest(backup_dir: &Path, source_name: &str) -> io::Result<Option<PathBuf>> {
+    let prefix = format!("{source_name}-");
+    let mut latest: Option<(u64, PathBuf)> = None;
+
+    for dent in fs::read_dir(backup_dir)? {
+        let dent = dent?;
+        let name = dent.file_name();
+        let Some(ts) = name
+            .to_str()
+            .and_then(|n| n.strip_prefix(&prefix))
+            .and_then(|n| n.strip_suffix(".backup"))
+            .and_then(|n| n.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        if latest.as_ref().is_none_or(|(best, _)| ts > *best) {
+            latest = Some((ts, dent.path()));
+        }
+    }
+
+    Ok(latest.map(|(_, path)| path))
+}
+
+/// Where the archive `reference` points to is expected: next to `child`,
+/// the archive that depends on it.
+pub fn sibling_path(child: &Path, reference: &ParentRef) -> io::Result<PathBuf> {
+    let name = Path::new(&reference.backup_file);
+    if name.components().count() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid archive name {:?}", reference.backup_file),
+        ));
+    }
+    Ok(child.parent().unwrap_or(Path::new(".")).join(name))
+}
+
+/// Opens the archive at `path` as the `role` ("parent" or "base") of another
+/// one, refusing it unless it still has the manifest recorded in `reference`.
+pub fn open_reference(
+    path: &Path,
+    role: &str,
+    reference: &ParentRef,
+    credentials: &Credentials,
+) -> io::Result<ArchiveReader> {
+    let archive = ArchiveReader::open(path, credentials).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed to open {role} archive {:?}: {e}", path),
+        )
+    })?;
+    if manifest_sha256(&archive.manifest_bytes) != reference.manifest_sha256 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{role} archive {:?} is not the one this backup was made against \
+                 (manifest hash differs)",
+                path
+            ),
+        ));
+    }
+    Ok(archive)
+}
+
+/// The archive an incremental or differential backup is compared against.
+pub struct Parent {
+    pub reference: ParentRef,
+    pub source: String,
+    pub backup_type: BackupType,
+    files: HashMap<String, ManifestFile>,
+}
+
+impl Parent {
+    pub fn load(path: &Path, credentials: &Credentials) -> io::Result<Self> {
+        let archive = ArchiveReader::open(path, credentials)?;
+        let manifest: BackupManifest = archive.manifest()?;
+        let backup_file = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid parent archive path {:?}", path),
+                )
+            })?;
+
+        Ok(Self {
+            reference: ParentRef {
+                backup_file,
+                manifest_sha256: manifest_sha256(&archive.manifest_bytes),
+            },
+            source: manifest.source,
+            backup_type: manifest.backup_type,
+            files: manifest
+                .files
+                .into_iter()
+                .map(|f| (f.path.clone(), f))
+                .collect(),
+        })
+    }
+
+    /// Returns the hash recorded for `entry` when it is still a regular file
+    /// with the same size and modification time, so it need not be rehashed.
+    pub fn unchanged_hash(&self, roots: &[SourceRoot], entry: &FileEntry) -> Option<[u8; 32]> {
+        let prev = self.files.get(&rel_path(roots, entry))?;
+        let meta = prev.meta.as_ref()?;
+        if prev.kind != EntryKind::File
+            || prev.size != entry.size
+            || meta.mtime != entry.meta.mtime
+            || meta.mtime_nsec != entry.meta.mtime_nsec
+        {
+            return None;
+        }
+        hash_from_hex(prev.sha256.as_deref()?)
+    }
+
+    /// Whether the parent records a regular file of the same size at the
+    /// path of `entry`, so a touched file may still have the same data.
+    pub fn may_have_data(&self, roots: &[SourceRoot], entry: &FileEntry) -> bool {
+        self.files
+            .get(&rel_path(roots, entry))
+            .is_some_and(|prev| prev.kind == EntryKind::File && prev.size == entry.size)
+    }
+
+    /// Whether the parent already holds the data of `entry`, e.g. for a file
+    /// that was only touched.
+    pub fn has_data(&self, roots: &[SourceRoot], entry: &FileEntry, hash: &[u8; 32]) -> bool {
+        self.files.get(&rel_path(roots, entry)).is_some_and(|prev| {
+            prev.kind == EntryKind::File
+                && prev.sha256.as_deref().and_then(hash_from_hex).as_ref() == Some(hash)
+        })
+    }
+
+    /// Paths the parent recorded that no longer exist in the source tree.
+    pub fn deleted(&self, roots: &[SourceRoot], current: &[&FileEntry]) -> Vec<String> {
+        let current: HashSet<String> = current.iter().map(|e| rel_path(roots, e)).collect();
+        let mut deleted: Vec<String> = self
+            .files
+            .keys()
+            .filter(|p| !current.contains(p.as_str()))
+            .cloned()
+            .collect();
+        deleted.sort();
+        deleted
+    }
+}
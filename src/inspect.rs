@@ -1,19 +1,41 @@
+use crate::archive::ArchiveReader;
+use crate::crypto::Credentials;
+use crate::repository::{self, Repository};
+use crate::signing;
+use crate::types::{BackupType, EntryKind, ParentRef, SourceRoot};
 use serde::Deserialize;
-use std::fs::File;
-use std::io::{self, BufReader, Read};
+use std::io;
 use std::path::Path;
 
 #[derive(Debug, Deserialize)]
 struct ManifestFile {
     pub path: String,
+    #[serde(default)]
+    pub kind: EntryKind,
     pub size: u64,
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub from_parent: bool,
+    #[serde(default)]
+    pub changed_during_backup: bool,
 }
 
 #[derive(Debug, Deserialize)]
 struct BackupManifest {
     pub source: String,
+    #[serde(default)]
+    pub roots: Vec<SourceRoot>,
     pub backup_file: String,
     pub created_at: u64,
+    #[serde(default)]
+    pub backup_type: BackupType,
+    #[serde(default)]
+    pub parent: Option<ParentRef>,
+    #[serde(default)]
+    pub base: Option<ParentRef>,
+    #[serde(default)]
+    pub deleted: Vec<String>,
     pub files: Vec<ManifestFile>,
 }
 
@@ -26,65 +48,168 @@ fn print_kv<K: AsRef<str>, V: AsRef<str>>(k: K, v: V) {
     println!("  {:12} {}", format!("{}:", k.as_ref()), v.as_ref());
 }
 
-pub fn inspect_backup(path: &Path) -> io::Result<()> {
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
-
-    let mut magic = [0u8; 4];
-    reader.read_exact(&mut magic)?;
-    if &magic != b"BKUP" {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid magic, not a .backup file",
-        ));
+pub fn inspect_backup(path: &Path, credentials: &Credentials) -> io::Result<()> {
+    if repository::is_repository(path) {
+        return inspect_repository(path);
     }
 
-    let mut ver_bytes = [0u8; 4];
-    reader.read_exact(&mut ver_bytes)?;
-    let version = u32::from_le_bytes(ver_bytes);
-    if version != 1 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("unsupported backup version: {version}"),
-        ));
-    }
-
-    let mut len_bytes = [0u8; 8];
-    reader.read_exact(&mut len_bytes)?;
-    let manifest_len = u64::from_le_bytes(len_bytes) as usize;
-
-    let mut manifest_bytes = vec![0u8; manifest_len];
-    reader.read_exact(&mut manifest_bytes)?;
-
-    let manifest: BackupManifest = serde_json::from_slice(&manifest_bytes).map_err(|e| {
-        io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("failed to parse embedded manifest: {e}"),
-        )
-    })?;
+    let archive = ArchiveReader::open(path, credentials)?;
+    let manifest: BackupManifest = archive.manifest()?;
 
     let total_files = manifest.files.len();
-    let total_bytes: u64 = manifest.files.iter().map(|f| f.size).sum();
+    let count_kind = |kind: EntryKind| manifest.files.iter().filter(|f| f.kind == kind).count();
+    let total_bytes = manifest
+        .files
+        .iter()
+        .fold(0u64, |total, f| total.saturating_add(f.size));
 
     println!("==================== backup inspect ====================");
-    print_kv("archive", &path.to_string_lossy());
+    print_kv("archive", path.to_string_lossy());
 
     print_section("info");
-    print_kv("source", &manifest.source);
+    print_kv(
+        "format",
+        match &archive.index {
+            Some(index) => format!("v{} (indexed, {} entries)", archive.version, index.len()),
+            None => format!("v{}", archive.version),
+        },
+    );
+    if let Some(slots) = &archive.encryption {
+        print_kv("encryption", format!("chacha20-poly1305 ({slots})"));
+    }
+    if let Some(sig) = &archive.signature {
+        print_kv("signed by", signing::public_key_string(&sig.public_key));
+    }
+    if manifest.roots.is_empty() {
+        print_kv("source", &manifest.source);
+    }
+    for root in &manifest.roots {
+        print_kv(
+            "source",
+            format!("{} as {}", root.path.to_string_lossy(), root.name),
+        );
+    }
     print_kv("backup file", &manifest.backup_file);
+    print_kv("type", manifest.backup_type.as_str());
     print_kv(
         "created_at",
         format!("{} (unix seconds)", manifest.created_at),
     );
-    print_kv("files", total_files.to_string());
+    print_kv("files", count_kind(EntryKind::File).to_string());
+    print_kv("dirs", count_kind(EntryKind::Dir).to_string());
+    print_kv("symlinks", count_kind(EntryKind::Symlink).to_string());
+    print_kv("hard links", count_kind(EntryKind::HardLink).to_string());
+    let special = total_files
+        - count_kind(EntryKind::File)
+        - count_kind(EntryKind::Dir)
+        - count_kind(EntryKind::Symlink)
+        - count_kind(EntryKind::HardLink);
+    if special > 0 {
+        print_kv("special", special.to_string());
+    }
     print_kv("total bytes", total_bytes.to_string());
+    print_kv("stored", archive.stored_bytes().to_string());
+    let changed = manifest
+        .files
+        .iter()
+        .filter(|f| f.changed_during_backup)
+        .count();
+    if changed > 0 {
+        print_kv(
+            "changed",
+            format!("{changed} file(s) changed during backup"),
+        );
+    }
+
+    let link = match (&manifest.parent, &manifest.base) {
+        (Some(parent), _) => Some(("parent", parent)),
+        (None, Some(base)) => Some(("base", base)),
+        (None, None) => None,
+    };
+    if let Some((role, reference)) = link {
+        print_section(manifest.backup_type.as_str());
+        print_kv(role, &reference.backup_file);
+        print_kv(format!("{role} hash"), &reference.manifest_sha256);
+        print_kv(
+            format!("from {role}"),
+            manifest
+                .files
+                .iter()
+                .filter(|f| f.from_parent)
+                .count()
+                .to_string(),
+        );
+        print_kv("deleted", manifest.deleted.len().to_string());
+    }
 
-    print_section("sample files");
+    print_section("sample entries");
     for f in manifest.files.iter().take(10) {
-        println!("  - {} ({} bytes)", f.path, f.size);
+        match f.kind {
+            EntryKind::File if f.from_parent => {
+                println!("  - {} ({} bytes, in parent)", f.path, f.size)
+            }
+            EntryKind::File if f.changed_during_backup => {
+                println!("  - {} ({} bytes, changed during backup)", f.path, f.size)
+            }
+            EntryKind::File => println!("  - {} ({} bytes)", f.path, f.size),
+            EntryKind::Dir => println!("  - {}/", f.path),
+            EntryKind::Symlink => {
+                println!("  - {} -> {}", f.path, f.target.as_deref().unwrap_or("?"))
+            }
+            EntryKind::HardLink => {
+                println!("  - {} => {}", f.path, f.target.as_deref().unwrap_or("?"))
+            }
+            EntryKind::Fifo => println!("  - {} (fifo)", f.path),
+            EntryKind::CharDevice => println!("  - {} (char device)", f.path),
+            EntryKind::BlockDevice => println!("  - {} (block device)", f.path),
+        }
     }
     if total_files > 10 {
-        println!("  ... ({} more files)", total_files - 10);
+        println!("  ... ({} more entries)", total_files - 10);
+    }
+
+    Ok(())
+}
+
+fn inspect_repository(path: &Path) -> io::Result<()> {
+    let repo = Repository::open(path)?;
+    let snapshots = repo.snapshots()?;
+
+    println!("==================== backup inspect ====================");
+    print_kv("repository", path.to_string_lossy());
+
+    print_section("info");
+    let chunking = repo.chunking();
+    print_kv(
+        "chunking",
+        format!(
+            "min {} / avg {} / max {} bytes",
+            chunking.min_size, chunking.avg_size, chunking.max_size
+        ),
+    );
+    print_kv("packs", repo.pack_count().to_string());
+    print_kv("blobs", repo.blob_count().to_string());
+    print_kv("blob bytes", repo.blob_bytes().to_string());
+    print_kv("snapshots", snapshots.len().to_string());
+
+    print_section("snapshots");
+    for name in &snapshots {
+        let (_, manifest_bytes) = repo.load_snapshot(name)?;
+        let manifest: BackupManifest = serde_json::from_slice(&manifest_bytes).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("failed to parse snapshot {name:?}: {e}"),
+            )
+        })?;
+        let bytes = manifest
+            .files
+            .iter()
+            .fold(0u64, |total, f| total.saturating_add(f.size));
+        println!(
+            "  - {name} ({} entries, {bytes} bytes, from {})",
+            manifest.files.len(),
+            manifest.source
+        );
     }
 
     Ok(())
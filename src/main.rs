@@ -1,29 +1,74 @@
+mod archive;
 mod backup_file;
+mod chunker;
+mod cleanup;
+mod compress;
 mod config;
+mod crypto;
 mod filter;
 mod fs_scan;
 mod hasher;
+mod incremental;
 mod inspect;
 mod manifest;
+mod metadata;
 mod pipeline;
+mod profile;
+mod prune;
+mod repository;
 mod restore;
+mod resume;
+mod safe_path;
+mod signing;
+mod snapshot;
 mod types;
 mod validation;
 mod verify_archive;
 
 use indicatif::ProgressBar;
-use pipeline::hash_files_parallel;
+use pipeline::{HashedFile, hash_files_parallel};
 use rayon::ThreadPoolBuilder;
+use ring::signature::KeyPair;
+use std::collections::HashSet;
 use std::env;
-use std::path::Path;
+use std::fs;
+use std::io;
+use std::path::{self, Path};
+use std::process;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::config::BackupConfig;
+use crate::backup_file::ArchiveOptions;
+use crate::compress::Codec;
+use crate::config::{
+    ArchiveConfig, BackupConfig, CleanupConfig, InitConfig, PruneConfig, RestoreConfig,
+    ResumeConfig, RunConfig, SnapshotConfig, VerifyConfig,
+};
+use crate::crypto::{Credentials, Envelope};
 use crate::filter::PathFilter;
-use crate::manifest::build_manifest_json;
+use crate::incremental::Parent;
+use crate::manifest::{Delta, build_manifest_json};
+use crate::resume::{Checkpoint, CheckpointHeader};
+use crate::types::{BackupType, EntryKind, FileEntry};
 use crate::validation::validate_paths;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const CREATE_USAGE: &str = "backup <source-dir>... <backup-dir> [--threads N] [--verify] [--include P] [--exclude P] [--dry-run] [--compress zstd|lz4|none] [--compress-level N] [--encrypt] [--passphrase-file F] [--recipient K] [--recipients-file F] [--sign-key F] [--incremental | --incremental-from F | --differential-from F] [--change-retries N] [--fail-on-change]";
+const RESTORE_USAGE: &str = "backup restore <backup-file|repository> <restore-dir | --to-original [--yes] [--create-root]> [path...] [--include P] [--exclude P] [--allow-unsafe-paths] [--no-owner] [--passphrase-file F] [--identity F] [--base F] [--snapshot ID] [--resume] [--overwrite POLICY] [--dry-run [--json]]";
+const INSPECT_USAGE: &str =
+    "backup inspect <backup-file|repository> [--passphrase-file F] [--identity F]";
+const VERIFY_USAGE: &str =
+    "backup verify  <backup-file> [--passphrase-file F] [--identity F] [--trusted-key K|F]";
+const KEYGEN_USAGE: &str = "backup keygen  [--sign] <key-file>";
+const RESUME_USAGE: &str =
+    "backup resume <partial-archive> [--passphrase-file F] [--identity F] [--threads N]";
+const CLEANUP_USAGE: &str = "backup cleanup <backup-dir> [--dry-run]";
+const PRUNE_USAGE: &str = "backup prune  <backup-dir> [--keep-last N] [--keep-hourly N] [--keep-daily N] [--keep-weekly N] [--keep-monthly N] [--keep-yearly N] [--keep-within DURATION] [--dry-run] [--passphrase-file F] [--identity F]";
+const RUN_USAGE: &str = "backup run    <profile> [--config F] [create options...]";
+const CONFIG_USAGE: &str = "backup config check [--config F]";
+const INIT_USAGE: &str =
+    "backup init    <repository> [--chunk-min SIZE] [--chunk-avg SIZE] [--chunk-max SIZE]";
+const SNAPSHOT_USAGE: &str =
+    "backup snapshot <source-dir> <repository> [--threads N] [--include P] [--exclude P]";
 
 fn print_banner(title: &str) {
     println!();
@@ -45,12 +90,18 @@ fn main() {
     let mut args = env::args().skip(1);
     let Some(first) = args.next() else {
         print_banner("usage");
-        println!(
-            "  backup <source-dir> <backup-dir> [--threads N] [--verify] [--include P] [--exclude P] [--dry-run]"
-        );
-        println!("  backup inspect <backup-file>");
-        println!("  backup restore <backup-file> <restore-dir>");
-        println!("  backup verify  <backup-file>");
+        println!("  {CREATE_USAGE}");
+        println!("  {INSPECT_USAGE}");
+        println!("  {RESTORE_USAGE}");
+        println!("  {VERIFY_USAGE}");
+        println!("  {KEYGEN_USAGE}");
+        println!("  {PRUNE_USAGE}");
+        println!("  {RESUME_USAGE}");
+        println!("  {CLEANUP_USAGE}");
+        println!("  {RUN_USAGE}");
+        println!("  {CONFIG_USAGE}");
+        println!("  {INIT_USAGE}");
+        println!("  {SNAPSHOT_USAGE}");
         println!("  backup --version | -V");
         return;
     };
@@ -61,45 +112,251 @@ fn main() {
     }
 
     if first == "inspect" {
-        let Some(archive) = args.next() else {
-            print_banner("inspect usage");
-            println!("  backup inspect <backup-file>");
-            return;
+        let config = match ArchiveConfig::from_args(args) {
+            Ok(c) => c,
+            Err(e) => {
+                print_banner("inspect usage");
+                eprintln!("error: {e}");
+                println!();
+                println!("  {INSPECT_USAGE}");
+                return;
+            }
         };
 
-        if let Err(e) = inspect::inspect_backup(Path::new(&archive)) {
+        if let Err(e) = inspect::inspect_backup(&config.archive, &config.credentials) {
             eprintln!("inspect failed: {e}");
+            process::exit(1);
         }
         return;
     }
 
     if first == "restore" {
-        let Some(archive) = args.next() else {
-            print_banner("restore usage");
-            println!("  backup restore <backup-file> <restore-dir>");
-            return;
-        };
-        let Some(dest) = args.next() else {
-            print_banner("restore usage");
-            println!("  backup restore <backup-file> <restore-dir>");
-            return;
+        let config = match RestoreConfig::from_args(args) {
+            Ok(c) => c,
+            Err(e) => {
+                print_banner("restore usage");
+                eprintln!("error: {e}");
+                println!();
+                println!("  {RESTORE_USAGE}");
+                return;
+            }
         };
 
-        if let Err(e) = restore::restore_backup(Path::new(&archive), Path::new(&dest)) {
+        if let Err(e) = restore::restore_backup(&config) {
             eprintln!("restore failed: {e}");
+            process::exit(1);
         }
         return;
     }
 
     if first == "verify" {
-        let Some(archive) = args.next() else {
-            print_banner("verify usage");
-            println!("  backup verify <backup-file>");
-            return;
+        let config = match VerifyConfig::from_args(args) {
+            Ok(c) => c,
+            Err(e) => {
+                print_banner("verify usage");
+                eprintln!("error: {e}");
+                println!();
+                println!("  {VERIFY_USAGE}");
+                return;
+            }
         };
 
-        if let Err(e) = verify_archive::verify_backup_file(Path::new(&archive)) {
+        let mut trusted_keys = Vec::new();
+        for arg in &config.trusted_keys {
+            match signing::load_trusted_keys(arg) {
+                Ok(keys) => trusted_keys.extend(keys),
+                Err(e) => {
+                    eprintln!("verify failed: {e}");
+                    process::exit(1);
+                }
+            }
+        }
+
+        if let Err(e) =
+            verify_archive::verify_backup_file(&config.archive, &config.credentials, &trusted_keys)
+        {
             eprintln!("verify failed: {e}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    if first == "keygen" {
+        let mut sign = false;
+        let mut key_file = None;
+        let mut invalid = false;
+        for arg in args {
+            match arg.as_str() {
+                "--sign" => sign = true,
+                _ if key_file.is_none() && !arg.starts_with("--") => key_file = Some(arg),
+                _ => invalid = true,
+            }
+        }
+        let (false, Some(key_file)) = (invalid, key_file) else {
+            print_banner("keygen usage");
+            println!("  {KEYGEN_USAGE}");
+            return;
+        };
+
+        let written = if sign {
+            signing::write_signing_key_file(Path::new(&key_file))
+        } else {
+            crypto::write_identity_file(Path::new(&key_file))
+        };
+        match written {
+            Ok(public) => {
+                let what = if sign { "signing key" } else { "identity" };
+                eprintln!("{what} written to {key_file}");
+                println!("{public}");
+            }
+            Err(e) => eprintln!("keygen failed: {e}"),
+        }
+        return;
+    }
+
+    if first == "prune" {
+        let config = match PruneConfig::from_args(args) {
+            Ok(c) => c,
+            Err(e) => {
+                print_banner("prune usage");
+                eprintln!("error: {e}");
+                println!();
+                println!("  {PRUNE_USAGE}");
+                return;
+            }
+        };
+
+        if let Err(e) = prune::prune(&config) {
+            eprintln!("prune failed: {e}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    if first == "resume" {
+        let config = match ResumeConfig::from_args(args) {
+            Ok(c) => c,
+            Err(e) => {
+                print_banner("resume usage");
+                eprintln!("error: {e}");
+                println!();
+                println!("  {RESUME_USAGE}");
+                return;
+            }
+        };
+        if !resume_backup(&config) {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if first == "cleanup" {
+        let config = match CleanupConfig::from_args(args) {
+            Ok(c) => c,
+            Err(e) => {
+                print_banner("cleanup usage");
+                eprintln!("error: {e}");
+                println!();
+                println!("  {CLEANUP_USAGE}");
+                return;
+            }
+        };
+
+        if let Err(e) = cleanup::cleanup(&config) {
+            eprintln!("cleanup failed: {e}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    if first == "init" {
+        let config = match InitConfig::from_args(args) {
+            Ok(c) => c,
+            Err(e) => {
+                print_banner("init usage");
+                eprintln!("error: {e}");
+                println!();
+                println!("  {INIT_USAGE}");
+                return;
+            }
+        };
+        match repository::init(&config.repository, config.chunking) {
+            Ok(()) => println!(
+                "initialized empty repository at {}",
+                config.repository.to_string_lossy()
+            ),
+            Err(e) => {
+                eprintln!("init failed: {e}");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if first == "snapshot" {
+        let config = match SnapshotConfig::from_args(args) {
+            Ok(c) => c,
+            Err(e) => {
+                print_banner("snapshot usage");
+                eprintln!("error: {e}");
+                println!();
+                println!("  {SNAPSHOT_USAGE}");
+                return;
+            }
+        };
+
+        print_banner("create snapshot");
+        if let Some(n) = config.threads
+            && let Err(err) = ThreadPoolBuilder::new().num_threads(n).build_global()
+        {
+            eprintln!("warning: failed to configure thread pool: {err}");
+        }
+        if let Err(e) = snapshot::create_snapshot(&config) {
+            eprintln!("snapshot failed: {e}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    if first == "run" {
+        let config = match RunConfig::from_args(args) {
+            Ok(c) => c,
+            Err(e) => {
+                print_banner("run usage");
+                eprintln!("error: {e}");
+                println!();
+                println!("  {RUN_USAGE}");
+                return;
+            }
+        };
+        if !run_profile(&config) {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if first == "config" {
+        let mut config_file = None;
+        let mut invalid = false;
+        match args.next().as_deref() {
+            Some("check") => {}
+            _ => invalid = true,
+        }
+        while let Some(arg) = args.next() {
+            match (arg.as_str(), args.next()) {
+                ("--config", Some(path)) if config_file.is_none() => config_file = Some(path),
+                _ => invalid = true,
+            }
+        }
+        if invalid {
+            print_banner("config usage");
+            println!("  {CONFIG_USAGE}");
+            return;
+        }
+
+        print_banner("config check");
+        if !profile::check(config_file.as_deref().map(Path::new)) {
+            process::exit(1);
         }
         return;
     }
@@ -111,27 +368,122 @@ fn main() {
             eprintln!("error: {e}");
             println!();
             println!("usage:");
-            println!(
-                "  backup <source-dir> <backup-dir> [--threads N] [--verify] [--include P] [--exclude P] [--dry-run]"
-            );
+            println!("  {CREATE_USAGE}");
             return;
         }
     };
 
-    print_banner("create backup");
+    if !create_backup(&config, None) {
+        process::exit(1);
+    }
+}
 
-    if let Some(n) = config.threads {
-        if let Err(err) = ThreadPoolBuilder::new().num_threads(n).build_global() {
-            eprintln!("warning: failed to configure thread pool: {err}");
+/// Runs a profile of the configuration file: a backup, then pruning if the
+/// profile has a retention policy. Returns whether both succeeded.
+fn run_profile(run: &RunConfig) -> bool {
+    let (path, file) = match profile::load(run.config_file.as_deref()) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("run failed: {e}");
+            return false;
+        }
+    };
+    let loaded = file.profile(&run.profile).and_then(|profile| {
+        let config = BackupConfig::from_profile(profile, run.overrides.iter().cloned())?;
+        let policy = profile.retention.as_ref().map(|r| r.policy()).transpose()?;
+        if policy.is_some() {
+            profile::prune_credentials(&config)?;
+        }
+        Ok((config, policy))
+    });
+    let (config, policy) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("run failed: profile {:?} in {:?}: {e}", run.profile, path);
+            return false;
+        }
+    };
+
+    if !create_backup(&config, None) {
+        return false;
+    }
+    let Some(policy) = policy else {
+        return true;
+    };
+
+    let source = validation::source_roots(&config.sources)
+        .ok()
+        .map(|roots| validation::archive_name(&roots));
+    let prune_config = PruneConfig {
+        backup_dir: config.destination.clone(),
+        source,
+        policy,
+        dry_run: config.dry_run,
+        credentials: config.credentials.clone(),
+    };
+    println!();
+    if let Err(e) = prune::prune(&prune_config) {
+        eprintln!("prune failed: {e}");
+        return false;
+    }
+    true
+}
+
+/// Resumes the backup that left the partial archive `resume.partial`, with
+/// the settings recorded in its checkpoint. Returns whether it succeeded.
+fn resume_backup(resume: &ResumeConfig) -> bool {
+    let checkpoint = match Checkpoint::load(&resume.partial) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("resume failed: {e}");
+            return false;
         }
+    };
+    let mut config =
+        match BackupConfig::from_profile(&checkpoint.header.profile, std::iter::empty()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("resume failed: invalid checkpoint settings: {e}");
+                return false;
+            }
+        };
+    if resume.threads.is_some() {
+        config.threads = resume.threads;
+    }
+    if resume.credentials.passphrase_file.is_some() {
+        config.credentials.passphrase_file = resume.credentials.passphrase_file.clone();
     }
+    config
+        .credentials
+        .identity_files
+        .extend(resume.credentials.identity_files.iter().cloned());
 
-    let paths = match validate_paths(&config) {
+    create_backup(&config, Some(&checkpoint))
+}
+
+/// Creates a backup archive as configured, printing progress and errors.
+/// Returns whether it succeeded; a dry run or a source with nothing to back
+/// up counts as success. With `resume`, the checkpoint of an interrupted
+/// run, its partial archive is continued instead.
+fn create_backup(config: &BackupConfig, resume: Option<&Checkpoint>) -> bool {
+    print_banner(if resume.is_some() {
+        "resume backup"
+    } else {
+        "create backup"
+    });
+
+    if let Some(n) = config.threads
+        && let Err(err) = ThreadPoolBuilder::new().num_threads(n).build_global()
+    {
+        eprintln!("warning: failed to configure thread pool: {err}");
+    }
+
+    let paths = match validate_paths(config) {
         Ok(p) => p,
         Err(e) => {
             print_section("configuration error");
             eprintln!("{e}");
-            return;
+            return false;
         }
     };
 
@@ -140,28 +492,47 @@ fn main() {
         Err(e) => {
             print_section("filter error");
             eprintln!("{e}");
-            return;
+            return false;
         }
     };
 
-    let source_name = paths
-        .source_root
-        .file_name()
-        .and_then(|s| s.to_str())
-        .unwrap_or("backup");
+    let source_name = &validation::archive_name(&paths.roots);
 
-    let ts = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
+    let ts = match resume {
+        Some(checkpoint) => checkpoint.header.created_at,
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
 
     let backup_file_name = format!("{source_name}-{ts}.backup");
     let backup_file = paths.backup_dir.join(&backup_file_name);
+    if let Some(checkpoint) = resume
+        && backup_file::partial_path(&backup_file) != checkpoint.partial
+    {
+        print_section("configuration error");
+        eprintln!(
+            "{:?} is not where the interrupted backup writes its archive, {:?}",
+            checkpoint.partial,
+            backup_file::partial_path(&backup_file)
+        );
+        return false;
+    }
 
     print_section("paths");
-    print_kv("source", &paths.source_root.to_string_lossy());
-    print_kv("backup dir", &paths.backup_dir.to_string_lossy());
-    print_kv("backup file", &backup_file.to_string_lossy());
+    for root in &paths.roots {
+        if root.name.is_empty() {
+            print_kv("source", root.path.to_string_lossy());
+        } else {
+            print_kv(
+                "source",
+                format!("{} as {}", root.path.to_string_lossy(), root.name),
+            );
+        }
+    }
+    print_kv("backup dir", paths.backup_dir.to_string_lossy());
+    print_kv("backup file", backup_file.to_string_lossy());
 
     if !config.includes.is_empty() || !config.excludes.is_empty() {
         print_section("filters");
@@ -173,66 +544,396 @@ fn main() {
         }
     }
 
+    if config.compression.codec != Codec::None {
+        print_kv(
+            "compression",
+            format!(
+                "{} (level {})",
+                config.compression.codec.name(),
+                config.compression.level
+            ),
+        );
+    }
+
+    let mut recipients = config.recipients.clone();
+    for path in &config.recipients_files {
+        match crypto::read_recipients_file(path) {
+            Ok(keys) => recipients.extend(keys),
+            Err(e) => {
+                print_section("encryption error");
+                eprintln!("{e}");
+                return false;
+            }
+        }
+    }
+
+    if config.encrypt || !recipients.is_empty() {
+        let mut slots = Vec::new();
+        if config.encrypt {
+            slots.push("passphrase".to_string());
+        }
+        if !recipients.is_empty() {
+            slots.push(format!("{} recipient(s)", recipients.len()));
+        }
+        print_kv(
+            "encryption",
+            format!("chacha20-poly1305 ({})", slots.join(", ")),
+        );
+    }
+
+    let signing_key = match &config.sign_key {
+        Some(path) => match signing::read_signing_key(path) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                print_section("signing error");
+                eprintln!("{e}");
+                return false;
+            }
+        },
+        None => None,
+    };
+    let signer_public = signing_key.as_ref().map(|key| {
+        let mut public = [0u8; 32];
+        public.copy_from_slice(key.public_key().as_ref());
+        public
+    });
+    if let Some(public) = &signer_public {
+        print_kv("signed by", signing::public_key_string(public));
+    }
+
+    let backup_type = if config.differential_from.is_some() {
+        BackupType::Differential
+    } else {
+        BackupType::Incremental
+    };
+    let role = match backup_type {
+        BackupType::Differential => "base",
+        _ => "parent",
+    };
+    let parent_path = match config
+        .differential_from
+        .as_ref()
+        .or(config.incremental_from.as_ref())
+    {
+        Some(path) => Some(path.clone()),
+        None if config.incremental => {
+            match incremental::find_latest(&paths.backup_dir, source_name) {
+                Ok(Some(path)) => Some(path),
+                Ok(None) => {
+                    print_kv("mode", "full, no previous archive found");
+                    None
+                }
+                Err(e) => {
+                    print_section("incremental error");
+                    eprintln!("failed to look for a previous archive: {e}");
+                    return false;
+                }
+            }
+        }
+        None => None,
+    };
+
+    let parent = match &parent_path {
+        Some(path) => match Parent::load(path, &config.credentials) {
+            Ok(_) if fs::canonicalize(path).ok() == Some(backup_file.clone()) => {
+                print_section("incremental error");
+                eprintln!(
+                    "{role} archive {:?} would be overwritten by the new backup, \
+                     wait a second and retry",
+                    path
+                );
+                return false;
+            }
+            Ok(parent)
+                if backup_type == BackupType::Differential
+                    && parent.backup_type != BackupType::Full =>
+            {
+                print_section("incremental error");
+                eprintln!(
+                    "base archive {:?} is not a full backup but {}, a differential backup needs a full one",
+                    path,
+                    parent.backup_type.as_str()
+                );
+                return false;
+            }
+            Ok(parent) => {
+                print_kv(role, path.to_string_lossy());
+                if parent.source != types::describe_roots(&paths.roots) {
+                    eprintln!(
+                        "warning: {role} archive was created from {:?}",
+                        parent.source
+                    );
+                }
+                if fs::canonicalize(path)
+                    .ok()
+                    .and_then(|p| p.parent().map(Path::to_path_buf))
+                    != Some(paths.backup_dir.clone())
+                {
+                    eprintln!(
+                        "warning: restore looks for the {role} archive next to the new one, \
+                         copy it to {:?}",
+                        paths.backup_dir
+                    );
+                }
+                Some(parent)
+            }
+            Err(e) => {
+                print_section("incremental error");
+                eprintln!("failed to read {role} archive {:?}: {e}", path);
+                return false;
+            }
+        },
+        None => None,
+    };
+
     if config.dry_run {
         print_kv("mode", "dry-run");
     }
 
     print_section("scan");
-    print_kv("root", &paths.source_root.to_string_lossy());
-    let files = fs_scan::scan_dir_with_filter(&paths.source_root, Some(&path_filter));
+    let (files, others): (Vec<_>, Vec<_>) = fs_scan::scan_roots(&paths.roots, Some(&path_filter))
+        .into_iter()
+        .partition(|e| e.kind == EntryKind::File);
 
     let total_bytes: u64 = files.iter().map(|f| f.size).sum();
     print_kv("files", files.len().to_string());
+    if !others.is_empty() {
+        print_kv("other", others.len().to_string());
+    }
     print_kv("bytes", total_bytes.to_string());
 
-    if files.is_empty() {
+    // Files whose size and modification time match the parent keep their
+    // recorded hash and are neither rehashed nor stored again.
+    let mut deleted = Vec::new();
+    let mut unchanged = Vec::new();
+    let mut changed = Vec::new();
+    if let Some(parent) = &parent {
+        let current: Vec<&FileEntry> = files.iter().chain(others.iter()).collect();
+        deleted = parent.deleted(&paths.roots, &current);
+    }
+    for f in files {
+        match parent
+            .as_ref()
+            .and_then(|p| p.unchanged_hash(&paths.roots, &f))
+        {
+            Some(hash) => unchanged.push(HashedFile {
+                entry: f,
+                hash,
+                chunks: Vec::new(),
+                changed: false,
+            }),
+            None => changed.push(f),
+        }
+    }
+    if parent.is_some() {
+        print_kv("unchanged", unchanged.len().to_string());
+        print_kv("deleted", deleted.len().to_string());
+    }
+
+    if changed.is_empty() && unchanged.is_empty() && others.is_empty() {
         println!();
         println!("nothing to hash or backup");
-        return;
+        return true;
     }
 
     if config.dry_run {
         print_section("summary");
         println!("dry-run: no hashing, manifest, or archive written.");
-        return;
+        return true;
     }
 
-    print_section("hash");
-    let pb_hash = ProgressBar::new(files.len() as u64);
-    let hashed = hash_files_parallel(&files, &pb_hash);
-    print_kv("hashed files", hashed.len().to_string());
+    // Ask for the passphrase before the long-running part so a typo is
+    // noticed before any file is read. A resumed backup checks it against
+    // the partial archive instead of asking twice.
+    let passphrase = if config.encrypt {
+        match config.credentials.passphrase(resume.is_none()) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                print_section("encryption error");
+                eprintln!("{e}");
+                return false;
+            }
+        }
+    } else {
+        None
+    };
+    let envelope = if config.encrypt || !recipients.is_empty() {
+        match Envelope::new(passphrase.as_deref(), &recipients) {
+            Ok(env) => Some(env),
+            Err(e) => {
+                print_section("encryption error");
+                eprintln!("{e}");
+                return false;
+            }
+        }
+    } else {
+        None
+    };
+    let verify_credentials = Credentials {
+        data_key: envelope.as_ref().map(Envelope::data_key),
+        ..Credentials::default()
+    };
 
-    print_section("manifest");
-    let manifest_json = match build_manifest_json(&paths.source_root, &backup_file, &hashed) {
-        Ok(json) => json,
-        Err(e) => {
-            eprintln!("failed to build manifest JSON: {e}");
-            return;
+    // The entries the interrupted run wrote are checked and kept, the other
+    // files are written after them.
+    let prefix = match resume {
+        Some(checkpoint) => {
+            print_section("resume");
+            print_kv("partial", checkpoint.partial.to_string_lossy());
+            print_kv(
+                "checkpoint",
+                format!("{} entries", checkpoint.entries.len()),
+            );
+            let credentials = Credentials {
+                passphrase,
+                ..config.credentials.clone()
+            };
+            match resume::verify_prefix(checkpoint, &credentials) {
+                Ok(prefix) => {
+                    print_kv("kept", format!("{} entries", prefix.entries.len()));
+                    Some(prefix)
+                }
+                Err(e) => {
+                    eprintln!("failed to read the partial archive: {e}");
+                    return false;
+                }
+            }
         }
+        None => None,
     };
-    print_kv("entries", hashed.len().to_string());
+    if let Some(prefix) = &prefix {
+        let kept: HashSet<&str> = prefix.entries.iter().map(|e| e.path.as_str()).collect();
+        let is_kept = |f: &FileEntry| {
+            kept.contains(
+                types::archive_path(&paths.roots, &f.path)
+                    .to_string_lossy()
+                    .as_ref(),
+            )
+        };
+        changed.retain(|f| !is_kept(f));
+        unchanged.retain(|h| !is_kept(&h.entry));
+        deleted.retain(|p| !kept.contains(p.as_str()));
+    }
+
+    // A file that was only touched keeps its size, so those are hashed
+    // first and left in the parent if it holds the same data. All other
+    // changed files are hashed while they are written.
+    if let Some(parent) = &parent {
+        let candidates: Vec<FileEntry> = changed
+            .iter()
+            .filter(|f| parent.may_have_data(&paths.roots, f))
+            .cloned()
+            .collect();
+        if !candidates.is_empty() {
+            print_section("hash");
+            let pb_hash = ProgressBar::new(candidates.len() as u64);
+            let hashed = hash_files_parallel(&candidates, &pb_hash);
+            let touched: Vec<HashedFile> = hashed
+                .into_iter()
+                .filter(|h| parent.has_data(&paths.roots, &h.entry, &h.hash))
+                .collect();
+            print_kv("hashed files", candidates.len().to_string());
+            print_kv("touched", touched.len().to_string());
+            let touched_paths: HashSet<&Path> =
+                touched.iter().map(|h| h.entry.path.as_path()).collect();
+            changed.retain(|f| !touched_paths.contains(f.path.as_path()));
+            unchanged.extend(touched);
+        }
+    }
 
     print_section("archive");
+    let delta = parent.as_ref().map(|p| Delta {
+        backup_type,
+        against: &p.reference,
+        unchanged: &unchanged,
+        deleted: &deleted,
+    });
+    let manifest = |stored: &[HashedFile]| {
+        build_manifest_json(
+            &paths.roots,
+            &backup_file,
+            ts,
+            stored,
+            &others,
+            delta.as_ref(),
+        )
+        .map_err(|e| io::Error::other(format!("failed to build manifest JSON: {e}")))
+    };
+    // What a resumed run needs to do the same as this one: the archive it
+    // is compared against and the recipients are pinned down.
+    let checkpoint = match resume {
+        Some(checkpoint) => checkpoint.header.clone(),
+        None => {
+            let mut profile = config.to_profile();
+            let pinned = parent_path
+                .as_deref()
+                .map(|p| path::absolute(p).unwrap_or_else(|_| p.to_path_buf()));
+            profile.incremental = false;
+            profile.incremental_from = None;
+            profile.differential_from = None;
+            match backup_type {
+                BackupType::Differential => profile.differential_from = pinned,
+                _ => profile.incremental_from = pinned,
+            }
+            profile.recipients = recipients.iter().map(crypto::public_key_string).collect();
+            profile.recipients_files = Vec::new();
+            CheckpointHeader {
+                backup_file: backup_file_name.clone(),
+                created_at: ts,
+                profile,
+            }
+        }
+    };
     let pb_backup = ProgressBar::new(0);
-    if let Err(e) = backup_file::create_backup_file(
+    let stored = match backup_file::create_backup_file(
         &backup_file,
-        &paths.source_root,
-        &hashed,
-        &manifest_json,
+        &paths.roots,
+        &changed,
+        &manifest,
+        &ArchiveOptions {
+            compression: config.compression,
+            envelope: envelope.as_ref(),
+            signing_key: signing_key.as_ref(),
+            change_retries: config.change_retries,
+            fail_on_change: config.fail_on_change,
+            checkpoint: &checkpoint,
+        },
+        prefix,
         &pb_backup,
     ) {
-        eprintln!("failed to create backup file: {e}");
-        return;
+        Ok(stored) => stored,
+        Err(e) => {
+            eprintln!("failed to create backup file: {e}");
+            return false;
+        }
+    };
+    print_kv("stored files", stored.len().to_string());
+    let changed_files = stored.iter().filter(|h| h.changed).count();
+    if changed_files > 0 {
+        print_kv(
+            "changed",
+            format!("{changed_files} file(s) changed while read"),
+        );
     }
-    print_kv("written", &backup_file.to_string_lossy());
+    print_kv(
+        "entries",
+        (stored.len() + unchanged.len() + others.len()).to_string(),
+    );
+    print_kv("written", backup_file.to_string_lossy());
 
     if config.verify {
         print_section("verify");
-        if let Err(e) = verify_archive::verify_backup_file(&backup_file) {
+        if let Err(e) = verify_archive::verify_backup_file(
+            &backup_file,
+            &verify_credentials,
+            signer_public.as_slice(),
+        ) {
             eprintln!("verify failed: {e}");
+            return false;
         }
     }
 
     print_section("done");
     println!("backup completed.");
+    true
 }
@@ -1,19 +1,69 @@
+use crate::metadata::FileMeta;
 use crate::pipeline::HashedFile;
+use crate::types::{self, BackupType, EntryKind, FileEntry, ParentRef, SourceRoot};
 use serde::Serialize;
 use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Serialize)]
 struct ManifestFile {
     pub path: String,
+    pub kind: EntryKind,
     pub size: u64,
-    pub sha256: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// ids of the repository blobs holding the content, in order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rdev: Option<u64>,
+    /// data is not stored in this archive but in its parent chain or base
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub from_parent: bool,
+    /// the file changed while it was read, so its data may be inconsistent
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub changed_during_backup: bool,
+    pub meta: FileMeta,
 }
+
+impl ManifestFile {
+    fn new(roots: &[SourceRoot], entry: &FileEntry, hash: Option<&[u8; 32]>) -> Self {
+        let rel = types::archive_path(roots, &entry.path);
+
+        Self {
+            path: rel.to_string_lossy().to_string(),
+            kind: entry.kind,
+            size: entry.size,
+            sha256: hash.map(hash_to_hex),
+            chunks: Vec::new(),
+            target: entry.link_target.clone(),
+            rdev: match entry.kind {
+                EntryKind::CharDevice | EntryKind::BlockDevice => Some(entry.rdev),
+                _ => None,
+            },
+            from_parent: false,
+            changed_during_backup: false,
+            meta: entry.meta.clone(),
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct BackupManifest {
     pub source: String,
+    /// the source directories of an archive of several, by top-level name
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub roots: Vec<SourceRoot>,
     pub backup_file: String,
     pub created_at: u64,
+    pub backup_type: BackupType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<ParentRef>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base: Option<ParentRef>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deleted: Vec<String>,
     pub files: Vec<ManifestFile>,
 }
 
@@ -27,37 +77,60 @@ fn hash_to_hex(hash: &[u8; 32]) -> String {
     s
 }
 
+/// How an incremental or differential backup relates to the archive it was
+/// compared against.
+pub struct Delta<'a> {
+    pub backup_type: BackupType,
+    pub against: &'a ParentRef,
+    /// files whose data is kept in `against` or the archives it builds on
+    pub unchanged: &'a [HashedFile],
+    pub deleted: &'a [String],
+}
+
+/// Builds the manifest listing every entry of the backed-up tree. `stored`
+/// files have their data in this archive, the unchanged files of `delta`
+/// in the archive it was compared against. `created_at` is when the backup
+/// started, in unix seconds.
 pub fn build_manifest_json(
-    source_root: &Path,
+    roots: &[SourceRoot],
     backup_file: &Path,
-    hashed: &[HashedFile],
+    created_at: u64,
+    stored: &[HashedFile],
+    others: &[FileEntry],
+    delta: Option<&Delta>,
 ) -> serde_json::Result<String> {
-    let files: Vec<ManifestFile> = hashed
+    let unchanged = delta.map_or(&[][..], |d| d.unchanged);
+    let files: Vec<ManifestFile> = stored
         .iter()
-        .map(|h| {
-            let rel = h
-                .entry
-                .path
-                .strip_prefix(source_root)
-                .unwrap_or(&h.entry.path);
-
-            ManifestFile {
-                path: rel.to_string_lossy().to_string(),
-                size: h.entry.size,
-                sha256: hash_to_hex(&h.hash),
-            }
+        .map(|h| ManifestFile {
+            chunks: h.chunks.iter().map(hash_to_hex).collect(),
+            changed_during_backup: h.changed,
+            ..ManifestFile::new(roots, &h.entry, Some(&h.hash))
         })
+        .chain(unchanged.iter().map(|h| ManifestFile {
+            from_parent: true,
+            ..ManifestFile::new(roots, &h.entry, Some(&h.hash))
+        }))
+        .chain(others.iter().map(|e| ManifestFile::new(roots, e, None)))
         .collect();
 
-    let created_at = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
-
     let manifest = BackupManifest {
-        source: source_root.to_string_lossy().to_string(),
+        source: types::describe_roots(roots),
+        roots: if roots.len() > 1 {
+            roots.to_vec()
+        } else {
+            Vec::new()
+        },
         backup_file: backup_file.to_string_lossy().to_string(),
         created_at,
+        backup_type: delta.map_or(BackupType::Full, |d| d.backup_type),
+        parent: delta
+            .filter(|d| d.backup_type == BackupType::Incremental)
+            .map(|d| d.against.clone()),
+        base: delta
+            .filter(|d| d.backup_type == BackupType::Differential)
+            .map(|d| d.against.clone()),
+        deleted: delta.map_or(Vec::new(), |d| d.deleted.to_vec()),
         files,
     };
 
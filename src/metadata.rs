@@ -0,0 +1,183 @@
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::fs::{self, File, FileTimes, Metadata};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use xattr::FileExt;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Xattr {
+    pub name: String,
+    /// hex-encoded value
+    pub value: String,
+}
+
+/// Unix metadata recorded for each entry so restore can reproduce it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileMeta {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: i64,
+    pub atime_nsec: u32,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub xattrs: Vec<Xattr>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    use std::fmt::Write as FmtWrite;
+
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(&mut s, "{:02x}", b);
+    }
+    s
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn to_system_time(secs: i64, nsec: u32) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::new(secs as u64, nsec)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + Duration::from_nanos(nsec as u64)
+    }
+}
+
+fn read_xattrs(path: &Path) -> Vec<Xattr> {
+    let Ok(names) = xattr::list(path) else {
+        return Vec::new();
+    };
+
+    let mut out = Vec::new();
+    for name in names {
+        let Some(name_str) = name.to_str() else {
+            eprintln!("warning: skipping non UTF-8 xattr name on {:?}", path);
+            continue;
+        };
+        if let Ok(Some(value)) = xattr::get(path, &name) {
+            out.push(Xattr {
+                name: name_str.to_string(),
+                value: to_hex(&value),
+            });
+        }
+    }
+    out
+}
+
+pub fn capture(path: &Path, md: &Metadata) -> FileMeta {
+    FileMeta {
+        mode: md.mode(),
+        uid: md.uid(),
+        gid: md.gid(),
+        atime: md.atime(),
+        atime_nsec: md.atime_nsec() as u32,
+        mtime: md.mtime(),
+        mtime_nsec: md.mtime_nsec() as u32,
+        xattrs: read_xattrs(path),
+    }
+}
+
+pub fn running_as_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Reapplies recorded metadata to a freshly restored file. Ownership is only
+/// changed when `restore_owner` is set; callers decide that from the
+/// effective uid and the `--no-owner` switch. Timestamps are applied last so
+/// that nothing else touches them afterwards.
+pub fn apply(path: &Path, file: &File, meta: &FileMeta, restore_owner: bool) -> io::Result<()> {
+    if restore_owner {
+        std::os::unix::fs::fchown(file, Some(meta.uid), Some(meta.gid))?;
+    }
+
+    file.set_permissions(fs::Permissions::from_mode(meta.mode & 0o7777))?;
+
+    for x in &meta.xattrs {
+        let Some(value) = from_hex(&x.value) else {
+            eprintln!("warning: invalid xattr value for {} on {:?}", x.name, path);
+            continue;
+        };
+        if let Err(e) = file.set_xattr(&x.name, &value) {
+            eprintln!("warning: failed to set xattr {} on {:?}: {e}", x.name, path);
+        }
+    }
+
+    let times = FileTimes::new()
+        .set_accessed(to_system_time(meta.atime, meta.atime_nsec))
+        .set_modified(to_system_time(meta.mtime, meta.mtime_nsec));
+    file.set_times(times)
+}
+
+fn set_times_nofollow(path: &Path, meta: &FileMeta) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec {
+            tv_sec: meta.atime as libc::time_t,
+            tv_nsec: meta.atime_nsec as libc::c_long,
+        },
+        libc::timespec {
+            tv_sec: meta.mtime as libc::time_t,
+            tv_nsec: meta.mtime_nsec as libc::c_long,
+        },
+    ];
+
+    // SAFETY: c_path is a valid NUL-terminated string and times points to
+    // the two timespec values utimensat expects.
+    let rc = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Path based variant of [`apply`] for directories and for entries that
+/// cannot be opened as regular files. Symlinks are never followed; their mode
+/// and xattrs are left alone since Linux does not use them.
+pub fn apply_to_path(
+    path: &Path,
+    meta: &FileMeta,
+    is_symlink: bool,
+    restore_owner: bool,
+) -> io::Result<()> {
+    if restore_owner {
+        std::os::unix::fs::lchown(path, Some(meta.uid), Some(meta.gid))?;
+    }
+
+    if !is_symlink {
+        fs::set_permissions(path, fs::Permissions::from_mode(meta.mode & 0o7777))?;
+
+        for x in &meta.xattrs {
+            let Some(value) = from_hex(&x.value) else {
+                eprintln!("warning: invalid xattr value for {} on {:?}", x.name, path);
+                continue;
+            };
+            if let Err(e) = xattr::set(path, &x.name, &value) {
+                eprintln!("warning: failed to set xattr {} on {:?}: {e}", x.name, path);
+            }
+        }
+    }
+
+    set_times_nofollow(path, meta)
+}
@@ -1,13 +1,35 @@
 use indicatif::ProgressBar;
 use rayon::prelude::*;
+use ring::digest;
+use std::fs::{File, Metadata};
+use std::io::{self, BufReader, Read};
+use std::os::unix::fs::MetadataExt;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
 
+use crate::archive::{self, EntryPiece};
+use crate::chunker::{self, ChunkParams, Chunker};
+use crate::compress::{BLOCK_SIZE, Compression};
 use crate::hasher::hash_file;
 use crate::types::FileEntry;
 
+/// Pieces a reader thread may have encoded ahead of the writer, per file.
+const READ_AHEAD: usize = 4;
+
+/// Encoded data a reader holds back until it has read the whole file, so a
+/// file that changed meanwhile can be read again. Larger files are streamed
+/// to the writer and only flagged when they change.
+const RETRY_BUFFER: usize = 8 * BLOCK_SIZE;
+
 #[derive(Debug, Clone)]
 pub struct HashedFile {
     pub entry: FileEntry,
     pub hash: [u8; 32],
+    /// ids of the content-defined chunks, for files stored in a repository
+    pub chunks: Vec<[u8; 32]>,
+    /// the file changed while it was read, so its data may be inconsistent
+    pub changed: bool,
 }
 
 pub fn hash_files_parallel(files: &[FileEntry], pb: &ProgressBar) -> Vec<HashedFile> {
@@ -19,6 +41,8 @@ pub fn hash_files_parallel(files: &[FileEntry], pb: &ProgressBar) -> Vec<HashedF
             let hashed = hash_file(&f.path).map(|h| HashedFile {
                 entry: f.clone(),
                 hash: h,
+                chunks: Vec::new(),
+                changed: false,
             });
 
             pb.inc(1);
@@ -29,3 +53,356 @@ pub fn hash_files_parallel(files: &[FileEntry], pb: &ProgressBar) -> Vec<HashedF
     pb.finish_with_message("hashing complete");
     result
 }
+
+/// Size and modification time of a file, compared before and after it is
+/// read to notice changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+}
+
+impl Stamp {
+    fn of(md: &Metadata) -> Self {
+        Self {
+            size: md.len(),
+            mtime: md.mtime(),
+            mtime_nsec: md.mtime_nsec() as u32,
+        }
+    }
+}
+
+/// What a reader thread sends for one file: `Opened` with the size it
+/// stores, the pieces of its entry data, then `Done`. A file that could not
+/// be read sends `Failed` instead, first if nothing was sent yet.
+pub enum Piece {
+    Opened(Stamp),
+    Entry(EntryPiece),
+    Done {
+        hash: [u8; 32],
+        /// the file changed while it was read, on the last attempt
+        changed: bool,
+        /// the file was streamed to the writer, so it was not read again
+        /// when it changed
+        streamed: bool,
+    },
+    Failed(io::Error),
+}
+
+/// Yields exactly `remaining` bytes from `inner`. If the file shrank after it
+/// was opened, the rest is zero-filled so the entry still occupies its
+/// declared size.
+struct PaddedReader<R> {
+    inner: R,
+    remaining: u64,
+    padded: bool,
+}
+
+impl<R: Read> Read for PaddedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.remaining as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let mut n = if self.padded {
+            0
+        } else {
+            self.inner.read(&mut buf[..len])?
+        };
+        if n == 0 {
+            self.padded = true;
+            buf[..len].fill(0);
+            n = len;
+        }
+
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads one file, sending its encoded pieces to `tx`. A file that changed
+/// while it was read is read again up to `retries` times, unless it was too
+/// large to hold back. Returns `false` once the receiving side is gone.
+fn read_file(
+    f: &FileEntry,
+    compression: Compression,
+    retries: u32,
+    tx: &SyncSender<Piece>,
+) -> bool {
+    let send = |piece| tx.send(piece).is_ok();
+    let mut attempt = 0;
+    loop {
+        let file = match File::open(&f.path) {
+            Ok(file) => file,
+            Err(e) => return send(Piece::Failed(e)),
+        };
+        let before = match file.metadata() {
+            Ok(md) => Stamp::of(&md),
+            Err(e) => return send(Piece::Failed(e)),
+        };
+        let mut reader = PaddedReader {
+            inner: BufReader::new(&file),
+            remaining: before.size,
+            padded: false,
+        };
+
+        let mut held = vec![Piece::Opened(before)];
+        let mut held_bytes = 0;
+        let mut streaming = false;
+        let encoded = archive::encode_entry(&mut reader, before.size, compression, |piece| {
+            let gone = |_| io::Error::from(io::ErrorKind::BrokenPipe);
+            if streaming {
+                return tx.send(Piece::Entry(piece)).map_err(gone);
+            }
+            if let EntryPiece::Data { bytes, .. } = &piece {
+                held_bytes += bytes.len();
+            }
+            held.push(Piece::Entry(piece));
+            if held_bytes > RETRY_BUFFER {
+                streaming = true;
+                for piece in held.drain(..) {
+                    tx.send(piece).map_err(gone)?;
+                }
+            }
+            Ok(())
+        });
+        let hash = match encoded {
+            Ok(hash) => hash,
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return false,
+            Err(e) => return send(Piece::Failed(e)),
+        };
+
+        let after = file.metadata().ok().map(|md| Stamp::of(&md));
+        let changed = reader.padded || after != Some(before);
+        if changed && !streaming && attempt < retries {
+            attempt += 1;
+            continue;
+        }
+        return held.into_iter().all(send)
+            && send(Piece::Done {
+                hash,
+                changed,
+                streamed: streaming,
+            });
+    }
+}
+
+/// Reads, hashes and encodes `files` on one reader thread per worker of the
+/// thread pool, and calls `write` with each file and the pieces of its entry
+/// in the order of `files`. Readers move on to the next unclaimed file while
+/// the writer is busy, each buffering at most [`READ_AHEAD`] pieces besides
+/// the [`RETRY_BUFFER`], so a file is read once unless it changes meanwhile
+/// and memory use stays bounded.
+pub fn read_files_ordered<F>(
+    files: &[FileEntry],
+    compression: Compression,
+    retries: u32,
+    write: F,
+) -> io::Result<()>
+where
+    F: FnMut(&FileEntry, Receiver<Piece>) -> io::Result<()>,
+{
+    ordered(files, |f, tx| read_file(f, compression, retries, tx), write)
+}
+
+/// What a reader thread sends for one file of a snapshot: its chunks in
+/// order, then `Done`, or `Failed` if it could not be read.
+pub enum ChunkPiece {
+    Chunk {
+        id: [u8; 32],
+        data: Vec<u8>,
+    },
+    Done {
+        /// SHA-256 of the whole content
+        hash: [u8; 32],
+        /// the file changed while it was read
+        changed: bool,
+    },
+    Failed(io::Error),
+}
+
+/// Splits one file into chunks, sending them to `tx` with the hash of the
+/// whole content. Returns `false` once the receiving side is gone.
+fn chunk_file(f: &FileEntry, params: ChunkParams, tx: &SyncSender<ChunkPiece>) -> bool {
+    let send = |piece| tx.send(piece).is_ok();
+    let file = match File::open(&f.path) {
+        Ok(file) => file,
+        Err(e) => return send(ChunkPiece::Failed(e)),
+    };
+    let before = match file.metadata() {
+        Ok(md) => Stamp::of(&md),
+        Err(e) => return send(ChunkPiece::Failed(e)),
+    };
+
+    let mut chunker = Chunker::new(&file, params);
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    loop {
+        let chunk = match chunker.next_chunk() {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => return send(ChunkPiece::Failed(e)),
+        };
+        ctx.update(chunk);
+        let piece = ChunkPiece::Chunk {
+            id: chunker::chunk_id(chunk),
+            data: chunk.to_vec(),
+        };
+        if !send(piece) {
+            return false;
+        }
+    }
+
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(ctx.finish().as_ref());
+    let after = file.metadata().ok().map(|md| Stamp::of(&md));
+    send(ChunkPiece::Done {
+        hash,
+        changed: after != Some(before),
+    })
+}
+
+/// Reads `files` once each, splitting them into content-defined chunks on
+/// one reader thread per worker of the thread pool, and calls `write` with
+/// each file and its chunks in the order of `files`, like
+/// [`read_files_ordered`].
+pub fn chunk_files_ordered<F>(files: &[FileEntry], params: ChunkParams, write: F) -> io::Result<()>
+where
+    F: FnMut(&FileEntry, Receiver<ChunkPiece>) -> io::Result<()>,
+{
+    ordered(files, |f, tx| chunk_file(f, params, tx), write)
+}
+
+/// Runs `read` for each of `files` on the reader threads and `write` for
+/// each file, in order, on the calling thread. `read` returns `false` once
+/// the writer stopped listening.
+fn ordered<T, R, F>(files: &[FileEntry], read: R, mut write: F) -> io::Result<()>
+where
+    T: Send,
+    R: Fn(&FileEntry, &SyncSender<T>) -> bool + Sync,
+    F: FnMut(&FileEntry, Receiver<T>) -> io::Result<()>,
+{
+    let (order_tx, order_rx) = mpsc::channel();
+    // Files are claimed in order and each claim queues the file's receiver
+    // under the same lock, so the writer gets them in the order of `files`.
+    let next = Mutex::new((files.iter(), Some(order_tx)));
+
+    thread::scope(|s| {
+        for _ in 0..rayon::current_num_threads() {
+            s.spawn(|| {
+                loop {
+                    let (f, tx) = {
+                        let mut next = next.lock().unwrap_or_else(|e| e.into_inner());
+                        let Some(f) = next.0.next() else {
+                            next.1 = None;
+                            return;
+                        };
+                        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+                        if next.1.as_ref().is_none_or(|order| order.send(rx).is_err()) {
+                            return;
+                        }
+                        (f, tx)
+                    };
+                    if !read(f, &tx) {
+                        return;
+                    }
+                }
+            });
+        }
+
+        let result = files
+            .iter()
+            .zip(order_rx.iter())
+            .try_for_each(|(f, pieces)| write(f, pieces));
+        // Dropping the queue stops the readers if the writer gave up early.
+        drop(order_rx);
+        result
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::Codec;
+    use crate::metadata;
+    use crate::types::EntryKind;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    fn scratch_file(name: &str, size: usize) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("backup-pipeline-{}-{name}", std::process::id()));
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn file_entry(path: &Path) -> FileEntry {
+        let md = fs::metadata(path).unwrap();
+        FileEntry {
+            path: path.to_path_buf(),
+            size: md.len(),
+            kind: EntryKind::File,
+            meta: metadata::capture(path, &md),
+            link_target: None,
+            rdev: 0,
+        }
+    }
+
+    const STORED: Compression = Compression {
+        codec: Codec::None,
+        level: 0,
+    };
+
+    /// The `changed` and `streamed` flags of the `Done` piece, and how many
+    /// times the file was reported opened.
+    fn outcome(pieces: &[Piece]) -> (bool, bool, usize) {
+        let opened = pieces
+            .iter()
+            .filter(|p| matches!(p, Piece::Opened(_)))
+            .count();
+        match pieces.last() {
+            Some(Piece::Done {
+                changed, streamed, ..
+            }) => (*changed, *streamed, opened),
+            _ => panic!("last piece is not Done"),
+        }
+    }
+
+    #[test]
+    fn unchanged_file_is_sent_once() {
+        let path = scratch_file("unchanged", 3 * BLOCK_SIZE + 17);
+        let (tx, rx) = mpsc::sync_channel(64);
+        assert!(read_file(&file_entry(&path), STORED, 2, &tx));
+        drop(tx);
+        let pieces: Vec<Piece> = rx.iter().collect();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(outcome(&pieces), (false, false, 1));
+    }
+
+    #[test]
+    fn large_file_changed_while_streamed_is_not_retried() {
+        let path = scratch_file("streamed", RETRY_BUFFER + BLOCK_SIZE);
+        let entry = file_entry(&path);
+        // A rendezvous channel stops the reader at its first send, which
+        // only happens once the file is streamed.
+        let (tx, rx) = mpsc::sync_channel(0);
+        let reader = thread::spawn(move || read_file(&entry, STORED, 2, &tx));
+
+        let mut pieces = vec![rx.recv().unwrap()];
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"appended while read")
+            .unwrap();
+        pieces.extend(rx.iter());
+        assert!(reader.join().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(outcome(&pieces), (true, true, 1));
+    }
+}
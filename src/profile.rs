@@ -0,0 +1,410 @@
+use crate::config::BackupConfig;
+use crate::crypto;
+use crate::prune::{self, RetentionPolicy};
+use crate::signing;
+use crate::validation;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SYSTEM_CONFIG: &str = "/etc/backup.toml";
+
+fn print_section(title: &str) {
+    println!();
+    println!("--- {title} ---");
+}
+
+fn print_kv<K: AsRef<str>, V: AsRef<str>>(k: K, v: V) {
+    println!("  {:12} {}", format!("{}:", k.as_ref()), v.as_ref());
+}
+
+/// A configuration file: named profiles under `[profiles.<name>]`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// The settings of one backup job. Every key mirrors the command-line flag
+/// of the same name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub source: Option<PathBuf>,
+    /// several source directories, each stored under its own name
+    #[serde(default)]
+    pub sources: Vec<PathBuf>,
+    pub destination: PathBuf,
+    #[serde(default)]
+    pub includes: Vec<String>,
+    #[serde(default)]
+    pub excludes: Vec<String>,
+    pub threads: Option<usize>,
+    #[serde(default)]
+    pub verify: bool,
+    pub compress: Option<String>,
+    pub compress_level: Option<i32>,
+    #[serde(default)]
+    pub encrypt: bool,
+    pub passphrase_file: Option<PathBuf>,
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    #[serde(default)]
+    pub recipients_files: Vec<PathBuf>,
+    /// identity files to read earlier archives encrypted to a recipient,
+    /// e.g. the parent of an incremental backup or archives to prune
+    #[serde(default)]
+    pub identities: Vec<PathBuf>,
+    pub sign_key: Option<PathBuf>,
+    #[serde(default)]
+    pub incremental: bool,
+    pub incremental_from: Option<PathBuf>,
+    pub differential_from: Option<PathBuf>,
+    pub change_retries: Option<u32>,
+    #[serde(default)]
+    pub fail_on_change: bool,
+    pub retention: Option<Retention>,
+}
+
+/// The `[profiles.<name>.retention]` table, applied with `prune` after each
+/// successful run of the profile.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Retention {
+    #[serde(default)]
+    pub keep_last: usize,
+    #[serde(default)]
+    pub keep_hourly: usize,
+    #[serde(default)]
+    pub keep_daily: usize,
+    #[serde(default)]
+    pub keep_weekly: usize,
+    #[serde(default)]
+    pub keep_monthly: usize,
+    #[serde(default)]
+    pub keep_yearly: usize,
+    pub keep_within: Option<String>,
+}
+
+impl Retention {
+    pub fn policy(&self) -> Result<RetentionPolicy, String> {
+        let policy = RetentionPolicy {
+            keep_last: self.keep_last,
+            keep_hourly: self.keep_hourly,
+            keep_daily: self.keep_daily,
+            keep_weekly: self.keep_weekly,
+            keep_monthly: self.keep_monthly,
+            keep_yearly: self.keep_yearly,
+            keep_within: self
+                .keep_within
+                .as_deref()
+                .map(prune::parse_duration)
+                .transpose()?,
+        };
+        if policy.is_empty() {
+            return Err(
+                "retention has no keep_* setting; it would remove every archive".to_string(),
+            );
+        }
+        Ok(policy)
+    }
+}
+
+/// Where the configuration file is looked for when `--config` is not given:
+/// the user's file first, then the system-wide one.
+fn default_locations() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+        paths.push(PathBuf::from(dir).join("backup/config.toml"));
+    } else if let Some(home) = env::var_os("HOME").filter(|h| !h.is_empty()) {
+        paths.push(PathBuf::from(home).join(".config/backup/config.toml"));
+    }
+    paths.push(PathBuf::from(SYSTEM_CONFIG));
+    paths
+}
+
+/// Reads the configuration file at `path`, or the first one found in the
+/// default locations.
+pub fn load(path: Option<&Path>) -> Result<(PathBuf, ConfigFile), String> {
+    let path = match path {
+        Some(p) => p.to_path_buf(),
+        None => {
+            let candidates = default_locations();
+            candidates
+                .iter()
+                .find(|p| p.is_file())
+                .cloned()
+                .ok_or_else(|| {
+                    let tried: Vec<String> = candidates
+                        .iter()
+                        .map(|p| p.to_string_lossy().to_string())
+                        .collect();
+                    format!(
+                        "no configuration file found (looked for {}), use --config",
+                        tried.join(", ")
+                    )
+                })?
+        }
+    };
+    let text = fs::read_to_string(&path).map_err(|e| format!("failed to read {:?}: {e}", path))?;
+    let file = toml::from_str(&text).map_err(|e| format!("invalid config {:?}: {e}", path))?;
+    Ok((path, file))
+}
+
+impl ConfigFile {
+    pub fn profile(&self, name: &str) -> Result<&Profile, String> {
+        self.profiles.get(name).ok_or_else(|| {
+            let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            format!(
+                "no profile named {name:?} (profiles: {})",
+                if names.is_empty() {
+                    "none".to_string()
+                } else {
+                    names.join(", ")
+                }
+            )
+        })
+    }
+}
+
+/// Pruning reads the manifest of every encrypted archive to find the
+/// archives it depends on, so a profile that encrypts and has a retention
+/// policy needs a passphrase file or an identity to read them with.
+pub fn prune_credentials(config: &BackupConfig) -> Result<(), String> {
+    let credentials = &config.credentials;
+    if config.encrypt
+        && credentials.passphrase_file.is_none()
+        && credentials.identity_files.is_empty()
+    {
+        return Err(
+            "retention of an encrypted profile needs passphrase_file or identities to prune"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Problems that would stop a run of `profile`, without writing anything.
+fn profile_problems(profile: &Profile) -> Vec<String> {
+    let config = match BackupConfig::from_profile(profile, std::iter::empty()) {
+        Ok(c) => c,
+        Err(e) => return vec![e],
+    };
+    let mut problems = Vec::new();
+
+    let roots = validation::source_roots(&config.sources)
+        .inspect_err(|e| problems.push(e.clone()))
+        .unwrap_or_default();
+    if config.destination.exists() && !config.destination.is_dir() {
+        problems.push(format!(
+            "destination exists and is not a directory: {:?}",
+            config.destination
+        ));
+    }
+    if let Ok(destination) = fs::canonicalize(&config.destination)
+        && roots.iter().any(|r| destination.starts_with(&r.path))
+    {
+        problems.push("destination cannot be inside a source directory".to_string());
+    }
+
+    for path in &config.recipients_files {
+        if let Err(e) = crypto::read_recipients_file(path) {
+            problems.push(e.to_string());
+        }
+    }
+    let files = config
+        .credentials
+        .passphrase_file
+        .iter()
+        .chain(&config.credentials.identity_files)
+        .chain(&config.incremental_from)
+        .chain(&config.differential_from);
+    for path in files {
+        if !path.is_file() {
+            problems.push(format!("file not found: {:?}", path));
+        }
+    }
+    if let Some(path) = &config.sign_key
+        && let Err(e) = signing::read_signing_key(path)
+    {
+        problems.push(e.to_string());
+    }
+
+    if let Some(retention) = &profile.retention {
+        problems.extend(retention.policy().err());
+        problems.extend(prune_credentials(&config).err());
+    }
+    problems
+}
+
+/// Validates every profile of the configuration file, printing the problems
+/// found. Returns whether the file is usable.
+pub fn check(path: Option<&Path>) -> bool {
+    let (path, file) = match load(path) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{e}");
+            return false;
+        }
+    };
+
+    print_section("config");
+    print_kv("file", path.to_string_lossy());
+    print_kv("profiles", file.profiles.len().to_string());
+
+    let mut ok = true;
+    for (name, profile) in &file.profiles {
+        let problems = profile_problems(profile);
+        if problems.is_empty() {
+            print_kv(name, "ok");
+            continue;
+        }
+        ok = false;
+        print_kv(name, format!("{} problem(s)", problems.len()));
+        for problem in problems {
+            eprintln!("    {problem}");
+        }
+    }
+    ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("backup-profile-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes a configuration file to `dir`, with `{dir}` standing for the
+    /// directory.
+    fn write_config(dir: &Path, text: &str) -> PathBuf {
+        let path = dir.join("config.toml");
+        fs::write(&path, text.replace("{dir}", dir.to_str().unwrap())).unwrap();
+        path
+    }
+
+    #[test]
+    fn profiles_are_looked_up_by_name() {
+        let dir = scratch_dir("lookup");
+        let path = write_config(
+            &dir,
+            r#"
+            [profiles.nightly]
+            source = "/data"
+            destination = "/backups"
+
+            [profiles.weekly]
+            sources = ["/etc", "/home"]
+            destination = "/backups"
+            "#,
+        );
+
+        let (loaded, file) = load(Some(&path)).unwrap();
+        assert_eq!(loaded, path);
+        assert_eq!(
+            file.profile("nightly").unwrap().source,
+            Some("/data".into())
+        );
+        assert_eq!(file.profile("weekly").unwrap().sources.len(), 2);
+        let err = file.profile("hourly").unwrap_err();
+        assert_eq!(
+            err,
+            r#"no profile named "hourly" (profiles: nightly, weekly)"#
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let dir = scratch_dir("unknown-key");
+        let path = write_config(
+            &dir,
+            r#"
+            [profiles.nightly]
+            source = "/data"
+            destination = "/backups"
+            compression = "zstd"
+            "#,
+        );
+
+        let err = load(Some(&path)).unwrap_err();
+        assert!(err.contains("unknown field `compression`"), "{err}");
+        assert!(!check(Some(&path)));
+    }
+
+    #[test]
+    fn check_reports_problems_of_each_profile() {
+        let dir = scratch_dir("check");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        let path = write_config(
+            &dir,
+            r#"
+            [profiles.good]
+            source = "{dir}/src"
+            destination = "{dir}/out"
+
+            [profiles.broken]
+            source = "{dir}/missing"
+            destination = "{dir}/src/out"
+            passphrase_file = "{dir}/no-such-file"
+            encrypt = true
+
+            [profiles.keep_nothing]
+            source = "{dir}/src"
+            destination = "{dir}/out"
+            retention = {}
+            "#,
+        );
+        let (_, file) = load(Some(&path)).unwrap();
+
+        assert!(profile_problems(file.profile("good").unwrap()).is_empty());
+        let problems = profile_problems(file.profile("broken").unwrap());
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[1].starts_with("file not found"), "{problems:?}");
+        let problems = profile_problems(file.profile("keep_nothing").unwrap());
+        assert_eq!(
+            problems,
+            ["retention has no keep_* setting; it would remove every archive"]
+        );
+        assert!(!check(Some(&path)));
+    }
+
+    #[test]
+    fn encrypted_retention_needs_credentials() {
+        let dir = scratch_dir("retention");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("passphrase"), "secret\n").unwrap();
+        let path = write_config(
+            &dir,
+            r#"
+            [profiles.no_credentials]
+            source = "{dir}/src"
+            destination = "{dir}/out"
+            encrypt = true
+            retention = { keep_last = 3 }
+
+            [profiles.passphrase]
+            source = "{dir}/src"
+            destination = "{dir}/out"
+            encrypt = true
+            passphrase_file = "{dir}/passphrase"
+            retention = { keep_last = 3 }
+            "#,
+        );
+        let (_, file) = load(Some(&path)).unwrap();
+
+        let problems = profile_problems(file.profile("passphrase").unwrap());
+        assert!(problems.is_empty(), "{problems:?}");
+        let problems = profile_problems(file.profile("no_credentials").unwrap());
+        assert_eq!(
+            problems,
+            ["retention of an encrypted profile needs passphrase_file or identities to prune"]
+        );
+    }
+}
@@ -0,0 +1,555 @@
+use crate::archive::ArchiveReader;
+use crate::config::PruneConfig;
+use crate::crypto::{Credentials, ENVELOPE_MAGIC};
+use crate::types::ParentRef;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+struct BackupManifest {
+    pub created_at: u64,
+    #[serde(default)]
+    pub parent: Option<ParentRef>,
+    #[serde(default)]
+    pub base: Option<ParentRef>,
+}
+
+fn print_section(title: &str) {
+    println!();
+    println!("--- {title} ---");
+}
+
+fn print_kv<K: AsRef<str>, V: AsRef<str>>(k: K, v: V) {
+    println!("  {:12} {}", format!("{}:", k.as_ref()), v.as_ref());
+}
+
+/// Which archives of a source to keep. Every rule keeps archives on its own;
+/// an archive is removed only if no rule keeps it and no kept archive needs
+/// it as parent or base.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_hourly: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+    pub keep_yearly: usize,
+    /// seconds before the newest archive within which all are kept
+    pub keep_within: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.keep_last == 0
+            && self.keep_hourly == 0
+            && self.keep_daily == 0
+            && self.keep_weekly == 0
+            && self.keep_monthly == 0
+            && self.keep_yearly == 0
+            && self.keep_within.is_none()
+    }
+
+    pub fn describe(&self) -> String {
+        let mut parts: Vec<String> = [
+            ("last", self.keep_last),
+            ("hourly", self.keep_hourly),
+            ("daily", self.keep_daily),
+            ("weekly", self.keep_weekly),
+            ("monthly", self.keep_monthly),
+            ("yearly", self.keep_yearly),
+        ]
+        .iter()
+        .filter(|(_, n)| *n > 0)
+        .map(|(name, n)| format!("{name} {n}"))
+        .collect();
+        if let Some(within) = self.keep_within {
+            parts.push(format!("within {}", format_duration(within)));
+        }
+        parts.join(", ")
+    }
+
+    /// Returns, for each of `times` (newest first), the rules keeping it.
+    /// Calendar rules keep the newest archive of each hour, day, week, month
+    /// or year, going back until they have kept their count.
+    fn reasons(&self, times: &[u64]) -> Vec<Vec<&'static str>> {
+        let mut reasons = vec![Vec::new(); times.len()];
+        let Some(&newest) = times.first() else {
+            return reasons;
+        };
+
+        for r in reasons.iter_mut().take(self.keep_last) {
+            r.push("last");
+        }
+        if let Some(within) = self.keep_within {
+            for (r, t) in reasons.iter_mut().zip(times) {
+                if newest.saturating_sub(*t) <= within {
+                    r.push("within");
+                }
+            }
+        }
+
+        let calendar: [(&'static str, usize, Bucket); 5] = [
+            ("hourly", self.keep_hourly, |t| (t / 3600) as i64),
+            ("daily", self.keep_daily, |t| (t / 86400) as i64),
+            // 1970-01-01 was a Thursday; weeks start on Monday
+            ("weekly", self.keep_weekly, |t| ((t / 86400 + 3) / 7) as i64),
+            ("monthly", self.keep_monthly, |t| {
+                let (y, m, _) = civil_from_days((t / 86400) as i64);
+                y * 12 + m as i64
+            }),
+            ("yearly", self.keep_yearly, |t| {
+                civil_from_days((t / 86400) as i64).0
+            }),
+        ];
+        for (name, count, bucket) in calendar {
+            let mut last = None;
+            let mut kept = 0;
+            for (r, t) in reasons.iter_mut().zip(times) {
+                if kept == count {
+                    break;
+                }
+                let b = bucket(*t);
+                if last != Some(b) {
+                    r.push(name);
+                    last = Some(b);
+                    kept += 1;
+                }
+            }
+        }
+        reasons
+    }
+}
+
+/// Maps a time to the hour, day, week, month or year it falls in.
+type Bucket = fn(u64) -> i64;
+
+/// Converts days since 1970-01-01 to a (year, month, day) UTC date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+const DURATION_UNITS: [(char, u64); 5] = [
+    ('y', 365 * 86400),
+    ('m', 30 * 86400),
+    ('w', 7 * 86400),
+    ('d', 86400),
+    ('h', 3600),
+];
+
+/// Parses a duration such as `30d`, `12h` or `1y6m` into seconds. Months
+/// count as 30 days and years as 365.
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid duration {s:?}, expected e.g. 30d, 12h or 1y6m");
+    let mut total = 0u64;
+    let mut digits = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let (_, unit) = DURATION_UNITS
+            .iter()
+            .find(|(u, _)| *u == c)
+            .ok_or_else(invalid)?;
+        let n: u64 = digits.parse().map_err(|_| invalid())?;
+        total = n
+            .checked_mul(*unit)
+            .and_then(|v| total.checked_add(v))
+            .ok_or_else(invalid)?;
+        digits.clear();
+    }
+    if !digits.is_empty() || total == 0 {
+        return Err(invalid());
+    }
+    Ok(total)
+}
+
+fn format_duration(mut secs: u64) -> String {
+    let mut out = String::new();
+    for (unit, len) in DURATION_UNITS {
+        if secs >= len {
+            out.push_str(&format!("{}{unit}", secs / len));
+            secs %= len;
+        }
+    }
+    if out.is_empty() {
+        out = format!("{secs}s");
+    }
+    out
+}
+
+/// An archive found in the backup directory.
+struct Candidate {
+    path: PathBuf,
+    file_name: String,
+    source: String,
+    time: u64,
+    size: u64,
+    /// file name of the parent or base archive this one needs for a restore
+    depends_on: Option<String>,
+}
+
+fn is_encrypted(path: &Path) -> io::Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = File::open(path)?;
+    Ok(file.read_exact(&mut magic).is_ok() && &magic == ENVELOPE_MAGIC)
+}
+
+/// Reads the archives of the backup directory, or only those of the source
+/// named `only`. Encrypted archives need credentials, since without their
+/// manifest it is unknown which other archives they depend on; archives that
+/// are otherwise unreadable cannot be restored anyway and are only kept or
+/// removed by their file name.
+fn scan(dir: &Path, only: Option<&str>, credentials: &Credentials) -> io::Result<Vec<Candidate>> {
+    let has_credentials =
+        credentials.passphrase_file.is_some() || !credentials.identity_files.is_empty();
+    let mut candidates = Vec::new();
+
+    for dent in fs::read_dir(dir)? {
+        let dent = dent?;
+        let file_name = dent.file_name().to_string_lossy().to_string();
+        let Some((source, ts)) = file_name
+            .strip_suffix(".backup")
+            .and_then(|stem| stem.rsplit_once('-'))
+            .and_then(|(source, ts)| Some((source.to_string(), ts.parse::<u64>().ok()?)))
+        else {
+            continue;
+        };
+        if only.is_some_and(|s| s != source) {
+            continue;
+        }
+        let path = dent.path();
+        let size = dent.metadata()?.len();
+
+        if is_encrypted(&path)? && !has_credentials {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{file_name} is encrypted; pass --passphrase-file or --identity so the \
+                     archives it depends on are known"
+                ),
+            ));
+        }
+
+        let manifest = ArchiveReader::open(&path, credentials).and_then(|a| a.manifest());
+        let (time, depends_on) = match manifest {
+            Ok(m) => {
+                let BackupManifest {
+                    created_at,
+                    parent,
+                    base,
+                } = m;
+                (created_at, parent.or(base).map(|r| r.backup_file))
+            }
+            Err(e) if is_encrypted(&path)? => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("failed to read {file_name}: {e}"),
+                ));
+            }
+            Err(e) => {
+                eprintln!("warning: failed to read {file_name}, using the time in its name: {e}");
+                (ts, None)
+            }
+        };
+
+        candidates.push(Candidate {
+            path,
+            file_name,
+            source,
+            time,
+            size,
+            depends_on,
+        });
+    }
+    Ok(candidates)
+}
+
+/// Indices of `candidates` by source name, newest first.
+fn by_source(candidates: &[Candidate]) -> BTreeMap<&str, Vec<usize>> {
+    let mut groups: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (i, c) in candidates.iter().enumerate() {
+        groups.entry(c.source.as_str()).or_default().push(i);
+    }
+    for members in groups.values_mut() {
+        members.sort_by_key(|&i| std::cmp::Reverse(candidates[i].time));
+    }
+    groups
+}
+
+/// Returns, for each candidate, why it is kept; an archive without reasons
+/// is removed. The policy applies to each group of `groups` separately, and
+/// every kept archive keeps its whole chain of parents and bases.
+fn keep_reasons(
+    candidates: &[Candidate],
+    groups: &BTreeMap<&str, Vec<usize>>,
+    policy: &RetentionPolicy,
+) -> Vec<Vec<String>> {
+    let mut reasons: Vec<Vec<String>> = vec![Vec::new(); candidates.len()];
+    for members in groups.values() {
+        let times: Vec<u64> = members.iter().map(|&i| candidates[i].time).collect();
+        for (&i, r) in members.iter().zip(policy.reasons(&times)) {
+            reasons[i].extend(r.into_iter().map(str::to_string));
+        }
+    }
+
+    let by_name: HashMap<&str, usize> = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.file_name.as_str(), i))
+        .collect();
+    let kept: Vec<usize> = (0..candidates.len())
+        .filter(|&i| !reasons[i].is_empty())
+        .collect();
+    for i in kept {
+        let mut child = i;
+        while let Some(dep) = &candidates[child].depends_on {
+            let Some(&parent) = by_name.get(dep.as_str()) else {
+                eprintln!(
+                    "warning: {} needs {dep}, which is not in the backup directory",
+                    candidates[child].file_name
+                );
+                break;
+            };
+            let reason = format!("needed by {}", candidates[child].file_name);
+            let already_kept = !reasons[parent].is_empty();
+            reasons[parent].push(reason);
+            if already_kept {
+                break;
+            }
+            child = parent;
+        }
+    }
+    reasons
+}
+
+/// Removes the archives of `config.backup_dir` that the retention policy
+/// does not keep, applying the policy to each source name separately.
+pub fn prune(config: &PruneConfig) -> io::Result<()> {
+    println!("==================== backup prune ====================");
+    print_kv("backup dir", config.backup_dir.to_string_lossy());
+    print_kv("policy", config.policy.describe());
+    if config.dry_run {
+        print_kv("mode", "dry-run");
+    }
+
+    if let Some(source) = &config.source {
+        print_kv("source", source);
+    }
+
+    let candidates = scan(
+        &config.backup_dir,
+        config.source.as_deref(),
+        &config.credentials,
+    )?;
+
+    let groups = by_source(&candidates);
+    let reasons = keep_reasons(&candidates, &groups, &config.policy);
+
+    let mut kept_count = 0usize;
+    let mut removed = 0usize;
+    let mut freed = 0u64;
+    for (source, members) in &groups {
+        print_section(source);
+        for &i in members {
+            let c = &candidates[i];
+            if !reasons[i].is_empty() {
+                println!("  keep    {}  ({})", c.file_name, reasons[i].join(", "));
+                kept_count += 1;
+                continue;
+            }
+
+            if config.dry_run {
+                println!("  remove  {}  (dry-run)", c.file_name);
+            } else if let Err(e) = fs::remove_file(&c.path) {
+                eprintln!("prune: failed to remove {:?}: {e}", c.path);
+                continue;
+            } else {
+                println!("  remove  {}", c.file_name);
+            }
+            removed += 1;
+            freed += c.size;
+        }
+    }
+
+    print_section("summary");
+    print_kv("kept", kept_count.to_string());
+    print_kv(
+        if config.dry_run {
+            "to remove"
+        } else {
+            "removed"
+        },
+        removed.to_string(),
+    );
+    print_kv("freed bytes", freed.to_string());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01 00:00:00 UTC, a Monday.
+    const JAN_1_2024: u64 = 1_704_067_200;
+    const HOUR: u64 = 3600;
+    const DAY: u64 = 86400;
+
+    fn policy() -> RetentionPolicy {
+        RetentionPolicy::default()
+    }
+
+    fn kept_by(reasons: &[Vec<&str>], rule: &str) -> Vec<usize> {
+        reasons
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.contains(&rule))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    #[test]
+    fn civil_from_days_converts_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+        assert_eq!(civil_from_days((JAN_1_2024 / DAY) as i64), (2024, 1, 1));
+        assert_eq!(
+            civil_from_days((JAN_1_2024 / DAY) as i64 - 1),
+            (2023, 12, 31)
+        );
+    }
+
+    #[test]
+    fn parse_duration_accepts_units_and_rejects_garbage() {
+        assert_eq!(parse_duration("30d"), Ok(30 * DAY));
+        assert_eq!(parse_duration("12h"), Ok(12 * HOUR));
+        assert_eq!(parse_duration("2w"), Ok(14 * DAY));
+        assert_eq!(parse_duration("1y6m"), Ok(365 * DAY + 180 * DAY));
+        for bad in ["", "5", "d", "3x", "0d", "1d2", "99999999999999999999y"] {
+            assert!(parse_duration(bad).is_err(), "{bad:?} accepted");
+        }
+        assert_eq!(format_duration(parse_duration("1y6m").unwrap()), "1y6m");
+    }
+
+    #[test]
+    fn keep_last_and_within_boundary() {
+        let newest = JAN_1_2024 + 10 * DAY;
+        let times = [newest, newest - DAY, newest - 2 * DAY, newest - 2 * DAY - 1];
+        let reasons = RetentionPolicy {
+            keep_last: 1,
+            keep_within: Some(2 * DAY),
+            ..policy()
+        }
+        .reasons(&times);
+
+        assert_eq!(kept_by(&reasons, "last"), [0]);
+        // exactly `within` before the newest is still kept, a second more is not
+        assert_eq!(kept_by(&reasons, "within"), [0, 1, 2]);
+        assert!(reasons[3].is_empty());
+    }
+
+    #[test]
+    fn calendar_rules_keep_the_newest_of_each_period() {
+        // newest first: two on Jan 3, one on Jan 2, two on Jan 1 and one
+        // on Sunday Dec 31 of the week before
+        let times = [
+            JAN_1_2024 + 2 * DAY + 20 * HOUR,
+            JAN_1_2024 + 2 * DAY + 8 * HOUR,
+            JAN_1_2024 + DAY + 12 * HOUR,
+            JAN_1_2024 + 23 * HOUR,
+            JAN_1_2024 + HOUR,
+            JAN_1_2024 - HOUR,
+        ];
+        let reasons = RetentionPolicy {
+            keep_hourly: 2,
+            keep_daily: 3,
+            keep_weekly: 2,
+            keep_monthly: 2,
+            keep_yearly: 5,
+            ..policy()
+        }
+        .reasons(&times);
+
+        assert_eq!(kept_by(&reasons, "hourly"), [0, 1]);
+        assert_eq!(kept_by(&reasons, "daily"), [0, 2, 3]);
+        // weeks start on Monday, so Sunday Dec 31 is the week before
+        assert_eq!(kept_by(&reasons, "weekly"), [0, 5]);
+        assert_eq!(kept_by(&reasons, "monthly"), [0, 5]);
+        assert_eq!(kept_by(&reasons, "yearly"), [0, 5]);
+        assert!(reasons[4].is_empty());
+    }
+
+    fn candidate(file_name: &str, time: u64, depends_on: Option<&str>) -> Candidate {
+        let source = file_name.rsplit_once('-').unwrap().0.to_string();
+        Candidate {
+            path: PathBuf::from(file_name),
+            file_name: file_name.to_string(),
+            source,
+            time,
+            size: 0,
+            depends_on: depends_on.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn kept_incremental_keeps_its_chain() {
+        let t = JAN_1_2024;
+        let candidates = [
+            candidate("docs-1.backup", t, None),
+            candidate("docs-2.backup", t + DAY, Some("docs-1.backup")),
+            candidate("docs-3.backup", t + 2 * DAY, Some("docs-2.backup")),
+            candidate("docs-4.backup", t + 3 * DAY, None),
+            candidate("docs-5.backup", t + 4 * DAY, Some("docs-4.backup")),
+            candidate("docs-6.backup", t + 5 * DAY, Some("docs-1.backup")),
+            candidate("etc-1.backup", t, None),
+        ];
+        let groups = by_source(&candidates);
+        assert_eq!(groups["docs"], [5, 4, 3, 2, 1, 0]);
+
+        // keeps docs-6, a differential whose base docs-1 is far outside the
+        // policy, and docs-3, an incremental on top of docs-2 and docs-1
+        let keep = RetentionPolicy {
+            keep_last: 1,
+            keep_within: Some(3 * DAY),
+            ..policy()
+        };
+        let reasons = keep_reasons(&candidates, &groups, &keep);
+
+        let removed: Vec<&str> = candidates
+            .iter()
+            .zip(&reasons)
+            .filter(|(_, r)| r.is_empty())
+            .map(|(c, _)| c.file_name.as_str())
+            .collect();
+        assert!(removed.is_empty(), "removed {removed:?}");
+        assert!(reasons[0].contains(&"needed by docs-6.backup".to_string()));
+        assert!(reasons[0].contains(&"needed by docs-2.backup".to_string()));
+        assert_eq!(reasons[1], ["needed by docs-3.backup"]);
+        assert_eq!(reasons[5], ["last", "within"]);
+        // each source has its own policy
+        assert_eq!(reasons[6], ["last", "within"]);
+
+        let last_only = RetentionPolicy {
+            keep_last: 1,
+            ..policy()
+        };
+        let reasons = keep_reasons(&candidates[..5], &by_source(&candidates[..5]), &last_only);
+        assert_eq!(reasons[4], ["last"]);
+        assert_eq!(reasons[3], ["needed by docs-5.backup"]);
+        assert!(reasons[..3].iter().all(Vec::is_empty));
+    }
+}
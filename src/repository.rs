@@ -0,0 +1,641 @@
+use crate::chunker::ChunkParams;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const CONFIG_FILE: &str = "repository.json";
+const FORMAT: &str = "backup-repository";
+/// Version 2 added content-defined chunking; version 1 repositories hold
+/// whole files as blobs and are chunked with the default parameters from
+/// then on.
+const VERSION: u32 = 2;
+pub const PACK_MAGIC: &[u8; 4] = b"BKPK";
+const PACK_VERSION: u32 = 1;
+/// A pack is closed and a new one started once it grows past this size.
+const PACK_TARGET_SIZE: u64 = 64 * 1024 * 1024;
+const BLOB_HEADER_LEN: u64 = 32 + 8;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RepositoryConfig {
+    format: String,
+    version: u32,
+    created_at: u64,
+    #[serde(default)]
+    chunking: ChunkParams,
+}
+
+/// Index of one pack, stored as `index/<pack>.json` once the pack is
+/// complete.
+#[derive(Debug, Serialize, Deserialize)]
+struct PackIndex {
+    pack: String,
+    blobs: Vec<IndexedBlob>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexedBlob {
+    id: String,
+    /// offset of the blob data in the pack
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Debug, Clone)]
+struct BlobLocation {
+    pack: String,
+    offset: u64,
+    length: u64,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn blob_id_from_hex(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(msg: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Makes renames and new files in `dir` durable.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Writes `contents` to `path` through a temporary file and a rename, so
+/// readers never see a partially written file.
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".partial");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent() {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+pub fn is_repository(path: &Path) -> bool {
+    path.join(CONFIG_FILE).is_file()
+}
+
+/// Creates an empty repository at `path`, which must not exist yet or be an
+/// empty directory. `chunking` is fixed for the lifetime of the repository.
+pub fn init(path: &Path, chunking: ChunkParams) -> io::Result<()> {
+    if path.exists() {
+        if !path.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} exists and is not a directory", path),
+            ));
+        }
+        if fs::read_dir(path)?.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} is not empty", path),
+            ));
+        }
+    }
+
+    for dir in ["packs", "index", "snapshots"] {
+        fs::create_dir_all(path.join(dir))?;
+    }
+
+    let config = RepositoryConfig {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        chunking,
+    };
+    let json = serde_json::to_string_pretty(&config).map_err(io::Error::other)?;
+    write_atomic(&path.join(CONFIG_FILE), json.as_bytes())
+}
+
+/// A content-addressed store: file contents are kept once as blobs named by
+/// their SHA-256, appended to pack files, and snapshots are manifests that
+/// refer to blobs by that hash.
+pub struct Repository {
+    root: PathBuf,
+    chunking: ChunkParams,
+    blobs: HashMap<[u8; 32], BlobLocation>,
+    packs: usize,
+}
+
+impl Repository {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let raw = fs::read(path.join(CONFIG_FILE)).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("{:?} is not a backup repository: {e}", path),
+            )
+        })?;
+        let config: RepositoryConfig = serde_json::from_slice(&raw)
+            .map_err(|e| invalid_data(format!("invalid repository config: {e}")))?;
+        if config.format != FORMAT {
+            return Err(invalid_data(format!(
+                "{:?} is not a backup repository",
+                path
+            )));
+        }
+        if config.version > VERSION {
+            return Err(invalid_data(format!(
+                "unsupported repository version {}",
+                config.version
+            )));
+        }
+        config
+            .chunking
+            .validate()
+            .map_err(|e| invalid_data(format!("invalid repository config: {e}")))?;
+
+        let mut blobs = HashMap::new();
+        let mut packs = 0;
+        for dent in fs::read_dir(path.join("index"))? {
+            let index_path = dent?.path();
+            if index_path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let index: PackIndex = serde_json::from_slice(&fs::read(&index_path)?)
+                .map_err(|e| invalid_data(format!("invalid pack index {:?}: {e}", index_path)))?;
+            if !index.pack.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(invalid_data(format!(
+                    "invalid pack name in {:?}",
+                    index_path
+                )));
+            }
+            for blob in index.blobs {
+                let id = blob_id_from_hex(&blob.id)
+                    .ok_or_else(|| invalid_data(format!("invalid blob id in {:?}", index_path)))?;
+                blobs.insert(
+                    id,
+                    BlobLocation {
+                        pack: index.pack.clone(),
+                        offset: blob.offset,
+                        length: blob.length,
+                    },
+                );
+            }
+            packs += 1;
+        }
+
+        Ok(Self {
+            root: path.to_path_buf(),
+            chunking: config.chunking,
+            blobs,
+            packs,
+        })
+    }
+
+    pub fn chunking(&self) -> ChunkParams {
+        self.chunking
+    }
+
+    pub fn blob_count(&self) -> usize {
+        self.blobs.len()
+    }
+
+    pub fn pack_count(&self) -> usize {
+        self.packs
+    }
+
+    /// Total size of all blobs, i.e. of the deduplicated contents.
+    pub fn blob_bytes(&self) -> u64 {
+        self.blobs.values().map(|b| b.length).sum()
+    }
+
+    pub fn contains(&self, id: &[u8; 32]) -> bool {
+        self.blobs.contains_key(id)
+    }
+
+    /// Opens the content made up of the blobs `ids`, in order, or returns
+    /// `None` if any of them is missing.
+    pub fn read_chunks(&self, ids: &[[u8; 32]]) -> Option<ChunkReader<'_>> {
+        if !ids.iter().all(|id| self.contains(id)) {
+            return None;
+        }
+        Some(ChunkReader {
+            repo: self,
+            ids: ids.to_vec(),
+            next: 0,
+            current: None,
+        })
+    }
+
+    fn pack_path(&self, pack: &str) -> PathBuf {
+        self.root.join("packs").join(format!("{pack}.pack"))
+    }
+
+    pub fn snapshot_path(&self, name: &str) -> PathBuf {
+        self.root.join("snapshots").join(format!("{name}.json"))
+    }
+
+    /// Snapshot names, oldest first.
+    pub fn snapshots(&self) -> io::Result<Vec<String>> {
+        let mut names: Vec<(u64, String)> = Vec::new();
+        for dent in fs::read_dir(self.root.join("snapshots"))? {
+            let name = dent?.file_name().to_string_lossy().to_string();
+            let Some(name) = name.strip_suffix(".json") else {
+                continue;
+            };
+            let ts = name
+                .rsplit_once('-')
+                .and_then(|(_, ts)| ts.parse().ok())
+                .unwrap_or(0);
+            names.push((ts, name.to_string()));
+        }
+        names.sort();
+        Ok(names.into_iter().map(|(_, name)| name).collect())
+    }
+
+    /// Reads the manifest of snapshot `name`, or of the newest snapshot for
+    /// `latest`. Returns the resolved name along with the manifest JSON.
+    pub fn load_snapshot(&self, name: &str) -> io::Result<(String, Vec<u8>)> {
+        let name = if name == "latest" {
+            self.snapshots()?
+                .pop()
+                .ok_or_else(|| invalid_data("repository has no snapshots"))?
+        } else {
+            name.strip_suffix(".json").unwrap_or(name).to_string()
+        };
+        if Path::new(&name).components().count() != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid snapshot name {name:?}"),
+            ));
+        }
+
+        let manifest = fs::read(self.snapshot_path(&name)).map_err(|e| {
+            io::Error::new(e.kind(), format!("failed to read snapshot {name:?}: {e}"))
+        })?;
+        Ok((name, manifest))
+    }
+
+    /// Stores the manifest of a new snapshot. Blobs it refers to must have
+    /// been written with a [`PackWriter`] that is already finished.
+    pub fn write_snapshot(&self, name: &str, manifest_json: &str) -> io::Result<PathBuf> {
+        let path = self.snapshot_path(name);
+        if path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("snapshot {name:?} already exists"),
+            ));
+        }
+        write_atomic(&path, manifest_json.as_bytes())?;
+        Ok(path)
+    }
+}
+
+/// Reads the concatenation of several blobs, opening one at a time.
+pub struct ChunkReader<'a> {
+    repo: &'a Repository,
+    ids: Vec<[u8; 32]>,
+    next: usize,
+    current: Option<io::Take<BufReader<File>>>,
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(current) = &mut self.current {
+                let n = current.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+                self.current = None;
+            }
+
+            let Some(id) = self.ids.get(self.next) else {
+                return Ok(0);
+            };
+            self.next += 1;
+            let loc = self
+                .repo
+                .blobs
+                .get(id)
+                .ok_or_else(|| invalid_data(format!("missing blob {}", to_hex(id))))?;
+            let mut file = File::open(self.repo.pack_path(&loc.pack))?;
+            file.seek(SeekFrom::Start(loc.offset))?;
+            self.current = Some(BufReader::new(file).take(loc.length));
+        }
+    }
+}
+
+struct OpenPack {
+    name: String,
+    temp_path: PathBuf,
+    writer: BufWriter<File>,
+    offset: u64,
+    blobs: Vec<IndexedBlob>,
+}
+
+/// Appends new blobs to packs. A pack and its index only become visible once
+/// the pack is complete, so an interrupted snapshot leaves no dangling
+/// references behind.
+pub struct PackWriter<'a> {
+    repo: &'a mut Repository,
+    current: Option<OpenPack>,
+    pending: HashSet<[u8; 32]>,
+    pub new_blobs: usize,
+    pub new_bytes: u64,
+}
+
+impl<'a> PackWriter<'a> {
+    pub fn new(repo: &'a mut Repository) -> Self {
+        Self {
+            repo,
+            current: None,
+            pending: HashSet::new(),
+            new_blobs: 0,
+            new_bytes: 0,
+        }
+    }
+
+    /// Whether blob `id` is already in the repository or in this run's packs.
+    pub fn contains(&self, id: &[u8; 32]) -> bool {
+        self.repo.contains(id) || self.pending.contains(id)
+    }
+
+    fn open_pack(&mut self) -> io::Result<&mut OpenPack> {
+        if self.current.is_none() {
+            let mut raw = [0u8; 16];
+            SystemRandom::new()
+                .fill(&mut raw)
+                .map_err(|_| io::Error::other("failed to generate pack name"))?;
+            let name = to_hex(&raw);
+            let temp_path = self
+                .repo
+                .root
+                .join("packs")
+                .join(format!("{name}.pack.partial"));
+
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&temp_path)?;
+            let mut writer = BufWriter::new(file);
+            writer.write_all(PACK_MAGIC)?;
+            writer.write_all(&PACK_VERSION.to_le_bytes())?;
+
+            self.current = Some(OpenPack {
+                name,
+                temp_path,
+                writer,
+                offset: 8,
+                blobs: Vec::new(),
+            });
+        }
+        Ok(self.current.as_mut().expect("pack was just opened"))
+    }
+
+    /// Appends `size` bytes read from `data` as blob `id`. Returns `false`,
+    /// and writes nothing, if the data does not hash to `id` or is not
+    /// `size` bytes long, e.g. because the file changed since it was hashed.
+    pub fn add_blob<R: Read>(
+        &mut self,
+        id: &[u8; 32],
+        data: &mut R,
+        size: u64,
+    ) -> io::Result<bool> {
+        let pack = self.open_pack()?;
+        let start = pack.offset;
+
+        pack.writer.write_all(id)?;
+        pack.writer.write_all(&size.to_le_bytes())?;
+
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        let mut limited = data.take(size);
+        let mut buf = [0u8; 8192];
+        let mut written = 0u64;
+        loop {
+            let n = limited.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            pack.writer.write_all(&buf[..n])?;
+            ctx.update(&buf[..n]);
+            written += n as u64;
+        }
+        let grew = data.read(&mut [0u8; 1])? > 0;
+
+        if written != size || grew || ctx.finish().as_ref() != id {
+            pack.writer.flush()?;
+            pack.writer.get_mut().set_len(start)?;
+            pack.writer.seek(SeekFrom::Start(start))?;
+            return Ok(false);
+        }
+
+        pack.blobs.push(IndexedBlob {
+            id: to_hex(id),
+            offset: start + BLOB_HEADER_LEN,
+            length: size,
+        });
+        pack.offset = start + BLOB_HEADER_LEN + size;
+        let full = pack.offset >= PACK_TARGET_SIZE;
+
+        self.pending.insert(*id);
+        self.new_blobs += 1;
+        self.new_bytes += size;
+        if full {
+            self.close_pack()?;
+        }
+        Ok(true)
+    }
+
+    fn close_pack(&mut self) -> io::Result<()> {
+        let Some(mut pack) = self.current.take() else {
+            return Ok(());
+        };
+        pack.writer.flush()?;
+        if pack.blobs.is_empty() {
+            drop(pack.writer);
+            return fs::remove_file(&pack.temp_path);
+        }
+        pack.writer.get_ref().sync_all()?;
+        drop(pack.writer);
+
+        let packs_dir = self.repo.root.join("packs");
+        fs::rename(&pack.temp_path, self.repo.pack_path(&pack.name))?;
+        sync_dir(&packs_dir)?;
+
+        let index = PackIndex {
+            pack: pack.name.clone(),
+            blobs: pack.blobs,
+        };
+        let json = serde_json::to_string(&index).map_err(io::Error::other)?;
+        let index_path = self
+            .repo
+            .root
+            .join("index")
+            .join(format!("{}.json", pack.name));
+        write_atomic(&index_path, json.as_bytes())?;
+
+        for blob in index.blobs {
+            if let Some(id) = blob_id_from_hex(&blob.id) {
+                self.pending.remove(&id);
+                self.repo.blobs.insert(
+                    id,
+                    BlobLocation {
+                        pack: pack.name.clone(),
+                        offset: blob.offset,
+                        length: blob.length,
+                    },
+                );
+            }
+        }
+        self.repo.packs += 1;
+        Ok(())
+    }
+
+    /// Completes the open pack, after which all added blobs can be read.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.close_pack()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: ChunkParams = ChunkParams {
+        min_size: 256,
+        avg_size: 1024,
+        max_size: 4096,
+    };
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("backup-repository-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn id(data: &[u8]) -> [u8; 32] {
+        let mut id = [0u8; 32];
+        id.copy_from_slice(digest::digest(&digest::SHA256, data).as_ref());
+        id
+    }
+
+    fn add(writer: &mut PackWriter, data: &[u8]) -> bool {
+        writer
+            .add_blob(&id(data), &mut &data[..], data.len() as u64)
+            .unwrap()
+    }
+
+    fn read_all(repo: &Repository, ids: &[[u8; 32]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        repo.read_chunks(ids)
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn init_creates_an_empty_repository() {
+        let repo_path = scratch_dir("init").join("repo");
+        init(&repo_path, PARAMS).unwrap();
+        assert!(is_repository(&repo_path));
+
+        let repo = Repository::open(&repo_path).unwrap();
+        assert_eq!(repo.chunking(), PARAMS);
+        assert_eq!(repo.blob_count(), 0);
+        assert!(repo.snapshots().unwrap().is_empty());
+
+        fs::write(repo_path.join("stray"), b"").unwrap();
+        let err = init(&repo_path, PARAMS).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn blobs_are_readable_after_reopening() {
+        let repo_path = scratch_dir("blobs").join("repo");
+        init(&repo_path, PARAMS).unwrap();
+
+        let mut repo = Repository::open(&repo_path).unwrap();
+        let mut writer = PackWriter::new(&mut repo);
+        assert!(add(&mut writer, b"first blob"));
+        assert!(add(&mut writer, b"second blob"));
+        // data that does not match its id is not stored
+        assert!(
+            !writer
+                .add_blob(&id(b"other"), &mut &b"changed"[..], 7)
+                .unwrap()
+        );
+        writer.finish().unwrap();
+
+        let repo = Repository::open(&repo_path).unwrap();
+        assert_eq!(repo.blob_count(), 2);
+        assert_eq!(repo.pack_count(), 1);
+        assert!(!repo.contains(&id(b"other")));
+        assert_eq!(
+            read_all(&repo, &[id(b"second blob"), id(b"first blob")]),
+            b"second blobfirst blob"
+        );
+        assert_eq!(read_all(&repo, &[]), b"");
+        assert!(repo.read_chunks(&[id(b"missing")]).is_none());
+    }
+
+    #[test]
+    fn known_blobs_are_not_written_again() {
+        let repo_path = scratch_dir("dedup").join("repo");
+        init(&repo_path, PARAMS).unwrap();
+
+        let mut repo = Repository::open(&repo_path).unwrap();
+        let mut writer = PackWriter::new(&mut repo);
+        add(&mut writer, b"shared");
+        writer.finish().unwrap();
+
+        let mut repo = Repository::open(&repo_path).unwrap();
+        let mut writer = PackWriter::new(&mut repo);
+        assert!(writer.contains(&id(b"shared")));
+        assert!(!writer.contains(&id(b"new")));
+        add(&mut writer, b"new");
+        assert!(writer.contains(&id(b"new")));
+        assert_eq!((writer.new_blobs, writer.new_bytes), (1, 3));
+        writer.finish().unwrap();
+
+        let repo = Repository::open(&repo_path).unwrap();
+        assert_eq!(repo.blob_count(), 2);
+        assert_eq!(repo.blob_bytes(), 9);
+    }
+
+    #[test]
+    fn snapshots_are_listed_oldest_first() {
+        let repo_path = scratch_dir("snapshots").join("repo");
+        init(&repo_path, PARAMS).unwrap();
+        let repo = Repository::open(&repo_path).unwrap();
+
+        repo.write_snapshot("data-20", "{\"second\":1}").unwrap();
+        repo.write_snapshot("data-3", "{\"first\":1}").unwrap();
+        let err = repo.write_snapshot("data-3", "{}").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        assert_eq!(repo.snapshots().unwrap(), ["data-3", "data-20"]);
+        let (name, manifest) = repo.load_snapshot("latest").unwrap();
+        assert_eq!(name, "data-20");
+        assert_eq!(manifest, b"{\"second\":1}");
+        assert!(repo.load_snapshot("../data-3").is_err());
+    }
+}
@@ -1,21 +1,362 @@
+use crate::archive::{ArchiveReader, EntryHeader};
+use crate::config::RestoreConfig;
+use crate::filter::PathFilter;
+use crate::hasher;
+use crate::incremental;
+use crate::metadata::{self, FileMeta};
+use crate::repository::{self, Repository};
+use crate::safe_path;
+use crate::types::{EntryKind, ParentRef, SourceRoot};
 use indicatif::ProgressBar;
 use ring::digest;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::ffi::CString;
 use std::fs::{self, File};
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::io::{self, BufWriter, IsTerminal, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Deserialize)]
 struct ManifestFile {
+    pub path: String,
+    #[serde(default)]
+    pub kind: EntryKind,
     pub size: u64,
+    #[serde(default)]
+    pub target: Option<String>,
+    #[serde(default)]
+    pub rdev: Option<u64>,
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub chunks: Vec<String>,
+    #[serde(default)]
+    pub meta: Option<FileMeta>,
 }
 
 #[derive(Debug, Deserialize)]
 struct BackupManifest {
     pub source: String,
+    #[serde(default)]
+    pub roots: Vec<SourceRoot>,
+    #[serde(default)]
+    pub parent: Option<ParentRef>,
+    #[serde(default)]
+    pub base: Option<ParentRef>,
     pub files: Vec<ManifestFile>,
 }
 
+impl BackupManifest {
+    /// Where each source was backed up from, for restoring it there. The
+    /// manifest is untrusted, so the locations must be absolute and the
+    /// top-level names plain directory names.
+    fn original_roots(&self) -> io::Result<Vec<SourceRoot>> {
+        let roots = if self.roots.is_empty() {
+            vec![SourceRoot {
+                name: String::new(),
+                path: PathBuf::from(&self.source),
+            }]
+        } else {
+            self.roots.clone()
+        };
+        for root in &roots {
+            let plain_name = self.roots.is_empty()
+                || matches!(
+                    Path::new(&root.name)
+                        .components()
+                        .collect::<Vec<_>>()
+                        .as_slice(),
+                    [std::path::Component::Normal(_)]
+                );
+            if !root.path.is_absolute() || !plain_name {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "manifest records an invalid source {:?} ({:?})",
+                        root.path, root.name
+                    ),
+                ));
+            }
+        }
+        Ok(roots)
+    }
+
+    /// The archive holding the data this one did not store again, if any.
+    fn next_link(&self) -> Option<(&'static str, ParentRef)> {
+        match (&self.parent, &self.base) {
+            (Some(parent), _) => Some(("parent", parent.clone())),
+            (None, Some(base)) => Some(("base", base.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+/// What a restore does when something already exists where an entry goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overwrite {
+    #[default]
+    Always,
+    Never,
+    IfNewer,
+    IfDifferent,
+    Rename,
+}
+
+impl Overwrite {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            "if-newer" => Ok(Self::IfNewer),
+            "if-different" => Ok(Self::IfDifferent),
+            "rename" => Ok(Self::Rename),
+            other => Err(format!(
+                "unknown overwrite policy {other:?} (expected always, never, if-newer, if-different or rename)"
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Always => "always",
+            Self::Never => "never",
+            Self::IfNewer => "if-newer",
+            Self::IfDifferent => "if-different",
+            Self::Rename => "rename",
+        }
+    }
+}
+
+/// Where an entry goes once the overwrite policy has been applied to what
+/// is already at its path.
+enum Placement {
+    /// nothing is in the way, or it is replaced
+    Replace(PathBuf),
+    /// restored next to what is there, under a new name
+    Renamed(PathBuf),
+    Skip,
+}
+
+/// Per-run settings shared by every entry of a restore.
+struct Target<'a> {
+    /// directories entries are restored below, by the top-level name of
+    /// their archive path; an unnamed root takes every entry
+    roots: Vec<SourceRoot>,
+    allow_unsafe_paths: bool,
+    restore_owner: bool,
+    overwrite: Overwrite,
+    meta: HashMap<&'a str, &'a FileMeta>,
+}
+
+impl Target<'_> {
+    /// Resolves where an entry is restored to and creates its parent
+    /// directories. With `replace` set, the entry's own path may currently be
+    /// a symlink; the caller then removes it instead of writing through it.
+    fn destination(&self, rel: &str, replace: bool) -> Result<PathBuf, String> {
+        self.resolve(rel, replace, true)
+    }
+
+    /// Like [`destination`](Target::destination), without creating
+    /// anything: where `rel` would be restored to, or why it would be
+    /// refused.
+    fn check(&self, rel: &str, replace: bool) -> Result<PathBuf, String> {
+        self.resolve(rel, replace, false)
+    }
+
+    fn resolve(&self, rel: &str, replace: bool, create: bool) -> Result<PathBuf, String> {
+        let (dir, rel) = self.locate(rel)?;
+        let Some(rel) = rel else {
+            // a named root itself, restored to its own location
+            return Ok(dir.to_path_buf());
+        };
+        if self.allow_unsafe_paths {
+            let dest_path = dir.join(rel);
+            if create && let Some(parent) = dest_path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("failed to create directory {:?}: {e}", parent))?;
+            }
+            return Ok(dest_path);
+        }
+        match (replace, create) {
+            (true, true) => safe_path::prepare_parent(dir, rel),
+            (false, true) => safe_path::prepare_destination(dir, rel),
+            (true, false) => safe_path::check_parent(dir, rel),
+            (false, false) => safe_path::check_destination(dir, rel),
+        }
+    }
+
+    /// The directory `rel` is restored below and the path relative to it,
+    /// `None` for a named root itself.
+    fn locate<'r>(&self, rel: &'r str) -> Result<(&Path, Option<&'r str>), String> {
+        for root in &self.roots {
+            if root.name.is_empty() {
+                return Ok((&root.path, Some(rel)));
+            }
+            if rel == root.name {
+                return Ok((&root.path, None));
+            }
+            if let Some(rest) = rel
+                .strip_prefix(root.name.as_str())
+                .and_then(|r| r.strip_prefix('/'))
+            {
+                return Ok((&root.path, Some(rest)));
+            }
+        }
+        Err("path is not below any source of the archive".to_string())
+    }
+
+    /// Applies the overwrite policy to whatever exists at `dest_path`, the
+    /// destination of `rel`. `same` tells whether it already is what the
+    /// entry would restore.
+    fn place<F>(&self, rel: &str, dest_path: &Path, same: F) -> Placement
+    where
+        F: FnOnce(&fs::Metadata) -> bool,
+    {
+        let Ok(md) = fs::symlink_metadata(dest_path) else {
+            return Placement::Replace(dest_path.to_path_buf());
+        };
+        let replace = match self.overwrite {
+            Overwrite::Always => true,
+            Overwrite::Never => false,
+            // without a recorded time the entry cannot be shown to be newer
+            Overwrite::IfNewer => self
+                .meta
+                .get(rel)
+                .is_some_and(|m| (m.mtime, m.mtime_nsec) > (md.mtime(), md.mtime_nsec() as u32)),
+            Overwrite::IfDifferent => !same(&md),
+            Overwrite::Rename => return Placement::Renamed(renamed_path(dest_path)),
+        };
+        if replace {
+            Placement::Replace(dest_path.to_path_buf())
+        } else {
+            Placement::Skip
+        }
+    }
+
+    fn apply_meta(&self, rel: &str, dest_path: &Path, is_symlink: bool) {
+        if let Some(meta) = self.meta.get(rel)
+            && let Err(e) = metadata::apply_to_path(dest_path, meta, is_symlink, self.restore_owner)
+        {
+            eprintln!("restore: failed to apply metadata to {:?}: {e}", dest_path);
+        }
+    }
+}
+
+enum Outcome {
+    Restored,
+    Renamed,
+    Skipped,
+    Mismatched,
+    Failed,
+}
+
+/// Why the data of a file could not be restored. Only a failed write stops
+/// the restore; data that cannot be read fails just its own entry when the
+/// next one can still be found.
+enum DataError {
+    Read(io::Error),
+    Write(io::Error),
+}
+
+impl From<DataError> for io::Error {
+    fn from(e: DataError) -> Self {
+        match e {
+            DataError::Read(e) | DataError::Write(e) => e,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Tally {
+    restored: usize,
+    renamed: usize,
+    skipped: usize,
+    mismatched: usize,
+    failed: usize,
+}
+
+impl Tally {
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Restored => self.restored += 1,
+            Outcome::Renamed => self.renamed += 1,
+            Outcome::Skipped => self.skipped += 1,
+            Outcome::Mismatched => self.mismatched += 1,
+            Outcome::Failed => self.failed += 1,
+        }
+    }
+}
+
+/// File data still to be restored: where it goes and, when the manifest
+/// records it, the hash the archive entry must have.
+struct PendingData {
+    rel: String,
+    size: u64,
+    hash: Option<[u8; 32]>,
+    /// repository blobs holding the data; empty when a single blob named
+    /// by `hash` holds it
+    chunks: Vec<[u8; 32]>,
+    /// where the data goes instead when what is at `rel` is kept
+    renamed: Option<PathBuf>,
+}
+
+/// Where file data is read from: an archive, possibly with a chain of
+/// parents, or the blobs of a repository snapshot.
+enum Source {
+    Archive(ArchiveReader),
+    Repository(Repository),
+}
+
+/// Decides which archive entries take part in a restore. Positional paths
+/// select an exact file or everything below a directory, include globs
+/// select further entries, and exclude globs always win.
+struct Selection {
+    paths: Vec<String>,
+    filter: PathFilter,
+    use_filter: bool,
+}
+
+impl Selection {
+    fn from_config(config: &RestoreConfig) -> Result<Self, String> {
+        let filter = PathFilter::from_patterns(&config.includes, &config.excludes)?;
+        let paths: Vec<String> = config
+            .paths
+            .iter()
+            .map(|p| {
+                let p = p.strip_prefix("./").unwrap_or(p);
+                p.trim_end_matches('/').to_string()
+            })
+            .collect();
+
+        Ok(Self {
+            use_filter: paths.is_empty() || !config.includes.is_empty(),
+            paths,
+            filter,
+        })
+    }
+
+    fn is_everything(&self, config: &RestoreConfig) -> bool {
+        self.paths.is_empty() && config.includes.is_empty() && config.excludes.is_empty()
+    }
+
+    fn matches_path(path: &str, rel: &str) -> bool {
+        rel == path
+            || rel
+                .strip_prefix(path)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    fn matches(&self, rel: &str) -> bool {
+        if self.paths.iter().any(|p| Self::matches_path(p, rel)) {
+            return !self.filter.is_excluded(Path::new(rel));
+        }
+        self.use_filter && self.filter.allow(Path::new(rel))
+    }
+}
+
 fn print_section(title: &str) {
     println!();
     println!("--- {title} ---");
@@ -25,165 +366,1332 @@ fn print_kv<K: AsRef<str>, V: AsRef<str>>(k: K, v: V) {
     println!("  {:12} {}", format!("{}:", k.as_ref()), v.as_ref());
 }
 
-pub fn restore_backup(backup_file: &Path, restore_dir: &Path) -> io::Result<()> {
-    println!("==================== backup restore ====================");
-    print_kv("archive", &backup_file.to_string_lossy());
-    print_kv("target", &restore_dir.to_string_lossy());
-
-    let file = File::open(backup_file)?;
-    let mut reader = BufReader::new(file);
+pub fn restore_backup(config: &RestoreConfig) -> io::Result<()> {
+    let backup_file = config.archive.as_path();
+    // a JSON report is the only thing written to stdout
+    let text = !config.json;
 
-    let mut magic = [0u8; 4];
-    reader.read_exact(&mut magic)?;
-    if &magic != b"BKUP" {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid magic, not a .backup file",
-        ));
-    }
-
-    let mut ver_bytes = [0u8; 4];
-    reader.read_exact(&mut ver_bytes)?;
-    let version = u32::from_le_bytes(ver_bytes);
-    if version != 1 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("unsupported backup version: {version}"),
-        ));
+    if text {
+        println!("==================== backup restore ====================");
+        print_kv("archive", backup_file.to_string_lossy());
+        match &config.destination {
+            Some(dir) => print_kv("target", dir.to_string_lossy()),
+            None => print_kv("target", "original locations"),
+        }
+        if config.dry_run {
+            print_kv("mode", "dry-run");
+        }
+        if config.allow_unsafe_paths {
+            print_kv("paths", "unsafe paths allowed");
+        }
+        if config.skip_identical {
+            print_kv("existing", "identical files skipped");
+        }
+        print_kv("overwrite", config.overwrite.name());
     }
 
-    let mut len_bytes = [0u8; 8];
-    reader.read_exact(&mut len_bytes)?;
-    let manifest_len = u64::from_le_bytes(len_bytes) as usize;
-
-    let mut manifest_bytes = vec![0u8; manifest_len];
-    reader.read_exact(&mut manifest_bytes)?;
+    let selection = Selection::from_config(config)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
 
+    let (mut source, manifest_bytes) = if repository::is_repository(backup_file) {
+        let repo = Repository::open(backup_file)?;
+        let (name, manifest_bytes) =
+            repo.load_snapshot(config.snapshot.as_deref().unwrap_or("latest"))?;
+        if text {
+            print_kv("snapshot", name);
+        }
+        (Source::Repository(repo), manifest_bytes)
+    } else {
+        if config.snapshot.is_some() {
+            eprintln!(
+                "restore: --snapshot ignored, {:?} is not a repository",
+                backup_file
+            );
+        }
+        let archive = ArchiveReader::open(backup_file, &config.credentials)?;
+        let manifest_bytes = archive.manifest_bytes.clone();
+        (Source::Archive(archive), manifest_bytes)
+    };
     let manifest: BackupManifest = serde_json::from_slice(&manifest_bytes).map_err(|e| {
         io::Error::new(
             io::ErrorKind::InvalidData,
-            format!("failed to parse embedded manifest: {e}"),
+            format!("failed to parse manifest: {e}"),
         )
     })?;
 
-    let total_bytes: u64 = manifest.files.iter().map(|f| f.size).sum();
+    let selected: Vec<&ManifestFile> = manifest
+        .files
+        .iter()
+        .filter(|f| selection.matches(&f.path))
+        .collect();
+
+    // Hard links whose first path is not selected still need that path's
+    // data; it is restored to the first selected link instead.
+    let mut detached: HashMap<&str, Vec<&str>> = HashMap::new();
+    for f in selected.iter().filter(|f| f.kind == EntryKind::HardLink) {
+        if let Some(first) = f.target.as_deref()
+            && !selection.matches(first)
+        {
+            detached.entry(first).or_default().push(f.path.as_str());
+        }
+    }
+
+    // sizes come from the manifest and are only checked against the data
+    // once it is read
+    let total_bytes: u64 = selected
+        .iter()
+        .copied()
+        .chain(
+            manifest
+                .files
+                .iter()
+                .filter(|f| detached.contains_key(f.path.as_str())),
+        )
+        .fold(0, |total, f| total.saturating_add(f.size));
     let pb = ProgressBar::new(total_bytes);
 
-    print_section("manifest");
-    print_kv("source", &manifest.source);
-    print_kv("files", manifest.files.len().to_string());
-    print_kv("bytes", total_bytes.to_string());
+    if text {
+        print_section("manifest");
+        print_kv("source", &manifest.source);
+        print_kv("files", manifest.files.len().to_string());
+        if !selection.is_everything(config) {
+            print_kv("selected", selected.len().to_string());
+        }
+        print_kv("bytes", total_bytes.to_string());
+    }
 
-    if !restore_dir.exists() {
-        fs::create_dir_all(restore_dir)?;
-    } else if !restore_dir.is_dir() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "restore destination must be a directory",
-        ));
+    for p in &selection.paths {
+        if !manifest
+            .files
+            .iter()
+            .any(|f| Selection::matches_path(p, &f.path))
+        {
+            eprintln!("restore: no entry in archive matches {p:?}");
+        }
+    }
+
+    let roots = match &config.destination {
+        Some(dir) => vec![SourceRoot {
+            name: String::new(),
+            path: dir.clone(),
+        }],
+        None => manifest.original_roots()?,
+    };
+    let mut missing = Vec::new();
+    for root in &roots {
+        if text && config.destination.is_none() {
+            print_kv("original", root.path.to_string_lossy());
+        }
+        if !root.path.exists() {
+            // a missing original location usually means the wrong host or
+            // a mount that is not there
+            if config.destination.is_none() && !config.create_root {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "original location {:?} does not exist (use --create-root to create it)",
+                        root.path
+                    ),
+                ));
+            }
+            missing.push(root.path.clone());
+        } else if !root.path.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("restore destination {:?} must be a directory", root.path),
+            ));
+        }
+    }
+
+    let restore_owner = !config.no_owner && metadata::running_as_root();
+    let target = Target {
+        roots,
+        allow_unsafe_paths: config.allow_unsafe_paths,
+        restore_owner,
+        overwrite: config.overwrite,
+        meta: selected
+            .iter()
+            .filter_map(|f| Some((f.path.as_str(), f.meta.as_ref()?)))
+            .collect(),
+    };
+
+    if config.dry_run {
+        return print_plan(&selected, &target, config);
+    }
+    if config.destination.is_none() {
+        print_preview(&selected, &target, config.skip_identical);
+        if !config.yes && !confirm("restore to the original locations?")? {
+            println!();
+            println!("restore cancelled");
+            return Ok(());
+        }
+    }
+    for path in &missing {
+        fs::create_dir_all(path)?;
     }
 
     print_section("restore");
-    let mut restored = 0usize;
-    let mut mismatched = 0usize;
-    let mut failed = 0usize;
+    print_kv(
+        "owner",
+        if restore_owner {
+            "restored"
+        } else {
+            "not restored"
+        },
+    );
+    let mut tally = Tally::default();
 
-    loop {
-        let mut len_buf = [0u8; 2];
-        match reader.read_exact(&mut len_buf) {
-            Ok(()) => {}
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-            Err(e) => return Err(e),
+    let data_destination = |path: &str| -> Option<String> {
+        if selection.matches(path) {
+            Some(path.to_string())
+        } else {
+            detached.get(path).map(|links| links[0].to_string())
         }
+    };
 
-        let path_len = u16::from_le_bytes(len_buf) as usize;
-        let mut path_bytes = vec![0u8; path_len];
-        reader.read_exact(&mut path_bytes)?;
+    let mut pending: HashMap<&str, PendingData> = manifest
+        .files
+        .iter()
+        .filter(|f| f.kind == EntryKind::File)
+        .filter_map(|f| {
+            let rel = data_destination(&f.path)?;
+            let hash = f.sha256.as_deref().and_then(incremental::hash_from_hex);
+            let chunks = f
+                .chunks
+                .iter()
+                .map(|c| incremental::hash_from_hex(c))
+                .collect::<Option<Vec<_>>>()
+                .unwrap_or_default();
+            Some((
+                f.path.as_str(),
+                PendingData {
+                    rel,
+                    size: f.size,
+                    hash,
+                    chunks,
+                    renamed: None,
+                },
+            ))
+        })
+        .collect();
 
-        let rel_path = match String::from_utf8(path_bytes) {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("restore: invalid UTF-8 path in archive: {e}");
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "invalid UTF-8 path in archive",
-                ));
+    // What already exists at the destinations is looked at before any data
+    // is read, so files that are kept need no archive of the chain.
+    pending.retain(
+        |_, wanted| match check_existing(wanted, &target, config.skip_identical) {
+            Some(outcome) => {
+                pb.inc(wanted.size);
+                tally.add(outcome);
+                false
             }
+            None => true,
+        },
+    );
+    // Hard links to a file restored under a new name link to that name.
+    let anchors: HashMap<String, PathBuf> = pending
+        .values()
+        .filter_map(|p| Some((p.rel.clone(), p.renamed.clone()?)))
+        .collect();
+
+    match &mut source {
+        Source::Archive(archive) => {
+            restore_from_archive(archive, &mut pending, &target, &pb, &mut tally)?;
+
+            // Data an incremental or differential backup did not store again
+            // is found by walking its parents, newest first, down to a full
+            // backup.
+            if config.base.is_some() && manifest.next_link().is_none() {
+                eprintln!("restore: --base ignored, the archive is a full backup");
+            }
+            let mut child = backup_file.to_path_buf();
+            let mut link = manifest.next_link();
+            while !pending.is_empty() {
+                let Some((role, reference)) = link else {
+                    break;
+                };
+                let path = match (role, &config.base) {
+                    ("base", Some(base)) => base.clone(),
+                    _ => incremental::sibling_path(&child, &reference)?,
+                };
+                let mut linked =
+                    incremental::open_reference(&path, role, &reference, &config.credentials)?;
+                print_kv(role, path.to_string_lossy());
+                let linked_manifest: BackupManifest = linked.manifest()?;
+                restore_from_archive(&mut linked, &mut pending, &target, &pb, &mut tally)?;
+                link = linked_manifest.next_link();
+                child = path;
+            }
+        }
+        Source::Repository(repo) => {
+            restore_from_repository(repo, &mut pending, &target, &pb, &mut tally)?;
+        }
+    }
+
+    for path in pending.keys() {
+        eprintln!("restore: no archive in the backup chain holds the data of {path:?}");
+        tally.add(Outcome::Failed);
+    }
+
+    for f in selected.iter().filter(|f| f.kind == EntryKind::HardLink) {
+        let Some(first) = f.target.as_deref() else {
+            eprintln!("restore: hard link {:?} has no target", f.path);
+            tally.add(Outcome::Failed);
+            continue;
         };
+        let anchor = data_destination(first).unwrap_or_else(|| first.to_string());
+        if anchor != f.path {
+            tally.add(restore_hard_link(&f.path, &anchor, &anchors, &target));
+        }
+    }
+
+    for f in selected.iter().filter(|f| {
+        !matches!(
+            f.kind,
+            EntryKind::File | EntryKind::Dir | EntryKind::HardLink
+        )
+    }) {
+        tally.add(restore_node(f, &target));
+    }
 
-        let mut size_buf = [0u8; 8];
-        reader.read_exact(&mut size_buf)?;
-        let size = u64::from_le_bytes(size_buf);
+    // Directories go last and deepest first, so restoring their contents
+    // does not disturb the recorded modification times or trip over
+    // read-only permissions.
+    let mut dirs: Vec<&ManifestFile> = selected
+        .iter()
+        .copied()
+        .filter(|f| f.kind == EntryKind::Dir)
+        .collect();
+    dirs.sort_by_key(|f| std::cmp::Reverse(Path::new(&f.path).components().count()));
+    for d in dirs {
+        tally.add(restore_directory(d, &target));
+    }
+
+    pb.finish_with_message("restore complete");
 
-        let mut expected_hash = [0u8; 32];
-        reader.read_exact(&mut expected_hash)?;
+    print_section("summary");
+    print_kv("restored", tally.restored.to_string());
+    if config.overwrite == Overwrite::Rename {
+        print_kv("renamed", tally.renamed.to_string());
+    }
+    if config.skip_identical || config.overwrite != Overwrite::Always {
+        print_kv("skipped", tally.skipped.to_string());
+    }
+    print_kv("mismatched", tally.mismatched.to_string());
+    print_kv("failed", tally.failed.to_string());
 
-        let dest_path = restore_dir.join(&rel_path);
+    if tally.failed > 0 || tally.mismatched > 0 {
+        return Err(io::Error::other(format!(
+            "{} entries failed and {} did not match their hash",
+            tally.failed, tally.mismatched
+        )));
+    }
+    Ok(())
+}
+
+/// What restoring would do at the destination of an entry.
+enum Action {
+    Create,
+    Overwrite,
+    Rename,
+    Keep,
+    /// the path would be refused, like an unsafe path on restore
+    Refuse,
+}
 
-        if let Some(parent) = dest_path.parent() {
-            if let Err(e) = fs::create_dir_all(parent) {
-                eprintln!("restore: failed to create directory {:?}: {e}", parent);
-                if size > 0 {
-                    let _ = reader.seek(SeekFrom::Current(size as i64));
-                    pb.inc(size);
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Create => "create",
+            Self::Overwrite => "overwrite",
+            Self::Rename => "rename",
+            Self::Keep => "keep",
+            Self::Refuse => "refused",
+        }
+    }
+}
+
+/// What restoring an entry would do, and what was found at its
+/// destination to decide that.
+struct Plan {
+    action: Action,
+    dest_path: Option<PathBuf>,
+    existing: Option<fs::Metadata>,
+    /// whether what exists already is what the entry restores, if that
+    /// had to be checked
+    identical: Option<bool>,
+    /// the name the entry would be restored under instead
+    renamed: Option<PathBuf>,
+    /// why the entry would be refused
+    refused: Option<String>,
+}
+
+/// Finds what restoring `f` would do to what is at its destination,
+/// without creating or changing anything. The destination is checked like
+/// on restore, so nothing outside the target is looked at for an entry
+/// that would be refused.
+fn plan_entry(f: &ManifestFile, target: &Target, skip_identical: bool) -> Plan {
+    let mut plan = Plan {
+        action: Action::Create,
+        dest_path: None,
+        existing: None,
+        identical: None,
+        renamed: None,
+        refused: None,
+    };
+    // files and directories are never restored over a symlink, the other
+    // kinds replace one
+    let replace = !matches!(f.kind, EntryKind::File | EntryKind::Dir);
+    let anchor = match (f.kind, f.target.as_deref()) {
+        (EntryKind::HardLink, Some(first)) => target.check(first, false).map(drop),
+        (EntryKind::HardLink, None) => Err("hard link has no target".to_string()),
+        _ => Ok(()),
+    };
+    let dest_path = match anchor.and_then(|()| target.check(&f.path, replace)) {
+        Ok(path) => path,
+        Err(e) => {
+            plan.action = Action::Refuse;
+            plan.refused = Some(e);
+            return plan;
+        }
+    };
+    plan.dest_path = Some(dest_path.clone());
+    let dest_path = dest_path.as_path();
+    let Ok(md) = fs::symlink_metadata(dest_path) else {
+        return plan;
+    };
+    let identical = OnceCell::new();
+    let same = |md: &fs::Metadata| *identical.get_or_init(|| entry_matches(f, dest_path, md));
+
+    // directories are merged into, and with `--resume` identical files
+    // are skipped whatever the policy
+    let kept = (md.is_dir() && f.kind == EntryKind::Dir)
+        || (skip_identical && f.kind == EntryKind::File && same(&md));
+    plan.action = if kept {
+        Action::Keep
+    } else {
+        match target.place(&f.path, dest_path, same) {
+            Placement::Replace(_) => Action::Overwrite,
+            Placement::Renamed(path) => {
+                plan.renamed = Some(path);
+                Action::Rename
+            }
+            Placement::Skip => Action::Keep,
+        }
+    };
+    plan.identical = identical.get().copied();
+    plan.existing = Some(md);
+    plan
+}
+
+/// Whether what is at `path`, with metadata `md`, already is what `f`
+/// restores. Hard links are never taken to match, since their first path
+/// is not restored yet.
+fn entry_matches(f: &ManifestFile, path: &Path, md: &fs::Metadata) -> bool {
+    let ft = md.file_type();
+    match f.kind {
+        EntryKind::File => {
+            ft.is_file()
+                && md.len() == f.size
+                && f.sha256
+                    .as_deref()
+                    .and_then(incremental::hash_from_hex)
+                    .is_some_and(|h| hasher::hash_file(path) == Some(h))
+        }
+        EntryKind::Dir => ft.is_dir(),
+        EntryKind::Symlink => {
+            ft.is_symlink()
+                && fs::read_link(path)
+                    .is_ok_and(|t| f.target.as_deref().is_some_and(|want| t == Path::new(want)))
+        }
+        EntryKind::Fifo => ft.is_fifo(),
+        EntryKind::CharDevice => ft.is_char_device() && md.rdev() == f.rdev.unwrap_or(0),
+        EntryKind::BlockDevice => ft.is_block_device() && md.rdev() == f.rdev.unwrap_or(0),
+        EntryKind::HardLink => false,
+    }
+}
+
+/// Shows how many of the selected entries would replace something at the
+/// original locations, and how many would be refused.
+fn print_preview(selected: &[&ManifestFile], target: &Target, skip_identical: bool) {
+    let (mut created, mut overwritten, mut renamed, mut kept, mut refused) = (0, 0, 0, 0, 0);
+    for f in selected.iter().filter(|f| f.kind != EntryKind::Dir) {
+        match plan_entry(f, target, skip_identical).action {
+            Action::Create => created += 1,
+            Action::Overwrite => overwritten += 1,
+            Action::Rename => renamed += 1,
+            Action::Keep => kept += 1,
+            Action::Refuse => refused += 1,
+        }
+    }
+
+    print_section("preview");
+    print_kv("new", created.to_string());
+    print_kv("overwritten", overwritten.to_string());
+    if renamed > 0 {
+        print_kv("renamed", renamed.to_string());
+    }
+    if kept > 0 {
+        print_kv("kept", kept.to_string());
+    }
+    if refused > 0 {
+        print_kv("refused", refused.to_string());
+    }
+}
+
+/// One line of a dry-run report.
+#[derive(Serialize)]
+struct PlannedEntry<'a> {
+    path: &'a str,
+    kind: EntryKind,
+    action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<String>,
+    /// size of the file in the archive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// size of the file it would overwrite or leaves alone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    existing_size: Option<u64>,
+    /// whether that file has the entry's SHA-256
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identical: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    renamed_to: Option<String>,
+    /// why the entry would be refused
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl PlannedEntry<'_> {
+    /// The text report's note on the line of the entry.
+    fn note(&self) -> String {
+        match (self.existing_size, self.size, self.identical) {
+            _ if self.action == "create" => String::new(),
+            _ if self.action == "refused" => {
+                format!("  ({})", self.reason.as_deref().unwrap_or_default())
+            }
+            _ if self.action == "rename" => {
+                format!("  (as {})", self.renamed_to.as_deref().unwrap_or_default())
+            }
+            (Some(old), Some(new), _) if old != new => format!("  (size {old} -> {new})"),
+            (Some(_), Some(_), Some(true)) => "  (identical)".to_string(),
+            (Some(_), Some(_), Some(false)) => "  (same size, content differs)".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PlanReport<'a> {
+    archive: String,
+    entries: Vec<PlannedEntry<'a>>,
+    create: usize,
+    overwrite: usize,
+    rename: usize,
+    keep: usize,
+    refused: usize,
+}
+
+/// Finds for every selected entry whether restoring would create it,
+/// overwrite what is there, restore it under a new name, leave what is
+/// there alone or refuse its path, without writing anything.
+fn plan_report<'a>(
+    selected: &[&'a ManifestFile],
+    target: &Target,
+    archive: &Path,
+    skip_identical: bool,
+) -> PlanReport<'a> {
+    let mut report = PlanReport {
+        archive: archive.to_string_lossy().to_string(),
+        entries: Vec::new(),
+        create: 0,
+        overwrite: 0,
+        rename: 0,
+        keep: 0,
+        refused: 0,
+    };
+    for f in selected {
+        let plan = plan_entry(f, target, skip_identical);
+        let existing_file = plan.existing.as_ref().filter(|md| md.is_file());
+        let identical = match (&plan.action, existing_file) {
+            // an overwritten file is compared so the report can tell what
+            // changes, unless its size already does
+            (Action::Overwrite, Some(md)) if f.kind == EntryKind::File => {
+                plan.identical.or_else(|| {
+                    (md.len() == f.size)
+                        .then(|| plan.dest_path.as_ref().map(|p| entry_matches(f, p, md)))
+                        .flatten()
+                })
+            }
+            _ => plan.identical.filter(|_| f.kind == EntryKind::File),
+        };
+        match plan.action {
+            Action::Create => report.create += 1,
+            Action::Overwrite => report.overwrite += 1,
+            Action::Rename => report.rename += 1,
+            Action::Keep => report.keep += 1,
+            Action::Refuse => report.refused += 1,
+        }
+        let is_file = f.kind == EntryKind::File;
+        report.entries.push(PlannedEntry {
+            path: &f.path,
+            kind: f.kind,
+            action: plan.action.name(),
+            destination: plan.dest_path.map(|p| p.to_string_lossy().to_string()),
+            size: is_file.then_some(f.size),
+            existing_size: existing_file.filter(|_| is_file).map(|md| md.len()),
+            identical,
+            renamed_to: plan.renamed.map(|p| p.to_string_lossy().to_string()),
+            reason: plan.refused,
+        });
+    }
+    report
+}
+
+/// Prints the dry-run report of the selected entries, as text or JSON.
+fn print_plan(
+    selected: &[&ManifestFile],
+    target: &Target,
+    config: &RestoreConfig,
+) -> io::Result<()> {
+    let report = plan_report(selected, target, &config.archive, config.skip_identical);
+    if config.json {
+        let json = serde_json::to_string_pretty(&report).map_err(io::Error::other)?;
+        println!("{json}");
+        return Ok(());
+    }
+
+    print_section("plan");
+    for entry in &report.entries {
+        println!("  {:9} {}{}", entry.action, entry.path, entry.note());
+    }
+    if report.entries.is_empty() {
+        println!("  nothing selected");
+    }
+
+    print_section("summary");
+    print_kv("create", report.create.to_string());
+    print_kv("overwrite", report.overwrite.to_string());
+    if config.overwrite == Overwrite::Rename {
+        print_kv("rename", report.rename.to_string());
+    }
+    print_kv("keep", report.keep.to_string());
+    if report.refused > 0 {
+        print_kv("refused", report.refused.to_string());
+    }
+    println!("dry-run: nothing restored.");
+    Ok(())
+}
+
+/// Asks a yes/no question on the terminal. Without a terminal to ask on
+/// the answer cannot be given, so that is an error.
+fn confirm(question: &str) -> io::Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "confirmation needed but standard input is not a terminal (use --yes)",
+        ));
+    }
+    println!();
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes"))
+}
+
+/// Looks at the file `wanted` is restored to before its data is read. With
+/// `skip_identical`, a file that already holds the data only gets its
+/// metadata reapplied. Otherwise the overwrite policy decides whether the
+/// file is kept, and where the data goes instead of replacing it. Returns
+/// `None` when the data still has to be written.
+fn check_existing(
+    wanted: &mut PendingData,
+    target: &Target,
+    skip_identical: bool,
+) -> Option<Outcome> {
+    // a destination that cannot be used is reported when it is written
+    let dest_path = target.destination(&wanted.rel, false).ok()?;
+    let same = |md: &fs::Metadata| {
+        md.is_file()
+            && md.len() == wanted.size
+            && wanted
+                .hash
+                .is_some_and(|h| hasher::hash_file(&dest_path) == Some(h))
+    };
+    if skip_identical && fs::symlink_metadata(&dest_path).is_ok_and(|md| same(&md)) {
+        target.apply_meta(&wanted.rel, &dest_path, false);
+        return Some(Outcome::Skipped);
+    }
+    match target.place(&wanted.rel, &dest_path, same) {
+        Placement::Replace(_) => None,
+        Placement::Renamed(path) => {
+            wanted.renamed = Some(path);
+            None
+        }
+        Placement::Skip => Some(Outcome::Skipped),
+    }
+}
+
+/// A free name next to `path` for restoring an entry without replacing
+/// what is there: `<name>.restored`, then `<name>.restored.1` and so on.
+fn renamed_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".restored");
+    let mut candidate = path.with_file_name(&name);
+    let mut n = 1;
+    while fs::symlink_metadata(&candidate).is_ok() {
+        let mut numbered = name.clone();
+        numbered.push(format!(".{n}"));
+        candidate = path.with_file_name(numbered);
+        n += 1;
+    }
+    candidate
+}
+
+/// Restores every data entry of `archive` that is still pending, removing it
+/// from `pending`. Entries whose hash differs from the expected one belong to
+/// another version of the file and are left for an older archive.
+fn restore_from_archive(
+    archive: &mut ArchiveReader,
+    pending: &mut HashMap<&str, PendingData>,
+    target: &Target,
+    pb: &ProgressBar,
+    tally: &mut Tally,
+) -> io::Result<()> {
+    let mut take = |path: &str, hash: &[u8; 32]| -> Option<PendingData> {
+        let wanted = pending.get(path)?;
+        if wanted.hash.is_some_and(|h| h != *hash) {
+            return None;
+        }
+        pending.remove(path)
+    };
+
+    if let Some(index) = archive.index.clone() {
+        for ie in &index {
+            let Some(wanted) = take(&ie.path, &ie.hash) else {
+                continue;
+            };
+            // the index still leads to the other entries
+            let entry = match archive.seek_entry(ie) {
+                Ok(entry) => entry,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    eprintln!("restore: skipping {:?}: {e}", ie.path);
+                    pb.inc(wanted.size);
+                    tally.add(Outcome::Failed);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            match restore_entry(archive, &entry, &wanted, target, pb) {
+                Ok(outcome) => tally.add(outcome),
+                Err(DataError::Read(e)) => {
+                    eprintln!("restore: failed to read {:?}: {e}", ie.path);
+                    tally.add(Outcome::Failed);
                 }
-                failed += 1;
+                Err(e) => return Err(e.into()),
+            }
+        }
+    } else {
+        while let Some(entry) = archive.next_entry()? {
+            let Some(wanted) = take(&entry.path, &entry.hash) else {
+                archive.skip_data(&entry)?;
                 continue;
+            };
+            tally.add(restore_entry(archive, &entry, &wanted, target, pb)?);
+        }
+    }
+    Ok(())
+}
+
+/// Restores every pending file from the repository, joining its chunks.
+fn restore_from_repository(
+    repo: &Repository,
+    pending: &mut HashMap<&str, PendingData>,
+    target: &Target,
+    pb: &ProgressBar,
+    tally: &mut Tally,
+) -> io::Result<()> {
+    let mut files: Vec<(&str, PendingData)> = pending.drain().collect();
+    files.sort_by(|a, b| a.0.cmp(b.0));
+
+    for (path, wanted) in files {
+        let blob = wanted.hash.and_then(|hash| {
+            // an empty file has no chunks and no blob to read
+            let ids = if wanted.size == 0 {
+                Vec::new()
+            } else if wanted.chunks.is_empty() {
+                vec![hash]
+            } else {
+                wanted.chunks.clone()
+            };
+            repo.read_chunks(&ids).map(|data| (hash, data))
+        });
+        let Some((hash, mut data)) = blob else {
+            eprintln!("restore: repository holds no data for {path:?}");
+            pb.inc(wanted.size);
+            tally.add(Outcome::Failed);
+            continue;
+        };
+        let Some((dest_path, out)) = open_destination(&wanted, target) else {
+            pb.inc(wanted.size);
+            tally.add(Outcome::Failed);
+            continue;
+        };
+        match write_data(&mut data, out, &dest_path, &hash, &wanted, target, pb) {
+            Ok(outcome) => tally.add(outcome),
+            Err(DataError::Read(e)) => {
+                eprintln!("restore: failed to read {path:?} from the repository: {e}");
+                tally.add(Outcome::Failed);
             }
+            Err(e) => return Err(e.into()),
         }
+    }
+    Ok(())
+}
 
-        let mut out = match File::create(&dest_path) {
-            Ok(f) => BufWriter::new(f),
+/// Writes the data of `entry` to `wanted.rel` below the target directory, or
+/// next to it when it was renamed. This is the entry's own path except when
+/// the data is only restored for a hard link. Unless unsafe paths are
+/// allowed, paths that would land outside the target directory are refused
+/// and counted as failed. The archive reader must be positioned at the start
+/// of the entry's data; on return it is positioned at the end of it, unless
+/// the data could not be read.
+fn restore_entry(
+    archive: &mut ArchiveReader,
+    entry: &EntryHeader,
+    wanted: &PendingData,
+    target: &Target,
+    pb: &ProgressBar,
+) -> Result<Outcome, DataError> {
+    let Some((dest_path, out)) = open_destination(wanted, target) else {
+        archive.skip_data(entry).map_err(DataError::Read)?;
+        pb.inc(entry.size);
+        return Ok(Outcome::Failed);
+    };
+
+    let mut data = archive.entry_data(entry);
+    write_data(&mut data, out, &dest_path, &entry.hash, wanted, target, pb)
+}
+
+/// Creates the file `wanted` is restored to, reporting why if it cannot be.
+fn open_destination(wanted: &PendingData, target: &Target) -> Option<(PathBuf, BufWriter<File>)> {
+    let dest_path = match &wanted.renamed {
+        Some(path) => path.clone(),
+        None => match target.destination(&wanted.rel, false) {
+            Ok(p) => p,
             Err(e) => {
-                eprintln!("restore: failed to create file {:?}: {e}", dest_path);
-                if size > 0 {
-                    let _ = reader.seek(SeekFrom::Current(size as i64));
-                    pb.inc(size);
+                eprintln!("restore: skipping {:?}: {e}", wanted.rel);
+                return None;
+            }
+        },
+    };
+
+    // a new name must not have been taken since it was picked
+    let created = match wanted.renamed {
+        Some(_) => File::create_new(&dest_path),
+        None => File::create(&dest_path),
+    };
+    match created {
+        Ok(f) => Some((dest_path, BufWriter::new(f))),
+        Err(e) => {
+            eprintln!("restore: failed to create file {:?}: {e}", dest_path);
+            None
+        }
+    }
+}
+
+/// Copies `data` into `out`, applies the recorded metadata and checks the
+/// copied data against `hash`.
+fn write_data<R: Read>(
+    data: &mut R,
+    mut out: BufWriter<File>,
+    dest_path: &Path,
+    hash: &[u8; 32],
+    wanted: &PendingData,
+    target: &Target,
+    pb: &ProgressBar,
+) -> Result<Outcome, DataError> {
+    let mut buf = [0u8; 8192];
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    let mut written = 0u64;
+
+    loop {
+        let n = match data.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) => {
+                // no partial file is left behind for data that cannot be read
+                drop(out);
+                if let Err(e) = fs::remove_file(dest_path) {
+                    eprintln!("restore: failed to remove {:?}: {e}", dest_path);
                 }
-                failed += 1;
-                continue;
+                pb.inc(wanted.size.saturating_sub(written));
+                return Err(DataError::Read(e));
             }
         };
+        if n == 0 {
+            break;
+        }
+        out.write_all(&buf[..n]).map_err(DataError::Write)?;
+        ctx.update(&buf[..n]);
+        pb.inc(n as u64);
+        written += n as u64;
+    }
 
-        let mut remaining = size;
-        let mut buf = [0u8; 8192];
-        let mut ctx = digest::Context::new(&digest::SHA256);
+    out.flush().map_err(DataError::Write)?;
 
-        while remaining > 0 {
-            let read_len = std::cmp::min(remaining, buf.len() as u64) as usize;
-            let n = reader.read(&mut buf[..read_len])?;
-            if n == 0 {
-                return Err(io::Error::new(
-                    io::ErrorKind::UnexpectedEof,
-                    "truncated file data in archive",
-                ));
+    if let Some(meta) = target.meta.get(wanted.rel.as_str())
+        && let Err(e) = metadata::apply(dest_path, out.get_ref(), meta, target.restore_owner)
+    {
+        eprintln!("restore: failed to apply metadata to {:?}: {e}", dest_path);
+    }
+
+    let calc = ctx.finish();
+    if calc.as_ref() != hash {
+        eprintln!(
+            "restore: hash mismatch for {:?} (restored, but contents differ from backup)",
+            dest_path
+        );
+        Ok(Outcome::Mismatched)
+    } else if wanted.renamed.is_some() {
+        Ok(Outcome::Renamed)
+    } else {
+        Ok(Outcome::Restored)
+    }
+}
+
+/// Applies the overwrite policy to the path of a link or node, clearing it
+/// when it is replaced. Returns where to create the entry and how that is
+/// counted, or the outcome when nothing is to be created.
+fn place_node<F>(
+    rel: &str,
+    dest_path: &Path,
+    same: F,
+    target: &Target,
+) -> Result<(PathBuf, Outcome), Outcome>
+where
+    F: FnOnce(&fs::Metadata) -> bool,
+{
+    match target.place(rel, dest_path, same) {
+        Placement::Replace(path) => match clear_destination(&path) {
+            Ok(()) => Ok((path, Outcome::Restored)),
+            Err(e) => {
+                eprintln!("restore: {e}");
+                Err(Outcome::Failed)
             }
-            out.write_all(&buf[..n])?;
-            ctx.update(&buf[..n]);
-            remaining -= n as u64;
-            pb.inc(n as u64);
+        },
+        Placement::Renamed(path) => Ok((path, Outcome::Renamed)),
+        Placement::Skip => Err(Outcome::Skipped),
+    }
+}
+
+/// Removes whatever non-directory is at `path`, so a link or node can be
+/// created in its place.
+fn clear_destination(path: &Path) -> Result<(), String> {
+    if let Ok(md) = fs::symlink_metadata(path) {
+        if md.is_dir() {
+            return Err(format!("{:?} exists and is a directory", path));
+        }
+        fs::remove_file(path).map_err(|e| format!("failed to replace {:?}: {e}", path))?;
+    }
+    Ok(())
+}
+
+/// Links `rel` to the file restored for `anchor`, at the new name in
+/// `anchors` when that file was renamed.
+fn restore_hard_link(
+    rel: &str,
+    anchor: &str,
+    anchors: &HashMap<String, PathBuf>,
+    target: &Target,
+) -> Outcome {
+    let anchor_path = match anchors.get(anchor) {
+        Some(path) => Ok(path.clone()),
+        None => target.destination(anchor, false),
+    };
+    let paths = anchor_path.and_then(|a| Ok((a, target.destination(rel, true)?)));
+    let (anchor_path, dest_path) = match paths {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("restore: skipping {:?}: {e}", rel);
+            return Outcome::Failed;
         }
+    };
 
-        out.flush()?;
+    let same = |md: &fs::Metadata| {
+        fs::symlink_metadata(&anchor_path).is_ok_and(|a| a.dev() == md.dev() && a.ino() == md.ino())
+    };
+    let (dest_path, outcome) = match place_node(rel, &dest_path, same, target) {
+        Ok(placed) => placed,
+        Err(outcome) => return outcome,
+    };
 
-        let calc = ctx.finish();
-        if calc.as_ref() != expected_hash {
+    match fs::hard_link(&anchor_path, &dest_path) {
+        Ok(()) => outcome,
+        Err(e) => {
             eprintln!(
-                "restore: hash mismatch for {:?} (restored, but contents differ from backup)",
-                dest_path
+                "restore: failed to link {:?} to {:?}: {e}",
+                dest_path, anchor_path
             );
-            mismatched += 1;
-        } else {
-            restored += 1;
+            Outcome::Failed
         }
     }
+}
 
-    pb.finish_with_message("restore complete");
+/// Recreates a symlink, FIFO or device node, replacing any non-directory
+/// already at its path.
+fn restore_node(f: &ManifestFile, target: &Target) -> Outcome {
+    let dest_path = match target.destination(&f.path, true) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("restore: skipping {:?}: {e}", f.path);
+            return Outcome::Failed;
+        }
+    };
 
-    print_section("summary");
-    print_kv("restored", restored.to_string());
-    print_kv("mismatched", mismatched.to_string());
-    print_kv("failed", failed.to_string());
+    let same = |md: &fs::Metadata| entry_matches(f, &dest_path, md);
+    let (dest_path, outcome) = match place_node(&f.path, &dest_path, same, target) {
+        Ok(placed) => placed,
+        Err(outcome) => return outcome,
+    };
+
+    let created = match f.kind {
+        EntryKind::Symlink => match &f.target {
+            Some(link_target) => std::os::unix::fs::symlink(link_target, &dest_path),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "symlink entry without target",
+            )),
+        },
+        kind => {
+            let mode = f.meta.as_ref().map_or(0o644, |m| m.mode);
+            make_node(&dest_path, kind, mode, f.rdev.unwrap_or(0))
+        }
+    };
+
+    if let Err(e) = created {
+        eprintln!("restore: failed to create {:?}: {e}", dest_path);
+        return Outcome::Failed;
+    }
+
+    target.apply_meta(&f.path, &dest_path, f.kind == EntryKind::Symlink);
+    outcome
+}
 
+fn make_node(path: &Path, kind: EntryKind, mode: u32, rdev: u64) -> io::Result<()> {
+    let file_type = match kind {
+        EntryKind::Fifo => libc::S_IFIFO,
+        EntryKind::CharDevice => libc::S_IFCHR,
+        EntryKind::BlockDevice => libc::S_IFBLK,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a special file",
+            ));
+        }
+    };
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: c_path is a valid NUL-terminated string.
+    let rc = unsafe {
+        libc::mknod(
+            c_path.as_ptr(),
+            file_type | (mode & 0o7777) as libc::mode_t,
+            rdev as libc::dev_t,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
     Ok(())
 }
+
+fn restore_directory(f: &ManifestFile, target: &Target) -> Outcome {
+    let dest_path = match target.destination(&f.path, false) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("restore: skipping {:?}: {e}", f.path);
+            return Outcome::Failed;
+        }
+    };
+
+    match fs::symlink_metadata(&dest_path) {
+        Ok(md) if md.is_dir() => {}
+        Ok(_) => {
+            eprintln!("restore: {:?} exists and is not a directory", dest_path);
+            return Outcome::Failed;
+        }
+        Err(_) => {
+            if let Err(e) = fs::create_dir(&dest_path) {
+                eprintln!("restore: failed to create directory {:?}: {e}", dest_path);
+                return Outcome::Failed;
+            }
+        }
+    }
+
+    target.apply_meta(&f.path, &dest_path, false);
+    Outcome::Restored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("backup-restore-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn target<'a>(
+        dir: &Path,
+        overwrite: Overwrite,
+        meta: HashMap<&'a str, &'a FileMeta>,
+    ) -> Target<'a> {
+        Target {
+            roots: vec![SourceRoot {
+                name: String::new(),
+                path: dir.to_path_buf(),
+            }],
+            allow_unsafe_paths: false,
+            restore_owner: false,
+            overwrite,
+            meta,
+        }
+    }
+
+    fn pending(rel: &str, content: &[u8]) -> PendingData {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(digest::digest(&digest::SHA256, content).as_ref());
+        PendingData {
+            rel: rel.to_string(),
+            size: content.len() as u64,
+            hash: Some(hash),
+            chunks: Vec::new(),
+            renamed: None,
+        }
+    }
+
+    fn modified_at(mtime: i64) -> FileMeta {
+        FileMeta {
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            atime: mtime,
+            atime_nsec: 0,
+            mtime,
+            mtime_nsec: 0,
+            xattrs: Vec::new(),
+        }
+    }
+
+    /// What `check_existing` decides for `wanted`: `Ok` with where the data
+    /// is written, or the outcome without writing it.
+    fn decide(
+        wanted: &mut PendingData,
+        target: &Target,
+        skip_identical: bool,
+    ) -> Result<PathBuf, &'static str> {
+        match check_existing(wanted, target, skip_identical) {
+            None => Ok(wanted
+                .renamed
+                .clone()
+                .unwrap_or_else(|| target.check(&wanted.rel, false).unwrap())),
+            Some(Outcome::Skipped) => Err("skipped"),
+            Some(_) => Err("other"),
+        }
+    }
+
+    #[test]
+    fn always_and_never() {
+        let dir = scratch_dir("always_never");
+        fs::write(dir.join("a"), b"old").unwrap();
+
+        let always = target(&dir, Overwrite::Always, HashMap::new());
+        assert_eq!(
+            decide(&mut pending("a", b"new"), &always, false),
+            Ok(dir.join("a"))
+        );
+
+        let never = target(&dir, Overwrite::Never, HashMap::new());
+        assert_eq!(
+            decide(&mut pending("a", b"new"), &never, false),
+            Err("skipped")
+        );
+        assert_eq!(
+            decide(&mut pending("b", b"new"), &never, false),
+            Ok(dir.join("b"))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn identical_files_are_skipped_on_resume_whatever_the_policy() {
+        let dir = scratch_dir("resume");
+        fs::write(dir.join("a"), b"same").unwrap();
+        let always = target(&dir, Overwrite::Always, HashMap::new());
+
+        assert_eq!(
+            decide(&mut pending("a", b"same"), &always, true),
+            Err("skipped")
+        );
+        assert_eq!(
+            decide(&mut pending("a", b"diff"), &always, true),
+            Ok(dir.join("a"))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn if_different_compares_content() {
+        let dir = scratch_dir("if_different");
+        fs::write(dir.join("a"), b"same").unwrap();
+        let policy = target(&dir, Overwrite::IfDifferent, HashMap::new());
+
+        assert_eq!(
+            decide(&mut pending("a", b"same"), &policy, false),
+            Err("skipped")
+        );
+        // same size, other content
+        assert_eq!(
+            decide(&mut pending("a", b"diff"), &policy, false),
+            Ok(dir.join("a"))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn if_newer_compares_modification_times() {
+        let dir = scratch_dir("if_newer");
+        let existing = File::create(dir.join("a")).unwrap();
+        existing
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            .unwrap();
+        let (older, newer) = (modified_at(1_600_000_000), modified_at(1_800_000_000));
+
+        let policy = target(&dir, Overwrite::IfNewer, HashMap::from([("a", &older)]));
+        assert_eq!(
+            decide(&mut pending("a", b"new"), &policy, false),
+            Err("skipped")
+        );
+        let policy = target(&dir, Overwrite::IfNewer, HashMap::from([("a", &newer)]));
+        assert_eq!(
+            decide(&mut pending("a", b"new"), &policy, false),
+            Ok(dir.join("a"))
+        );
+        // without a recorded time the entry is not known to be newer
+        let policy = target(&dir, Overwrite::IfNewer, HashMap::new());
+        assert_eq!(
+            decide(&mut pending("a", b"new"), &policy, false),
+            Err("skipped")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rename_picks_a_free_name() {
+        let dir = scratch_dir("rename");
+        let policy = target(&dir, Overwrite::Rename, HashMap::new());
+        assert_eq!(
+            decide(&mut pending("a", b"new"), &policy, false),
+            Ok(dir.join("a"))
+        );
+
+        fs::write(dir.join("a"), b"old").unwrap();
+        assert_eq!(
+            decide(&mut pending("a", b"new"), &policy, false),
+            Ok(dir.join("a.restored"))
+        );
+
+        fs::write(dir.join("a.restored"), b"old").unwrap();
+        fs::write(dir.join("a.restored.1"), b"old").unwrap();
+        assert_eq!(
+            decide(&mut pending("a", b"new"), &policy, false),
+            Ok(dir.join("a.restored.2"))
+        );
+        // identical content is renamed too, unless resuming
+        assert_eq!(
+            decide(&mut pending("a", b"old"), &policy, false),
+            Ok(dir.join("a.restored.2"))
+        );
+        assert_eq!(
+            decide(&mut pending("a", b"old"), &policy, true),
+            Err("skipped")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn manifest_file(path: &str, content: &[u8]) -> ManifestFile {
+        let hash: String = digest::digest(&digest::SHA256, content)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "path": path,
+            "kind": "file",
+            "size": content.len(),
+            "sha256": hash,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn dry_run_report_shape() {
+        let dir = scratch_dir("plan");
+        fs::write(dir.join("same"), b"same data").unwrap();
+        fs::write(dir.join("changed"), b"old data").unwrap();
+        let files = [
+            manifest_file("same", b"same data"),
+            manifest_file("changed", b"new data"),
+            manifest_file("added", b"added data"),
+        ];
+        let selected: Vec<&ManifestFile> = files.iter().collect();
+        let policy = target(&dir, Overwrite::IfDifferent, HashMap::new());
+
+        let report = plan_report(&selected, &policy, Path::new("a.backup"), false);
+        let dest = |name: &str| dir.join(name).to_string_lossy().to_string();
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "archive": "a.backup",
+                "entries": [
+                    {
+                        "path": "same",
+                        "kind": "file",
+                        "action": "keep",
+                        "destination": dest("same"),
+                        "size": 9,
+                        "existing_size": 9,
+                        "identical": true,
+                    },
+                    {
+                        "path": "changed",
+                        "kind": "file",
+                        "action": "overwrite",
+                        "destination": dest("changed"),
+                        "size": 8,
+                        "existing_size": 8,
+                        "identical": false,
+                    },
+                    {
+                        "path": "added",
+                        "kind": "file",
+                        "action": "create",
+                        "destination": dest("added"),
+                        "size": 10,
+                    },
+                ],
+                "create": 1,
+                "overwrite": 1,
+                "rename": 0,
+                "keep": 1,
+                "refused": 0,
+            })
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
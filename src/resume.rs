@@ -0,0 +1,463 @@
+use crate::archive::{ArchiveReader, IndexEntry};
+use crate::backup_file::PARTIAL_SUFFIX;
+use crate::crypto::Credentials;
+use crate::incremental::hash_from_hex;
+use crate::metadata::FileMeta;
+use crate::pipeline::HashedFile;
+use crate::profile::Profile;
+use crate::types::{EntryKind, FileEntry, SourceRoot};
+use indicatif::ProgressBar;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Appended to the name of an archive for the checkpoint kept next to its
+/// partial file while it is written.
+pub const CHECKPOINT_SUFFIX: &str = ".checkpoint";
+
+/// How often the entries written so far are synced and recorded.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+pub fn checkpoint_path(backup_file: &Path) -> PathBuf {
+    let mut path = backup_file.as_os_str().to_owned();
+    path.push(CHECKPOINT_SUFFIX);
+    PathBuf::from(path)
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(msg: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn hash_to_hex(hash: &[u8; 32]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// First line of a checkpoint: how to run the interrupted backup again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckpointHeader {
+    /// name the archive gets once complete
+    pub backup_file: String,
+    pub created_at: u64,
+    /// the settings of the backup, with the archive it is compared against
+    /// and the recipients pinned down
+    pub profile: Profile,
+}
+
+/// An entry that is completely written to the partial archive and synced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckpointEntry {
+    pub path: String,
+    pub offset: u64,
+    /// where the next entry starts
+    pub end: u64,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub changed: bool,
+    pub meta: FileMeta,
+}
+
+impl CheckpointEntry {
+    pub fn new(entry: &IndexEntry, file: &HashedFile, end: u64) -> Self {
+        Self {
+            path: entry.path.clone(),
+            offset: entry.offset,
+            end,
+            size: entry.size,
+            sha256: hash_to_hex(&entry.hash),
+            changed: file.changed,
+            meta: file.entry.meta.clone(),
+        }
+    }
+
+    pub fn index_entry(&self) -> io::Result<IndexEntry> {
+        Ok(IndexEntry {
+            path: self.path.clone(),
+            offset: self.offset,
+            size: self.size,
+            hash: hash_from_hex(&self.sha256).ok_or_else(|| {
+                invalid_data(format!("invalid hash in checkpoint: {}", self.path))
+            })?,
+        })
+    }
+
+    /// The stored file as the manifest lists it, with its source path
+    /// found from the archive path.
+    pub fn hashed_file(&self, roots: &[SourceRoot]) -> io::Result<HashedFile> {
+        Ok(HashedFile {
+            entry: FileEntry {
+                path: source_path(roots, &self.path),
+                size: self.size,
+                kind: EntryKind::File,
+                meta: self.meta.clone(),
+                link_target: None,
+                rdev: 0,
+            },
+            hash: self.index_entry()?.hash,
+            chunks: Vec::new(),
+            changed: self.changed,
+        })
+    }
+}
+
+/// Source path of the entry stored at `path` in an archive of `roots`.
+fn source_path(roots: &[SourceRoot], path: &str) -> PathBuf {
+    let path = Path::new(path);
+    roots
+        .iter()
+        .find_map(|r| {
+            if r.name.is_empty() {
+                Some(r.path.join(path))
+            } else {
+                path.strip_prefix(&r.name)
+                    .ok()
+                    .map(|rest| r.path.join(rest))
+            }
+        })
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+fn write_line<T: Serialize>(out: &mut Vec<u8>, value: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, value).map_err(io::Error::other)?;
+    out.push(b'\n');
+    Ok(())
+}
+
+/// The checkpoint of an interrupted backup, read back to resume it.
+pub struct Checkpoint {
+    /// the partial archive the checkpoint belongs to
+    pub partial: PathBuf,
+    pub header: CheckpointHeader,
+    pub entries: Vec<CheckpointEntry>,
+}
+
+impl Checkpoint {
+    /// Reads the checkpoint kept next to the partial archive `partial`. A
+    /// last line cut short by the interruption is ignored.
+    pub fn load(partial: &Path) -> io::Result<Self> {
+        let partial = fs::canonicalize(partial)?;
+        let name = partial
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(PARTIAL_SUFFIX))
+            .filter(|n| n.ends_with(".backup"))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{:?} is not a partial archive (*.backup{PARTIAL_SUFFIX})",
+                        partial
+                    ),
+                )
+            })?
+            .to_string();
+        let path = checkpoint_path(&partial.with_file_name(&name));
+        let file = File::open(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to open checkpoint {:?}: {e}", path),
+            )
+        })?;
+
+        let mut lines = BufReader::new(file).lines();
+        let header: CheckpointHeader = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)
+                .map_err(|e| invalid_data(format!("invalid checkpoint {:?}: {e}", path)))?,
+            None => return Err(invalid_data(format!("checkpoint {:?} is empty", path))),
+        };
+        if header.backup_file != name {
+            return Err(invalid_data(format!(
+                "checkpoint {:?} belongs to {}",
+                path, header.backup_file
+            )));
+        }
+
+        let mut entries = Vec::new();
+        for line in lines {
+            match serde_json::from_str(&line?) {
+                Ok(entry) => entries.push(entry),
+                Err(_) => break,
+            }
+        }
+
+        Ok(Self {
+            partial,
+            header,
+            entries,
+        })
+    }
+}
+
+/// Records the entries of an archive being written in its checkpoint.
+/// Entries are only recorded once the archive has been synced past them.
+pub struct Checkpointer {
+    file: File,
+    pending: Vec<CheckpointEntry>,
+    last: Instant,
+}
+
+impl Checkpointer {
+    /// Starts the checkpoint at `path` over with `header` and the entries
+    /// the archive already holds.
+    pub fn create(
+        path: &Path,
+        header: &CheckpointHeader,
+        entries: &[CheckpointEntry],
+    ) -> io::Result<Self> {
+        let mut out = Vec::new();
+        write_line(&mut out, header)?;
+        for entry in entries {
+            write_line(&mut out, entry)?;
+        }
+        let mut file = File::create(path)?;
+        file.write_all(&out)?;
+        file.sync_data()?;
+
+        Ok(Self {
+            file,
+            pending: Vec::new(),
+            last: Instant::now(),
+        })
+    }
+
+    /// Adds an entry that was written to the archive, to be recorded with
+    /// the next checkpoint.
+    pub fn add(&mut self, entry: CheckpointEntry) {
+        self.pending.push(entry);
+    }
+
+    pub fn due(&self) -> bool {
+        self.last.elapsed() >= CHECKPOINT_INTERVAL
+    }
+
+    /// Records the pending entries that end within the first `durable`
+    /// bytes of the archive, which are synced to disk.
+    pub fn record(&mut self, durable: u64) -> io::Result<()> {
+        let count = self.pending.iter().take_while(|e| e.end <= durable).count();
+        let mut out = Vec::new();
+        for entry in self.pending.drain(..count) {
+            write_line(&mut out, &entry)?;
+        }
+        self.file.write_all(&out)?;
+        self.file.sync_data()?;
+        self.last = Instant::now();
+        Ok(())
+    }
+}
+
+/// The part of a partial archive a resumed backup keeps.
+pub struct Prefix {
+    /// the partial archive, locked so no other run touches it
+    pub file: File,
+    /// reader of the partial archive, `None` when nothing is kept
+    pub archive: Option<ArchiveReader>,
+    pub entries: Vec<CheckpointEntry>,
+    /// where the kept entries end, 0 when nothing is kept
+    pub end: u64,
+}
+
+/// Reads back the entries the checkpoint records, in order, and keeps those
+/// up to the first one that is missing or damaged in the partial archive.
+pub fn verify_prefix(checkpoint: &Checkpoint, credentials: &Credentials) -> io::Result<Prefix> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&checkpoint.partial)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "the partial archive is still being written by another run",
+            ));
+        }
+        Err(TryLockError::Error(e)) => return Err(e),
+    }
+    if checkpoint.entries.is_empty() {
+        return Ok(Prefix {
+            file,
+            archive: None,
+            entries: Vec::new(),
+            end: 0,
+        });
+    }
+
+    let index = checkpoint
+        .entries
+        .iter()
+        .map(CheckpointEntry::index_entry)
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut archive = ArchiveReader::open_partial(&checkpoint.partial, credentials, index.clone())?;
+
+    let pb = ProgressBar::new(checkpoint.entries.iter().map(|e| e.size).sum());
+    let mut kept = 0;
+    let mut end = 4 + 4;
+    for (entry, indexed) in checkpoint.entries.iter().zip(&index) {
+        if let Err(e) = verify_entry(&mut archive, indexed, end, entry.end, &pb) {
+            eprintln!(
+                "warning: {} is not intact in the partial archive, resuming before it: {e}",
+                entry.path
+            );
+            break;
+        }
+        kept += 1;
+        end = entry.end;
+    }
+    pb.finish_with_message("verify complete");
+
+    if kept == 0 {
+        return Ok(Prefix {
+            file,
+            archive: None,
+            entries: Vec::new(),
+            end: 0,
+        });
+    }
+    Ok(Prefix {
+        file,
+        archive: Some(archive),
+        entries: checkpoint.entries[..kept].to_vec(),
+        end,
+    })
+}
+
+/// Checks that `entry` starts at `start`, ends at `end` and holds the data
+/// it was recorded with.
+fn verify_entry(
+    archive: &mut ArchiveReader,
+    entry: &IndexEntry,
+    start: u64,
+    end: u64,
+    pb: &ProgressBar,
+) -> io::Result<()> {
+    if entry.offset != start {
+        return Err(invalid_data("entry does not follow the one before it"));
+    }
+    let header = archive.seek_entry(entry)?;
+
+    let mut data = archive.entry_data(&header);
+    let mut buf = [0u8; 8192];
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    loop {
+        let n = data.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        ctx.update(&buf[..n]);
+        pb.inc(n as u64);
+    }
+
+    if ctx.finish().as_ref() != entry.hash {
+        return Err(invalid_data("data does not match the recorded hash"));
+    }
+    if archive.position()? != end {
+        return Err(invalid_data("entry does not end where it was recorded to"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &str = "src-1700000000.backup";
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("backup-resume-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn header(backup_file: &str) -> CheckpointHeader {
+        CheckpointHeader {
+            backup_file: backup_file.to_string(),
+            created_at: 1_700_000_000,
+            profile: toml::from_str(r#"destination = "/backups""#).unwrap(),
+        }
+    }
+
+    fn entry(path: &str, offset: u64, end: u64) -> CheckpointEntry {
+        CheckpointEntry {
+            path: path.to_string(),
+            offset,
+            end,
+            size: end - offset,
+            sha256: "00".repeat(32),
+            changed: false,
+            meta: FileMeta {
+                mode: 0o644,
+                uid: 0,
+                gid: 0,
+                atime: 0,
+                atime_nsec: 0,
+                mtime: 0,
+                mtime_nsec: 0,
+                xattrs: Vec::new(),
+            },
+        }
+    }
+
+    /// Writes a checkpoint for `dir/NAME` and an empty partial archive.
+    fn checkpoint(dir: &Path, header: &CheckpointHeader, entries: &[CheckpointEntry]) -> PathBuf {
+        let backup_file = dir.join(NAME);
+        Checkpointer::create(&checkpoint_path(&backup_file), header, entries).unwrap();
+        let mut partial = backup_file.into_os_string();
+        partial.push(PARTIAL_SUFFIX);
+        fs::write(&partial, b"").unwrap();
+        PathBuf::from(partial)
+    }
+
+    #[test]
+    fn line_cut_short_is_ignored() {
+        let dir = scratch_dir("cut-line");
+        let entries = [entry("a", 8, 100), entry("b", 100, 200)];
+        let partial = checkpoint(&dir, &header(NAME), &entries);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(checkpoint_path(&dir.join(NAME)))
+            .unwrap();
+        file.write_all(br#"{"path":"c","offs"#).unwrap();
+
+        let loaded = Checkpoint::load(&partial).unwrap();
+        assert_eq!(loaded.header.backup_file, NAME);
+        let paths: Vec<&str> = loaded.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["a", "b"]);
+        assert_eq!(loaded.entries[1].end, 200);
+    }
+
+    #[test]
+    fn checkpoint_must_belong_to_the_partial_archive() {
+        let dir = scratch_dir("other-archive");
+        let partial = checkpoint(&dir, &header("other-1700000000.backup"), &[]);
+        let err = Checkpoint::load(&partial).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let finished = dir.join(NAME);
+        fs::write(&finished, b"").unwrap();
+        let err = Checkpoint::load(&finished).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn partial_archive_in_use_is_not_resumed() {
+        let dir = scratch_dir("locked");
+        let partial = checkpoint(&dir, &header(NAME), &[]);
+        let loaded = Checkpoint::load(&partial).unwrap();
+
+        let writer = File::open(&partial).unwrap();
+        writer.lock().unwrap();
+        let err = verify_prefix(&loaded, &Credentials::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        drop(writer);
+        let prefix = verify_prefix(&loaded, &Credentials::default()).unwrap();
+        assert!(prefix.entries.is_empty());
+        assert_eq!(prefix.end, 0);
+    }
+}
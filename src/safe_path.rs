@@ -0,0 +1,85 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Maps an archive path onto `root` for restoring, creating the missing
+/// parent directories on the way.
+///
+/// Archive paths come from untrusted input, so absolute paths, `..`
+/// components and paths that would pass through a symlink (including a
+/// symlink sitting where the entry itself goes) are rejected. Parents are
+/// created one component at a time so an existing symlink is never followed.
+pub fn prepare_destination(root: &Path, rel: &str) -> Result<PathBuf, String> {
+    not_a_symlink(prepare_parent(root, rel)?)
+}
+
+/// Like [`prepare_destination`], but accepts a symlink at the final
+/// component. Used for entries that replace whatever is at their path
+/// without ever writing through it.
+pub fn prepare_parent(root: &Path, rel: &str) -> Result<PathBuf, String> {
+    resolve(root, rel, true)
+}
+
+/// Like [`prepare_destination`], without creating anything. Used to report
+/// what a restore would do; directories that are missing would be created.
+pub fn check_destination(root: &Path, rel: &str) -> Result<PathBuf, String> {
+    not_a_symlink(check_parent(root, rel)?)
+}
+
+/// Like [`prepare_parent`], without creating anything.
+pub fn check_parent(root: &Path, rel: &str) -> Result<PathBuf, String> {
+    resolve(root, rel, false)
+}
+
+fn not_a_symlink(path: PathBuf) -> Result<PathBuf, String> {
+    if let Ok(md) = fs::symlink_metadata(&path)
+        && md.file_type().is_symlink()
+    {
+        return Err(format!("{:?} is a symlink", path));
+    }
+    Ok(path)
+}
+
+fn resolve(root: &Path, rel: &str, create: bool) -> Result<PathBuf, String> {
+    let mut parts = Vec::new();
+    for comp in Path::new(rel).components() {
+        match comp {
+            Component::Normal(p) => parts.push(p),
+            Component::CurDir => {}
+            Component::ParentDir => return Err("path contains a '..' component".to_string()),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err("path is absolute".to_string());
+            }
+        }
+    }
+
+    let Some((name, dirs)) = parts.split_last() else {
+        return Err("path is empty".to_string());
+    };
+
+    let mut current = root.to_path_buf();
+    let mut missing = false;
+    for dir in dirs {
+        current.push(dir);
+        // below a directory that does not exist yet nothing can be in the way
+        if missing {
+            continue;
+        }
+        match fs::symlink_metadata(&current) {
+            Ok(md) if md.file_type().is_symlink() => {
+                return Err(format!("{:?} is a symlink", current));
+            }
+            Ok(md) if md.is_dir() => {}
+            Ok(_) => return Err(format!("{:?} is not a directory", current)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !create => missing = true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir(&current)
+                    .map_err(|e| format!("failed to create directory {:?}: {e}", current))?;
+            }
+            Err(e) => return Err(format!("failed to inspect {:?}: {e}", current)),
+        }
+    }
+
+    current.push(name);
+    Ok(current)
+}
@@ -0,0 +1,167 @@
+use crate::archive::ArchiveSignature;
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const PUBLIC_KEY_PREFIX: &str = "backup-sign-pub-";
+pub const SECRET_KEY_PREFIX: &str = "backup-sign-secret-";
+const SIGNATURE_CONTEXT: &[u8] = b"backup archive signature v1\0";
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(msg: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn public_key_string(key: &[u8; 32]) -> String {
+    format!("{PUBLIC_KEY_PREFIX}{}", to_hex(key))
+}
+
+pub fn parse_public_key(s: &str) -> Result<[u8; 32], String> {
+    s.trim()
+        .strip_prefix(PUBLIC_KEY_PREFIX)
+        .and_then(from_hex)
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| format!("invalid signing public key {s:?}"))
+}
+
+/// Resolves a `--trusted-key` value, which is either a public key as printed
+/// by `backup keygen --sign` or a file holding such keys, one per line.
+pub fn load_trusted_keys(arg: &str) -> io::Result<Vec<[u8; 32]>> {
+    if arg.starts_with(PUBLIC_KEY_PREFIX) {
+        return parse_public_key(arg)
+            .map(|k| vec![k])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+    }
+
+    let raw = fs::read_to_string(arg).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed to read trusted key file {arg:?}: {e}"),
+        )
+    })?;
+    let keys: Vec<[u8; 32]> = raw
+        .lines()
+        .map(str::trim)
+        .filter(|l| l.starts_with(PUBLIC_KEY_PREFIX))
+        .map(|l| parse_public_key(l).map_err(invalid_data))
+        .collect::<io::Result<_>>()?;
+
+    if keys.is_empty() {
+        return Err(invalid_data(format!(
+            "no signing public key found in {arg:?}"
+        )));
+    }
+    Ok(keys)
+}
+
+pub fn read_signing_key(path: &Path) -> io::Result<Ed25519KeyPair> {
+    let raw = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed to read signing key {:?}: {e}", path),
+        )
+    })?;
+
+    let pkcs8 = raw
+        .lines()
+        .find_map(|l| l.trim().strip_prefix(SECRET_KEY_PREFIX))
+        .and_then(from_hex)
+        .ok_or_else(|| invalid_data(format!("no signing secret key found in {:?}", path)))?;
+    Ed25519KeyPair::from_pkcs8(&pkcs8)
+        .map_err(|e| invalid_data(format!("invalid signing key in {:?}: {e}", path)))
+}
+
+/// Creates a new Ed25519 signing key file and returns the matching public
+/// key. Like identity files it is only readable by its owner and never
+/// overwrites an existing file.
+pub fn write_signing_key_file(path: &Path) -> io::Result<String> {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| io::Error::other("failed to generate signing key"))?;
+    let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+        .map_err(|_| io::Error::other("failed to generate signing key"))?;
+    let public = format!("{PUBLIC_KEY_PREFIX}{}", to_hex(pair.public_key().as_ref()));
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "# created: {created} (unix seconds)")?;
+    writeln!(file, "# public key: {public}")?;
+    writeln!(file, "{SECRET_KEY_PREFIX}{}", to_hex(pkcs8.as_ref()))?;
+    file.sync_all()?;
+    Ok(public)
+}
+
+/// Running digest over the header of every data entry, in archive order.
+/// Together with the entry hashes it pins down the complete archive contents.
+pub struct EntryDigest(digest::Context);
+
+impl EntryDigest {
+    pub fn new() -> Self {
+        Self(digest::Context::new(&digest::SHA256))
+    }
+
+    pub fn add(&mut self, path: &str, size: u64, hash: &[u8; 32]) {
+        self.0.update(&(path.len() as u64).to_le_bytes());
+        self.0.update(path.as_bytes());
+        self.0.update(&size.to_le_bytes());
+        self.0.update(hash);
+    }
+
+    pub fn finish(self) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        out.copy_from_slice(self.0.finish().as_ref());
+        out
+    }
+}
+
+fn signed_message(manifest: &[u8], entries: &[u8; 32]) -> Vec<u8> {
+    let mut msg = SIGNATURE_CONTEXT.to_vec();
+    msg.extend_from_slice(digest::digest(&digest::SHA256, manifest).as_ref());
+    msg.extend_from_slice(entries);
+    msg
+}
+
+pub fn sign(key: &Ed25519KeyPair, manifest: &[u8], entries: &[u8; 32]) -> ArchiveSignature {
+    let sig = key.sign(&signed_message(manifest, entries));
+    let mut public_key = [0u8; 32];
+    public_key.copy_from_slice(key.public_key().as_ref());
+    let mut signature = [0u8; 64];
+    signature.copy_from_slice(sig.as_ref());
+    ArchiveSignature {
+        public_key,
+        signature,
+    }
+}
+
+/// Checks `sig` against the manifest and entry digest. This only proves the
+/// archive was signed by `sig.public_key`; callers decide whether that key
+/// is trusted.
+pub fn verify(sig: &ArchiveSignature, manifest: &[u8], entries: &[u8; 32]) -> bool {
+    UnparsedPublicKey::new(&signature::ED25519, &sig.public_key)
+        .verify(&signed_message(manifest, entries), &sig.signature)
+        .is_ok()
+}
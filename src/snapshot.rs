@@ -0,0 +1,298 @@
+use crate::config::SnapshotConfig;
+use crate::filter::PathFilter;
+use crate::fs_scan;
+use crate::manifest::build_manifest_json;
+use crate::pipeline::{ChunkPiece, HashedFile, chunk_files_ordered};
+use crate::repository::{PackWriter, Repository};
+use crate::types::{EntryKind, SourceRoot};
+use indicatif::ProgressBar;
+use std::fs;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn print_section(title: &str) {
+    println!();
+    println!("--- {title} ---");
+}
+
+fn print_kv<K: AsRef<str>, V: AsRef<str>>(k: K, v: V) {
+    println!("  {:12} {}", format!("{}:", k.as_ref()), v.as_ref());
+}
+
+fn invalid_input<E: Into<Box<dyn std::error::Error + Send + Sync>>>(msg: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Stores the source tree in the repository. Files are read once, split
+/// into content-defined chunks as they are read, and only chunks the
+/// repository does not hold yet are written; the snapshot manifest lists
+/// every file's chunks by their SHA-256.
+pub fn create_snapshot(config: &SnapshotConfig) -> io::Result<()> {
+    if !config.source.is_dir() {
+        return Err(invalid_input(format!(
+            "source must be a directory: {:?}",
+            config.source
+        )));
+    }
+    let source_root = fs::canonicalize(&config.source)?;
+    let mut repo = Repository::open(&config.repository)?;
+    if fs::canonicalize(&config.repository)?.starts_with(&source_root) {
+        return Err(invalid_input(
+            "repository cannot be inside the source directory",
+        ));
+    }
+
+    let path_filter =
+        PathFilter::from_patterns(&config.includes, &config.excludes).map_err(invalid_input)?;
+
+    let source_name = source_root
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("backup");
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let name = format!("{source_name}-{ts}");
+
+    print_section("paths");
+    print_kv("source", source_root.to_string_lossy());
+    print_kv("repository", config.repository.to_string_lossy());
+    print_kv("snapshot", &name);
+
+    print_section("scan");
+    let (files, others): (Vec<_>, Vec<_>) =
+        fs_scan::scan_dir_with_filter(&source_root, Some(&path_filter))
+            .into_iter()
+            .partition(|e| e.kind == EntryKind::File);
+    let total_bytes: u64 = files.iter().map(|f| f.size).sum();
+    print_kv("files", files.len().to_string());
+    if !others.is_empty() {
+        print_kv("other", others.len().to_string());
+    }
+    print_kv("bytes", total_bytes.to_string());
+
+    print_section("store");
+    let pb_store = ProgressBar::new(total_bytes);
+    let chunking = repo.chunking();
+    let mut writer = PackWriter::new(&mut repo);
+    let mut stored: Vec<HashedFile> = Vec::with_capacity(files.len());
+    let mut changed = 0usize;
+    let mut failed = 0usize;
+    chunk_files_ordered(&files, chunking, |f, pieces| {
+        let mut chunks = Vec::new();
+        for piece in pieces {
+            match piece {
+                ChunkPiece::Chunk { id, data } => {
+                    pb_store.inc(data.len() as u64);
+                    if !writer.contains(&id) {
+                        writer.add_blob(&id, &mut &data[..], data.len() as u64)?;
+                    }
+                    chunks.push(id);
+                }
+                ChunkPiece::Done {
+                    hash,
+                    changed: false,
+                } => {
+                    stored.push(HashedFile {
+                        entry: f.clone(),
+                        hash,
+                        chunks,
+                        changed: false,
+                    });
+                    break;
+                }
+                // Its chunks may not be one consistent version of the file.
+                ChunkPiece::Done { changed: true, .. } => {
+                    eprintln!(
+                        "warning: {:?} changed while taking the snapshot, not included",
+                        f.path
+                    );
+                    changed += 1;
+                    break;
+                }
+                ChunkPiece::Failed(e) => {
+                    eprintln!("warning: failed to read file {:?}: {e}", f.path);
+                    failed += 1;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    let (new_blobs, new_bytes) = (writer.new_blobs, writer.new_bytes);
+    writer.finish()?;
+    pb_store.finish_with_message("store complete");
+
+    let stored_bytes: u64 = stored.iter().map(|h| h.entry.size).sum();
+    print_kv("stored", stored.len().to_string());
+    print_kv(
+        "chunks",
+        stored
+            .iter()
+            .map(|h| h.chunks.len())
+            .sum::<usize>()
+            .to_string(),
+    );
+    print_kv("new chunks", new_blobs.to_string());
+    print_kv("new bytes", new_bytes.to_string());
+    print_kv(
+        "dedup bytes",
+        stored_bytes.saturating_sub(new_bytes).to_string(),
+    );
+    if changed > 0 {
+        print_kv("changed", changed.to_string());
+    }
+    if failed > 0 {
+        print_kv("failed", failed.to_string());
+    }
+
+    print_section("snapshot");
+    let root = SourceRoot {
+        name: String::new(),
+        path: source_root.clone(),
+    };
+    let manifest_json = build_manifest_json(
+        &[root],
+        &repo.snapshot_path(&name),
+        ts,
+        &stored,
+        &others,
+        None,
+    )
+    .map_err(io::Error::other)?;
+    let written = repo.write_snapshot(&name, &manifest_json)?;
+    print_kv("entries", (stored.len() + others.len()).to_string());
+    print_kv("written", written.to_string_lossy());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::ChunkParams;
+    use crate::config::RestoreConfig;
+    use crate::repository;
+    use crate::restore::restore_backup;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    const PARAMS: ChunkParams = ChunkParams {
+        min_size: 256,
+        avg_size: 1024,
+        max_size: 4096,
+    };
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("backup-snapshot-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Reproducible pseudo-random bytes (xorshift64).
+    fn data(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn args(list: &[&str]) -> impl Iterator<Item = String> + use<> {
+        list.iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn path(p: &Path) -> &str {
+        p.to_str().unwrap()
+    }
+
+    /// Takes a snapshot and returns its name.
+    fn snapshot(source: &Path, repo: &Path) -> String {
+        let config = SnapshotConfig::from_args(args(&[path(source), path(repo)])).unwrap();
+        create_snapshot(&config).unwrap();
+        Repository::open(repo)
+            .unwrap()
+            .snapshots()
+            .unwrap()
+            .pop()
+            .unwrap()
+    }
+
+    fn restore(repo: &Path, name: &str, out: &Path) {
+        let config =
+            RestoreConfig::from_args(args(&[path(repo), path(out), "--snapshot", name])).unwrap();
+        restore_backup(&config).unwrap();
+    }
+
+    #[test]
+    fn round_trip_restores_empty_files() {
+        let dir = scratch_dir("round-trip");
+        let source = dir.join("src");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("data.bin"), data(100_000)).unwrap();
+        fs::write(source.join("empty"), b"").unwrap();
+        fs::write(source.join("sub/empty"), b"").unwrap();
+
+        let repo = dir.join("repo");
+        repository::init(&repo, ChunkParams::default()).unwrap();
+        let name = snapshot(&source, &repo);
+
+        let out = dir.join("out");
+        restore(&repo, &name, &out);
+        assert_eq!(fs::read(out.join("data.bin")).unwrap(), data(100_000));
+        assert_eq!(fs::read(out.join("empty")).unwrap(), b"");
+        assert_eq!(fs::read(out.join("sub/empty")).unwrap(), b"");
+    }
+
+    #[test]
+    fn second_snapshot_stores_only_new_chunks() {
+        let dir = scratch_dir("dedup");
+        let source = dir.join("src");
+        fs::create_dir_all(&source).unwrap();
+        let original = data(200_000);
+        fs::write(source.join("big.bin"), &original).unwrap();
+        fs::write(source.join("copy.bin"), &original).unwrap();
+
+        let repo = dir.join("repo");
+        repository::init(&repo, PARAMS).unwrap();
+        let first = snapshot(&source, &repo);
+        let stored = Repository::open(&repo).unwrap().blob_bytes();
+        // the identical copy adds nothing
+        assert!(stored <= original.len() as u64);
+
+        let mut changed = original.clone();
+        changed[100_000..100_010].fill(0);
+        fs::write(source.join("big.bin"), &changed).unwrap();
+        fs::write(source.join("new.txt"), b"new file").unwrap();
+        // snapshot names have a resolution of one second
+        std::thread::sleep(Duration::from_secs(1));
+        let second = snapshot(&source, &repo);
+        assert_ne!(first, second);
+
+        let added = Repository::open(&repo).unwrap().blob_bytes() - stored;
+        assert!(added > 0);
+        assert!(added < 3 * PARAMS.max_size as u64, "added {added} bytes");
+
+        let out = dir.join("first");
+        restore(&repo, &first, &out);
+        assert_eq!(fs::read(out.join("big.bin")).unwrap(), original);
+        assert_eq!(fs::read(out.join("copy.bin")).unwrap(), original);
+        assert!(!out.join("new.txt").exists());
+
+        let out = dir.join("second");
+        restore(&repo, &second, &out);
+        assert_eq!(fs::read(out.join("big.bin")).unwrap(), changed);
+        assert_eq!(fs::read(out.join("copy.bin")).unwrap(), original);
+        assert_eq!(fs::read(out.join("new.txt")).unwrap(), b"new file");
+    }
+}
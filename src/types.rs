@@ -1,7 +1,99 @@
-use std::path::PathBuf;
+use crate::metadata::FileMeta;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    #[default]
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    HardLink,
+}
 
 #[derive(Debug, Clone)]
 pub struct FileEntry {
     pub path: PathBuf,
     pub size: u64,
+    pub kind: EntryKind,
+    pub meta: FileMeta,
+    /// target of a symlink as stored in the link, or for a hard link the
+    /// archive path of the first entry sharing its inode
+    pub link_target: Option<String>,
+    /// device number of a character or block device
+    pub rdev: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupType {
+    /// holds the data of every file
+    #[default]
+    Full,
+    /// holds what changed since its parent, which may itself be incremental
+    Incremental,
+    /// holds what changed since its base, which is always a full backup
+    Differential,
+}
+
+impl BackupType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BackupType::Full => "full",
+            BackupType::Incremental => "incremental",
+            BackupType::Differential => "differential",
+        }
+    }
+}
+
+/// Identifies the archive an incremental or differential backup builds on.
+/// The archive is looked up by file name next to the dependent one, and the
+/// manifest hash pins down its exact contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParentRef {
+    pub backup_file: String,
+    pub manifest_sha256: String,
+}
+
+/// A directory backed up into an archive and the top-level name its entries
+/// are stored under. The only source of an archive is stored unnamed, its
+/// entries directly at the top.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceRoot {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl SourceRoot {
+    /// Path of `path`, which lies below this root, inside the archive.
+    pub fn archive_path(&self, path: &Path) -> Option<PathBuf> {
+        let rel = path.strip_prefix(&self.path).ok()?;
+        if rel.as_os_str().is_empty() {
+            return Some(PathBuf::from(&self.name));
+        }
+        Some(Path::new(&self.name).join(rel))
+    }
+}
+
+/// Path of `path` inside an archive of `roots`, or `path` itself if it is
+/// below none of them.
+pub fn archive_path(roots: &[SourceRoot], path: &Path) -> PathBuf {
+    roots
+        .iter()
+        .find_map(|r| r.archive_path(path))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// The source recorded in a manifest: the source directory, or a list of
+/// them for an archive of several.
+pub fn describe_roots(roots: &[SourceRoot]) -> String {
+    roots
+        .iter()
+        .map(|r| r.path.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
@@ -1,24 +1,89 @@
 use crate::config::BackupConfig;
+use crate::types::SourceRoot;
+use std::collections::HashSet;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone)]
 pub struct ValidatedPaths {
-    pub source_root: PathBuf,
+    pub roots: Vec<SourceRoot>,
     pub backup_dir: PathBuf,
 }
 
-pub fn validate_paths(config: &BackupConfig) -> Result<ValidatedPaths, String> {
-    let source = &config.source;
-    let backup_dir = &config.destination;
+fn dir_name(path: &Path) -> &str {
+    path.file_name().and_then(|s| s.to_str()).unwrap_or("root")
+}
 
-    if !source.exists() {
-        return Err(format!("source path does not exist: {:?}", source));
+/// Checks and canonicalizes the source directories. A single source is
+/// stored unnamed; several are each stored under their directory name, made
+/// unique with a numeric suffix where two names clash.
+pub fn source_roots(sources: &[PathBuf]) -> Result<Vec<SourceRoot>, String> {
+    let mut paths: Vec<PathBuf> = Vec::with_capacity(sources.len());
+    for source in sources {
+        if !source.exists() {
+            return Err(format!("source path does not exist: {:?}", source));
+        }
+        if !source.is_dir() {
+            return Err(format!("source must be a directory: {:?}", source));
+        }
+        let canon = fs::canonicalize(source)
+            .map_err(|e| format!("failed to canonicalize source {:?}: {e}", source))?;
+        if let Some(other) = paths
+            .iter()
+            .find(|p| p.starts_with(&canon) || canon.starts_with(p))
+        {
+            return Err(format!(
+                "sources {:?} and {:?} overlap, back up only the outer one",
+                other, canon
+            ));
+        }
+        paths.push(canon);
     }
 
-    if !source.is_dir() {
-        return Err(format!("source must be a directory: {:?}", source));
+    if let [path] = paths.as_slice() {
+        return Ok(vec![SourceRoot {
+            name: String::new(),
+            path: path.clone(),
+        }]);
+    }
+
+    let mut taken = HashSet::new();
+    Ok(paths
+        .into_iter()
+        .map(|path| {
+            let base = dir_name(&path).to_string();
+            let mut name = base.clone();
+            let mut n = 1;
+            while !taken.insert(name.clone()) {
+                n += 1;
+                name = format!("{base}-{n}");
+            }
+            SourceRoot { name, path }
+        })
+        .collect())
+}
+
+/// Name of the archives of `roots`, before the timestamp: the source
+/// directory's name, or the top-level names joined with `+`.
+pub fn archive_name(roots: &[SourceRoot]) -> String {
+    match roots {
+        [root] => root
+            .path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or("backup")
+            .to_string(),
+        _ => roots
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect::<Vec<_>>()
+            .join("+"),
     }
+}
+
+pub fn validate_paths(config: &BackupConfig) -> Result<ValidatedPaths, String> {
+    let backup_dir = &config.destination;
+    let roots = source_roots(&config.sources)?;
 
     if backup_dir.exists() && !backup_dir.is_dir() {
         return Err(format!(
@@ -32,8 +97,6 @@ pub fn validate_paths(config: &BackupConfig) -> Result<ValidatedPaths, String> {
             .map_err(|e| format!("failed to create backup directory {:?}: {e}", backup_dir))?;
     }
 
-    let source_canon = fs::canonicalize(source)
-        .map_err(|e| format!("failed to canonicalize source {:?}: {e}", source))?;
     let backup_dir_canon = fs::canonicalize(backup_dir).map_err(|e| {
         format!(
             "failed to canonicalize backup directory {:?}: {e}",
@@ -41,16 +104,18 @@ pub fn validate_paths(config: &BackupConfig) -> Result<ValidatedPaths, String> {
         )
     })?;
 
-    if source_canon == backup_dir_canon {
-        return Err("backup directory cannot be the same as source directory".to_string());
-    }
+    for root in &roots {
+        if root.path == backup_dir_canon {
+            return Err("backup directory cannot be the same as source directory".to_string());
+        }
 
-    if backup_dir_canon.starts_with(&source_canon) {
-        return Err("backup directory cannot be inside source directory".to_string());
+        if backup_dir_canon.starts_with(&root.path) {
+            return Err("backup directory cannot be inside source directory".to_string());
+        }
     }
 
     Ok(ValidatedPaths {
-        source_root: source_canon,
+        roots,
         backup_dir: backup_dir_canon,
     })
 }
@@ -1,13 +1,20 @@
+use crate::archive::ArchiveReader;
+use crate::crypto::Credentials;
+use crate::signing::{self, EntryDigest};
+use crate::types::EntryKind;
 use indicatif::ProgressBar;
 use ring::digest;
 use serde::Deserialize;
-use std::fs::File;
-use std::io::{self, BufReader, Read};
+use std::io::{self, Read};
 use std::path::Path;
 
 #[derive(Debug, Deserialize)]
 struct ManifestFile {
+    #[serde(default)]
+    pub kind: EntryKind,
     pub size: u64,
+    #[serde(default)]
+    pub from_parent: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -25,103 +32,67 @@ fn print_kv<K: AsRef<str>, V: AsRef<str>>(k: K, v: V) {
     println!("  {:12} {}", format!("{}:", k.as_ref()), v.as_ref());
 }
 
-pub fn verify_backup_file(path: &Path) -> io::Result<()> {
+/// Checks every entry against its recorded hash and, for signed archives,
+/// the signature over the manifest and entry headers. With `trusted_keys`
+/// given, a missing, invalid or untrusted signature is an error.
+pub fn verify_backup_file(
+    path: &Path,
+    credentials: &Credentials,
+    trusted_keys: &[[u8; 32]],
+) -> io::Result<()> {
     println!("==================== backup verify ====================");
-    print_kv("archive", &path.to_string_lossy());
-
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
-
-    let mut magic = [0u8; 4];
-    reader.read_exact(&mut magic)?;
-    if &magic != b"BKUP" {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "invalid magic, not a .backup file",
-        ));
-    }
-
-    let mut ver_bytes = [0u8; 4];
-    reader.read_exact(&mut ver_bytes)?;
-    let version = u32::from_le_bytes(ver_bytes);
-    if version != 1 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("unsupported backup version: {version}"),
-        ));
-    }
-
-    let mut len_bytes = [0u8; 8];
-    reader.read_exact(&mut len_bytes)?;
-    let manifest_len = u64::from_le_bytes(len_bytes) as usize;
-
-    let mut manifest_bytes = vec![0u8; manifest_len];
-    reader.read_exact(&mut manifest_bytes)?;
-
-    let manifest: BackupManifest = serde_json::from_slice(&manifest_bytes).map_err(|e| {
-        io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("failed to parse embedded manifest: {e}"),
-        )
-    })?;
-
-    let total_bytes: u64 = manifest.files.iter().map(|f| f.size).sum();
+    print_kv("archive", path.to_string_lossy());
+
+    let mut archive = ArchiveReader::open(path, credentials)?;
+    let manifest: BackupManifest = archive.manifest()?;
+
+    // Files an incremental backup left in its parent have no entry here.
+    let stored = |f: &&ManifestFile| f.kind == EntryKind::File && !f.from_parent;
+    // manifest sizes are untrusted until the data is read
+    let total_bytes = manifest
+        .files
+        .iter()
+        .filter(stored)
+        .fold(0u64, |total, f| total.saturating_add(f.size));
     let pb = ProgressBar::new(total_bytes);
 
     print_section("manifest");
     print_kv("source", &manifest.source);
-    print_kv("files", manifest.files.len().to_string());
+    print_kv("entries", manifest.files.len().to_string());
     print_kv("bytes", total_bytes.to_string());
 
     let mut checked = 0usize;
     let mut ok = 0usize;
     let mut mismatched = 0usize;
+    let mut index_errors = 0usize;
+    let mut entry_digest = EntryDigest::new();
 
     print_section("verify");
-    loop {
-        let mut len_buf = [0u8; 2];
-        match reader.read_exact(&mut len_buf) {
-            Ok(()) => {}
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-            Err(e) => return Err(e),
-        }
-
-        let path_len = u16::from_le_bytes(len_buf) as usize;
-        let mut path_bytes = vec![0u8; path_len];
-        reader.read_exact(&mut path_bytes)?;
-
-        let _rel_path = match String::from_utf8(path_bytes) {
-            Ok(s) => s,
-            Err(e) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("invalid UTF-8 path in archive: {e}"),
-                ));
+    while let Some(entry) = archive.next_entry()? {
+        if let Some(index) = &archive.index {
+            match index.get(checked) {
+                Some(ie)
+                    if ie.path == entry.path && ie.size == entry.size && ie.hash == entry.hash => {}
+                _ => {
+                    eprintln!("verify: index does not match entry {}", entry.path);
+                    index_errors += 1;
+                }
             }
-        };
-
-        let mut size_buf = [0u8; 8];
-        reader.read_exact(&mut size_buf)?;
-        let size = u64::from_le_bytes(size_buf);
+        }
 
-        let mut expected_hash = [0u8; 32];
-        reader.read_exact(&mut expected_hash)?;
+        entry_digest.add(&entry.path, entry.size, &entry.hash);
+        let expected_hash = entry.hash;
 
-        let mut remaining = size;
+        let mut data = archive.entry_data(&entry);
         let mut buf = [0u8; 8192];
         let mut ctx = digest::Context::new(&digest::SHA256);
 
-        while remaining > 0 {
-            let read_len = std::cmp::min(remaining, buf.len() as u64) as usize;
-            let n = reader.read(&mut buf[..read_len])?;
+        loop {
+            let n = data.read(&mut buf)?;
             if n == 0 {
-                return Err(io::Error::new(
-                    io::ErrorKind::UnexpectedEof,
-                    "truncated file data in archive during verify",
-                ));
+                break;
             }
             ctx.update(&buf[..n]);
-            remaining -= n as u64;
             pb.inc(n as u64);
         }
 
@@ -137,18 +108,80 @@ pub fn verify_backup_file(path: &Path) -> io::Result<()> {
 
     pb.finish_with_message("verify complete");
 
-    let manifest_count = manifest.files.len();
-    if checked != manifest_count {
+    let manifest_count = manifest.files.iter().filter(stored).count();
+
+    if let Some(index) = &archive.index
+        && index.len() != checked
+    {
         eprintln!(
-            "warning: manifest lists {} files but archive contains {} entries",
-            manifest_count, checked
+            "warning: index lists {} entries but archive contains {} entries",
+            index.len(),
+            checked
         );
+        index_errors += index.len().abs_diff(checked);
     }
 
     print_section("summary");
     print_kv("checked", checked.to_string());
     print_kv("ok", ok.to_string());
     print_kv("mismatched", mismatched.to_string());
+    if archive.index.is_some() {
+        print_kv("bad index", index_errors.to_string());
+    }
+    if mismatched > 0 || index_errors > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "archive is damaged: {mismatched} entries fail their hash, {index_errors} index errors"
+            ),
+        ));
+    }
+    if checked != manifest_count {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "archive is damaged: manifest lists {manifest_count} files but archive contains {checked} entries"
+            ),
+        ));
+    }
+
+    print_section("signature");
+    let Some(sig) = &archive.signature else {
+        print_kv("signature", "none");
+        if !trusted_keys.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "archive is not signed but --trusted-key was given",
+            ));
+        }
+        return Ok(());
+    };
+
+    let valid = signing::verify(sig, &archive.manifest_bytes, &entry_digest.finish());
+    print_kv("signed by", signing::public_key_string(&sig.public_key));
+    print_kv("signature", if valid { "valid" } else { "INVALID" });
+
+    if trusted_keys.is_empty() {
+        print_kv("trust", "not checked, no --trusted-key given");
+        if !valid {
+            eprintln!("warning: archive signature does not match its contents");
+        }
+        return Ok(());
+    }
+
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "archive signature is invalid: manifest or entries were modified after signing",
+        ));
+    }
+    if !trusted_keys.contains(&sig.public_key) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "archive is signed by a key that is not trusted",
+        ));
+    }
+    print_kv("trust", "trusted key");
 
     Ok(())
 }
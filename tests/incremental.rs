@@ -0,0 +1,147 @@
+//! Restores chains of incremental and differential archives written by the
+//! real binary.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::thread;
+use std::time::Duration;
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("incremental")
+        .join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn backup(args: &[&Path]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_backup"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Backs up `source` to `out` with `extra` flags and returns the new
+/// archive. Archive names have a resolution of one second, so it waits for
+/// the next second first.
+fn create(source: &Path, out: &Path, extra: &[&Path]) -> PathBuf {
+    thread::sleep(Duration::from_millis(1100));
+    let before: Vec<PathBuf> = archives(out);
+    let output = backup(&[&[source, out], extra].concat());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    let mut new: Vec<PathBuf> = archives(out)
+        .into_iter()
+        .filter(|a| !before.contains(a))
+        .collect();
+    assert_eq!(new.len(), 1, "expected one new archive in {out:?}");
+    new.pop().unwrap()
+}
+
+fn archives(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .map(|d| d.unwrap().path())
+                .filter(|p| p.extension().is_some_and(|e| e == "backup"))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn restore(archive: &Path, dest: &Path, extra: &[&Path]) -> Output {
+    backup(&[&[Path::new("restore"), archive, dest], extra].concat())
+}
+
+/// Every regular file below `dir` with its contents, by relative path.
+fn tree(dir: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                let rel = path
+                    .strip_prefix(dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned();
+                files.push((rel, fs::read(&path).unwrap()));
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+#[test]
+fn incremental_chain_restores_the_latest_tree() {
+    let dir = scratch_dir("chain");
+    let source = dir.join("source");
+    let out = dir.join("out");
+    fs::create_dir_all(source.join("docs")).unwrap();
+    fs::create_dir_all(&out).unwrap();
+    fs::write(source.join("kept.txt"), "never changes").unwrap();
+    fs::write(source.join("docs/report.txt"), "first draft").unwrap();
+    fs::write(source.join("docs/old.txt"), "soon deleted").unwrap();
+    fs::write(source.join("notes.txt"), "v1").unwrap();
+    let full = create(&source, &out, &[]);
+    let full_tree = tree(&source);
+
+    fs::write(source.join("docs/report.txt"), "second, longer draft").unwrap();
+    fs::remove_file(source.join("docs/old.txt")).unwrap();
+    fs::write(source.join("added.txt"), "new in the first increment").unwrap();
+    create(&source, &out, &[Path::new("--incremental")]);
+
+    fs::remove_file(source.join("notes.txt")).unwrap();
+    fs::write(source.join("added.txt"), "changed in the second increment").unwrap();
+    let last = create(&source, &out, &[Path::new("--incremental")]);
+
+    let restored = dir.join("restored");
+    let output = restore(&last, &restored, &[]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(tree(&restored), tree(&source));
+    assert!(!restored.join("docs/old.txt").exists());
+    assert!(!restored.join("notes.txt").exists());
+
+    // the full backup still restores the tree it recorded
+    let restored = dir.join("restored-full");
+    assert!(restore(&full, &restored, &[]).status.success());
+    assert_eq!(tree(&restored), full_tree);
+}
+
+#[test]
+fn differential_is_refused_with_another_base() {
+    let dir = scratch_dir("wrong_base");
+    let source = dir.join("source");
+    let out = dir.join("out");
+    fs::create_dir_all(&source).unwrap();
+    fs::create_dir_all(&out).unwrap();
+    fs::write(source.join("a.txt"), "first").unwrap();
+    let base = create(&source, &out, &[]);
+
+    fs::write(source.join("b.txt"), "second").unwrap();
+    let differential = create(&source, &out, &[Path::new("--differential-from"), &base]);
+    let other = create(&source, &out, &[]);
+
+    let restored = dir.join("restored");
+    let output = restore(&differential, &restored, &[Path::new("--base"), &other]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{stderr}");
+    assert!(stderr.contains("manifest hash differs"), "{stderr}");
+
+    // nor is a different archive accepted under the base's name
+    fs::rename(&other, &base).unwrap();
+    let output = restore(&differential, &restored, &[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{stderr}");
+    assert!(stderr.contains("manifest hash differs"), "{stderr}");
+}
@@ -0,0 +1,512 @@
+//! Restores hand-crafted archives whose entry paths or hard links try to
+//! escape the restore directory, or whose sizes do not match their data, and
+//! checks that nothing is written outside of it.
+
+use ring::digest;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, symlink};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("malicious_archives")
+        .join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Archive formats the fixtures are written in: the original streamed
+/// layout and the current one with the manifest and index after the data.
+const VERSIONS: [u32; 2] = [1, 4];
+
+/// An entry as a hand-crafted archive records it.
+struct Entry<'a> {
+    path: &'a str,
+    data: &'a [u8],
+    /// size recorded for the entry, normally the length of `data`
+    size: u64,
+    /// first path of a hard link entry, which has no data of its own
+    link: Option<&'a str>,
+    /// compression codec byte of a current format entry, `data` being the
+    /// stored blocks
+    codec: u8,
+}
+
+fn file<'a>(path: &'a str, data: &'a [u8]) -> Entry<'a> {
+    Entry {
+        path,
+        data,
+        size: data.len() as u64,
+        link: None,
+        codec: 0,
+    }
+}
+
+fn hard_link<'a>(path: &'a str, first: &'a str) -> Entry<'a> {
+    Entry {
+        path,
+        data: b"",
+        size: 0,
+        link: Some(first),
+        codec: 0,
+    }
+}
+
+/// Writes an archive of format `version` containing `entries` verbatim,
+/// without any of the checks the real writer would apply.
+fn write_archive(path: &Path, version: u32, entries: &[Entry]) {
+    write_archive_from(path, version, Path::new("/nowhere"), entries);
+}
+
+/// Like [`write_archive`], for an archive that claims to be a backup of
+/// `source`.
+fn write_archive_from(path: &Path, version: u32, source: &Path, entries: &[Entry]) {
+    let hash = |data: &[u8]| digest::digest(&digest::SHA256, data);
+    let files: Vec<serde_json::Value> = entries
+        .iter()
+        .map(|e| match e.link {
+            Some(first) => serde_json::json!({
+                "path": e.path, "kind": "hard_link", "size": 0, "target": first,
+            }),
+            None => serde_json::json!({
+                "path": e.path,
+                "size": e.size,
+                "sha256": hash(e.data).as_ref().iter().map(|b| format!("{b:02x}")).collect::<String>(),
+            }),
+        })
+        .collect();
+    let manifest = serde_json::json!({
+        "source": source,
+        "backup_file": path.to_string_lossy(),
+        "created_at": 0,
+        "files": files,
+    })
+    .to_string();
+    let stored = entries.iter().filter(|e| e.link.is_none());
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"BKUP");
+    out.extend_from_slice(&version.to_le_bytes());
+    if version == 1 {
+        out.extend_from_slice(&(manifest.len() as u64).to_le_bytes());
+        out.extend_from_slice(manifest.as_bytes());
+        for e in stored {
+            out.extend_from_slice(&(e.path.len() as u16).to_le_bytes());
+            out.extend_from_slice(e.path.as_bytes());
+            out.extend_from_slice(&e.size.to_le_bytes());
+            out.extend_from_slice(hash(e.data).as_ref());
+            out.extend_from_slice(e.data);
+        }
+        fs::write(path, out).unwrap();
+        return;
+    }
+
+    // entries stored raw, then the manifest, the index and the trailer
+    let mut index = Vec::new();
+    let mut count = 0u64;
+    for e in stored {
+        index.extend_from_slice(&(e.path.len() as u16).to_le_bytes());
+        index.extend_from_slice(e.path.as_bytes());
+        index.extend_from_slice(&(out.len() as u64).to_le_bytes());
+        index.extend_from_slice(&e.size.to_le_bytes());
+        index.extend_from_slice(hash(e.data).as_ref());
+        count += 1;
+
+        out.extend_from_slice(&(e.path.len() as u16).to_le_bytes());
+        out.extend_from_slice(e.path.as_bytes());
+        out.extend_from_slice(&e.size.to_le_bytes());
+        out.push(e.codec);
+        out.extend_from_slice(e.data);
+    }
+    let index_offset = out.len() as u64;
+    out.extend_from_slice(&(manifest.len() as u64).to_le_bytes());
+    out.extend_from_slice(manifest.as_bytes());
+    out.extend_from_slice(&index);
+    out.extend_from_slice(&index_offset.to_le_bytes());
+    out.extend_from_slice(&count.to_le_bytes());
+    out.extend_from_slice(b"BKIX");
+
+    fs::write(path, out).unwrap();
+}
+
+fn restore(archive: &Path, dest: &Path, extra: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_backup"))
+        .arg("restore")
+        .arg(archive)
+        .arg(dest)
+        .args(extra)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    // a restore that stops early or leaves entries failed or mismatched
+    // exits non-zero
+    if !extra.contains(&"--dry-run") {
+        let clean = stdout.contains("--- summary ---")
+            && summary_count(&stdout, "failed") == 0
+            && summary_count(&stdout, "mismatched") == 0;
+        assert_eq!(output.status.success(), clean, "{stdout}");
+    }
+    stdout
+}
+
+fn summary_count(stdout: &str, key: &str) -> usize {
+    let prefix = format!("{key}:");
+    stdout
+        .lines()
+        .map(str::trim)
+        .find_map(|l| l.strip_prefix(&prefix))
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or_else(|| panic!("no {key} in output:\n{stdout}"))
+}
+
+/// A scratch directory for the fixture of `name` in format `version`.
+fn fixture_dir(name: &str, version: u32) -> PathBuf {
+    scratch_dir(&format!("{name}_v{version}"))
+}
+
+#[test]
+fn rejects_parent_dir_components() {
+    for version in VERSIONS {
+        let dir = fixture_dir("parent_dir", version);
+        let archive = dir.join("evil.backup");
+        let dest = dir.join("a/b/restore");
+        write_archive(
+            &archive,
+            version,
+            &[
+                file("../escape.txt", b"pwned"),
+                file("ok/../../../nested-escape.txt", b"pwned"),
+                file("good.txt", b"fine"),
+            ],
+        );
+
+        let out = restore(&archive, &dest, &[]);
+
+        assert_eq!(summary_count(&out, "restored"), 1);
+        assert_eq!(summary_count(&out, "failed"), 2);
+        assert!(!dir.join("a/b/escape.txt").exists());
+        assert!(!dir.join("a/nested-escape.txt").exists());
+        assert_eq!(fs::read(dest.join("good.txt")).unwrap(), b"fine");
+    }
+}
+
+#[test]
+fn rejects_absolute_paths() {
+    for version in VERSIONS {
+        let dir = fixture_dir("absolute", version);
+        let archive = dir.join("evil.backup");
+        let dest = dir.join("restore");
+        let target = dir.join("absolute.txt");
+        let target_str = target.to_string_lossy().into_owned();
+        write_archive(&archive, version, &[file(&target_str, b"pwned")]);
+
+        let out = restore(&archive, &dest, &[]);
+
+        assert_eq!(summary_count(&out, "failed"), 1);
+        assert!(!target.exists());
+    }
+}
+
+#[test]
+fn rejects_symlinked_intermediate_directory() {
+    for version in VERSIONS {
+        let dir = fixture_dir("symlink_dir", version);
+        let archive = dir.join("evil.backup");
+        let dest = dir.join("restore");
+        let outside = dir.join("outside");
+        fs::create_dir_all(&dest).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        symlink(&outside, dest.join("link")).unwrap();
+        write_archive(&archive, version, &[file("link/pwned.txt", b"pwned")]);
+
+        let out = restore(&archive, &dest, &[]);
+
+        assert_eq!(summary_count(&out, "failed"), 1);
+        assert!(!outside.join("pwned.txt").exists());
+    }
+}
+
+#[test]
+fn rejects_symlink_at_entry_path() {
+    for version in VERSIONS {
+        let dir = fixture_dir("symlink_file", version);
+        let archive = dir.join("evil.backup");
+        let dest = dir.join("restore");
+        let victim = dir.join("victim.txt");
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(&victim, b"original").unwrap();
+        symlink(&victim, dest.join("file.txt")).unwrap();
+        write_archive(&archive, version, &[file("file.txt", b"pwned")]);
+
+        let out = restore(&archive, &dest, &[]);
+
+        assert_eq!(summary_count(&out, "failed"), 1);
+        assert_eq!(fs::read(&victim).unwrap(), b"original");
+    }
+}
+
+#[test]
+fn rejects_hard_link_outside_restore_dir() {
+    for version in VERSIONS {
+        let dir = fixture_dir("hard_link_path", version);
+        let archive = dir.join("evil.backup");
+        let dest = dir.join("restore");
+        write_archive(
+            &archive,
+            version,
+            &[
+                file("data.txt", b"linked"),
+                hard_link("../escape.txt", "data.txt"),
+            ],
+        );
+
+        let out = restore(&archive, &dest, &[]);
+
+        assert_eq!(summary_count(&out, "restored"), 1);
+        assert_eq!(summary_count(&out, "failed"), 1);
+        assert!(!dir.join("escape.txt").exists());
+        assert_eq!(fs::read(dest.join("data.txt")).unwrap(), b"linked");
+    }
+}
+
+#[test]
+fn rejects_hard_link_to_file_outside_restore_dir() {
+    for version in VERSIONS {
+        let dir = fixture_dir("hard_link_target", version);
+        let archive = dir.join("evil.backup");
+        let dest = dir.join("restore");
+        let victim = dir.join("victim.txt");
+        fs::write(&victim, b"secret").unwrap();
+        let absolute = victim.to_string_lossy().into_owned();
+        write_archive(
+            &archive,
+            version,
+            &[
+                hard_link("relative.txt", "../victim.txt"),
+                hard_link("absolute.txt", &absolute),
+            ],
+        );
+
+        let out = restore(&archive, &dest, &[]);
+
+        assert_eq!(summary_count(&out, "failed"), 2);
+        assert!(!dest.join("relative.txt").exists());
+        assert!(!dest.join("absolute.txt").exists());
+        assert_eq!(fs::metadata(&victim).unwrap().nlink(), 1);
+    }
+}
+
+#[test]
+fn hard_link_through_symlinked_directory_is_refused() {
+    for version in VERSIONS {
+        let dir = fixture_dir("hard_link_symlink", version);
+        let archive = dir.join("evil.backup");
+        let dest = dir.join("restore");
+        let outside = dir.join("outside");
+        fs::create_dir_all(&dest).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("victim.txt"), b"secret").unwrap();
+        symlink(&outside, dest.join("link")).unwrap();
+        write_archive(
+            &archive,
+            version,
+            &[
+                file("data.txt", b"linked"),
+                hard_link("link/pwned.txt", "data.txt"),
+                hard_link("stolen.txt", "link/victim.txt"),
+            ],
+        );
+
+        let out = restore(&archive, &dest, &[]);
+
+        assert_eq!(summary_count(&out, "failed"), 2);
+        assert!(!outside.join("pwned.txt").exists());
+        assert!(!dest.join("stolen.txt").exists());
+    }
+}
+
+#[test]
+fn oversized_entry_is_not_restored() {
+    for version in VERSIONS {
+        let dir = fixture_dir("oversized", version);
+        let archive = dir.join("evil.backup");
+        let dest = dir.join("restore");
+        let huge = Entry {
+            size: u64::MAX,
+            ..file("huge.bin", b"short data")
+        };
+        write_archive(
+            &archive,
+            version,
+            &[file("good.txt", b"fine"), huge, file("after.txt", b"fine")],
+        );
+
+        let out = restore(&archive, &dest, &[]);
+
+        // Nothing past the entry's data is read into it, and only an index
+        // leads past it to the next entry.
+        assert!(!dest.join("huge.bin").exists());
+        assert_eq!(fs::read(dest.join("good.txt")).unwrap(), b"fine");
+        if version >= 2 {
+            assert_eq!(summary_count(&out, "restored"), 2);
+            assert_eq!(summary_count(&out, "failed"), 1);
+            assert_eq!(fs::read(dest.join("after.txt")).unwrap(), b"fine");
+        }
+
+        for (command, succeeds) in [("verify", false), ("inspect", true)] {
+            let output = Command::new(env!("CARGO_BIN_EXE_backup"))
+                .arg(command)
+                .arg(&archive)
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(!stderr.contains("panicked"), "{command}:\n{stderr}");
+            assert_eq!(output.status.success(), succeeds, "{command}:\n{stderr}");
+        }
+    }
+}
+
+#[test]
+fn unreadable_entry_data_fails_only_that_entry() {
+    let dir = fixture_dir("corrupt_block", 4);
+    let archive = dir.join("evil.backup");
+    let dest = dir.join("restore");
+    // one zstd block of 64 bytes whose stored data is not zstd at all
+    let mut block = Vec::new();
+    block.extend_from_slice(&64u32.to_le_bytes());
+    block.extend_from_slice(&8u32.to_le_bytes());
+    block.extend_from_slice(b"garbage!");
+    let broken = Entry {
+        size: 64,
+        codec: 1,
+        ..file("broken.bin", &block)
+    };
+    write_archive(
+        &archive,
+        4,
+        &[
+            file("good.txt", b"fine"),
+            broken,
+            file("after.txt", b"fine"),
+        ],
+    );
+
+    let out = restore(&archive, &dest, &[]);
+
+    assert_eq!(summary_count(&out, "restored"), 2);
+    assert_eq!(summary_count(&out, "failed"), 1);
+    assert!(!dest.join("broken.bin").exists());
+    assert_eq!(fs::read(dest.join("after.txt")).unwrap(), b"fine");
+}
+
+#[test]
+fn unreadable_archive_fails_with_non_zero_status() {
+    let dir = scratch_dir("unreadable");
+    let archive = dir.join("garbage.backup");
+    fs::write(&archive, b"not a backup archive").unwrap();
+
+    let out = restore(&archive, &dir.join("restore"), &[]);
+    assert!(!out.contains("--- summary ---"), "{out}");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_backup"))
+        .arg("inspect")
+        .arg(&archive)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn dry_run_refuses_unsafe_paths_without_looking_at_them() {
+    for version in VERSIONS {
+        let dir = fixture_dir("dry_run", version);
+        let archive = dir.join("evil.backup");
+        let dest = dir.join("restore");
+        let outside = dir.join("outside");
+        fs::create_dir_all(&dest).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret.txt"), b"secret").unwrap();
+        symlink(&outside, dest.join("link")).unwrap();
+        let absolute = outside.join("secret.txt").to_string_lossy().into_owned();
+        write_archive(
+            &archive,
+            version,
+            &[
+                file("../outside/secret.txt", b"secret"),
+                file(&absolute, b"secret"),
+                file("link/secret.txt", b"secret"),
+                hard_link("stolen.txt", "../outside/secret.txt"),
+                file("good.txt", b"fine"),
+            ],
+        );
+
+        let out = restore(&archive, &dest, &["--dry-run", "--json"]);
+        let report: serde_json::Value = serde_json::from_str(&out).unwrap();
+
+        let entries = report["entries"].as_array().unwrap();
+        let (refused, rest) = entries.split_at(4);
+        for entry in refused {
+            assert_eq!(entry["action"], "refused", "{entry}");
+            assert!(entry["reason"].is_string(), "{entry}");
+            // nothing about the file outside the target is reported
+            for key in ["destination", "existing_size", "identical"] {
+                assert!(entry.get(key).is_none(), "{key} in {entry}");
+            }
+        }
+        assert_eq!(rest[0]["action"], "create");
+        assert_eq!(report["refused"], 4);
+        assert_eq!(report["create"], 1);
+        assert!(!dest.join("good.txt").exists());
+    }
+}
+
+#[test]
+fn preview_counts_unsafe_paths_as_refused() {
+    for version in VERSIONS {
+        let dir = fixture_dir("preview", version);
+        let archive = dir.join("evil.backup");
+        let original = dir.join("original");
+        fs::create_dir_all(&original).unwrap();
+        fs::write(dir.join("victim.txt"), b"original").unwrap();
+        fs::write(original.join("good.txt"), b"old").unwrap();
+        write_archive_from(
+            &archive,
+            version,
+            &original,
+            &[file("../victim.txt", b"pwned"), file("good.txt", b"fine")],
+        );
+
+        // Without a terminal the restore stops at the confirmation.
+        let output = Command::new(env!("CARGO_BIN_EXE_backup"))
+            .arg("restore")
+            .arg(&archive)
+            .arg("--to-original")
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        let out = String::from_utf8_lossy(&output.stdout);
+
+        assert_eq!(summary_count(&out, "new"), 0);
+        assert_eq!(summary_count(&out, "overwritten"), 1);
+        assert_eq!(summary_count(&out, "refused"), 1);
+        assert_eq!(fs::read(dir.join("victim.txt")).unwrap(), b"original");
+        assert_eq!(fs::read(original.join("good.txt")).unwrap(), b"old");
+    }
+}
+
+#[test]
+fn allow_unsafe_paths_restores_verbatim() {
+    for version in VERSIONS {
+        let dir = fixture_dir("allow_unsafe", version);
+        let archive = dir.join("forensic.backup");
+        let dest = dir.join("restore");
+        write_archive(&archive, version, &[file("../escape.txt", b"kept")]);
+
+        let out = restore(&archive, &dest, &["--allow-unsafe-paths"]);
+
+        assert_eq!(summary_count(&out, "restored"), 1);
+        assert_eq!(fs::read(dir.join("escape.txt")).unwrap(), b"kept");
+    }
+}
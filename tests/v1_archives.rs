@@ -0,0 +1,114 @@
+//! Reads archives in the original streamed format, version 1, which has the
+//! manifest up front and neither index nor trailer, with the commands that
+//! must keep accepting them.
+
+use ring::digest;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const FILES: [(&str, &[u8]); 3] = [
+    ("notes.txt", b"written before the index existed"),
+    ("docs/report.txt", b"a nested file"),
+    ("empty.txt", b""),
+];
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("v1_archives")
+        .join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn backup(args: &[&Path]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_backup"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(digest::digest(&digest::SHA256, data).as_ref());
+    hash
+}
+
+/// Writes `FILES` as a version 1 archive, the way the first release did.
+fn write_v1_archive(path: &Path) {
+    let files: Vec<serde_json::Value> = FILES
+        .iter()
+        .map(|(name, data)| {
+            serde_json::json!({
+                "path": name,
+                "size": data.len(),
+                "sha256": sha256(data).iter().map(|b| format!("{b:02x}")).collect::<String>(),
+            })
+        })
+        .collect();
+    let manifest = serde_json::json!({
+        "source": "/home/user/documents",
+        "backup_file": path.to_string_lossy(),
+        "created_at": 1_600_000_000,
+        "files": files,
+    })
+    .to_string();
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"BKUP");
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&(manifest.len() as u64).to_le_bytes());
+    out.extend_from_slice(manifest.as_bytes());
+    for (name, data) in FILES {
+        out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        out.extend_from_slice(&sha256(data));
+        out.extend_from_slice(data);
+    }
+    fs::write(path, out).unwrap();
+}
+
+fn assert_success(output: &Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn inspect_restore_and_verify_read_v1_archives() {
+    let dir = scratch_dir("read");
+    let archive = dir.join("documents-1600000000.backup");
+    write_v1_archive(&archive);
+
+    let stdout = assert_success(&backup(&[Path::new("inspect"), &archive]));
+    for (name, _) in FILES {
+        assert!(stdout.contains(name), "{name} not listed:\n{stdout}");
+    }
+
+    assert_success(&backup(&[Path::new("verify"), &archive]));
+
+    let restored = dir.join("restored");
+    assert_success(&backup(&[Path::new("restore"), &archive, &restored]));
+    for (name, data) in FILES {
+        assert_eq!(fs::read(restored.join(name)).unwrap(), data, "{name}");
+    }
+}
+
+#[test]
+fn damaged_v1_archive_fails_verification() {
+    let dir = scratch_dir("damaged");
+    let archive = dir.join("documents-1600000000.backup");
+    write_v1_archive(&archive);
+    let mut bytes = fs::read(&archive).unwrap();
+    let at = bytes
+        .windows(FILES[1].1.len())
+        .position(|w| w == FILES[1].1)
+        .unwrap();
+    bytes[at] ^= 0x01;
+    fs::write(&archive, bytes).unwrap();
+
+    let output = backup(&[Path::new("verify"), &archive]);
+    assert!(!output.status.success());
+}
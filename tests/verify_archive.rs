@@ -0,0 +1,165 @@
+//! Verifies archives written by the real binary after damaging them, and
+//! checks that the damage is reported as a failure.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("verify_archive")
+        .join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn backup(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_backup"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// The only archive in `dir`.
+fn archive_in(dir: &Path) -> PathBuf {
+    let archives: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|d| d.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "backup"))
+        .collect();
+    assert_eq!(archives.len(), 1, "expected one archive in {dir:?}");
+    archives.into_iter().next().unwrap()
+}
+
+#[test]
+fn trusted_key_does_not_hide_damaged_entry_data() {
+    let dir = scratch_dir("flipped_byte");
+    let source = dir.join("source");
+    let out = dir.join("out");
+    fs::create_dir_all(&source).unwrap();
+    fs::create_dir_all(&out).unwrap();
+    let content = b"entry data that is about to be damaged";
+    fs::write(source.join("file.txt"), content).unwrap();
+
+    let key = dir.join("sign.key");
+    let keygen = backup(&["keygen", "--sign", key.to_str().unwrap()]);
+    let public = String::from_utf8(keygen.stdout).unwrap().trim().to_string();
+    assert!(!public.is_empty(), "keygen printed no public key");
+
+    let created = backup(&[
+        source.to_str().unwrap(),
+        out.to_str().unwrap(),
+        "--compress",
+        "none",
+        "--sign-key",
+        key.to_str().unwrap(),
+    ]);
+    assert!(created.status.success());
+    let archive = archive_in(&out);
+
+    let verify = |archive: &Path| {
+        backup(&[
+            "verify",
+            archive.to_str().unwrap(),
+            "--trusted-key",
+            public.as_str(),
+        ])
+    };
+    assert!(verify(&archive).status.success());
+
+    let mut bytes = fs::read(&archive).unwrap();
+    assert_eq!(&bytes[..4], b"BKUP");
+    assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 4);
+    let at = bytes
+        .windows(content.len())
+        .position(|w| w == content)
+        .expect("entry data not found in archive");
+    bytes[at] ^= 0x01;
+    fs::write(&archive, bytes).unwrap();
+
+    let damaged = verify(&archive);
+    assert!(!damaged.status.success());
+    let stdout = String::from_utf8_lossy(&damaged.stdout);
+    assert!(!stdout.contains("trusted key"), "trust reported:\n{stdout}");
+}
+
+#[test]
+fn dropped_entry_fails_verification() {
+    let dir = scratch_dir("dropped_entry");
+    let source = dir.join("source");
+    let out = dir.join("out");
+    fs::create_dir_all(&source).unwrap();
+    fs::create_dir_all(&out).unwrap();
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        fs::write(source.join(name), format!("contents of {name}")).unwrap();
+    }
+
+    let created = backup(&[
+        source.to_str().unwrap(),
+        out.to_str().unwrap(),
+        "--compress",
+        "none",
+    ]);
+    assert!(created.status.success());
+    let archive = archive_in(&out);
+    let verify = |archive: &Path| backup(&["verify", archive.to_str().unwrap()]);
+    assert!(verify(&archive).status.success());
+
+    // Remove the entry of b.txt along with its index record, keeping the
+    // index consistent with the remaining entries, so only the manifest
+    // still lists the file.
+    let bytes = fs::read(&archive).unwrap();
+    let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+    let trailer = bytes.len() - 20;
+    assert_eq!(&bytes[trailer + 16..], b"BKIX");
+    let index_offset = u64_at(trailer) as usize;
+    let count = u64_at(trailer + 8);
+    let index_start = index_offset + 8 + u64_at(index_offset) as usize;
+
+    let mut records = Vec::new();
+    let mut at = index_start;
+    for _ in 0..count {
+        let len = u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap()) as usize;
+        let path = String::from_utf8(bytes[at + 2..at + 2 + len].to_vec()).unwrap();
+        records.push((path, at, at + 2 + len + 48));
+        at += 2 + len + 48;
+    }
+    assert_eq!(at, trailer, "archive is signed or has an unexpected layout");
+
+    let dropped = records.iter().position(|(p, ..)| p == "b.txt").unwrap();
+    let entry_offset = |i: usize| u64_at(records[i].1 + 2 + records[i].0.len()) as usize;
+    let start = entry_offset(dropped);
+    let end = records
+        .get(dropped + 1)
+        .map_or(index_offset, |_| entry_offset(dropped + 1));
+    let removed = (end - start) as u64;
+
+    let mut damaged = Vec::new();
+    damaged.extend_from_slice(&bytes[..start]);
+    damaged.extend_from_slice(&bytes[end..index_start]);
+    for (i, (path, from, to)) in records.iter().enumerate() {
+        if i == dropped {
+            continue;
+        }
+        let mut record = bytes[*from..*to].to_vec();
+        let offset_at = 2 + path.len();
+        let mut offset = entry_offset(i) as u64;
+        if i > dropped {
+            offset -= removed;
+        }
+        record[offset_at..offset_at + 8].copy_from_slice(&offset.to_le_bytes());
+        damaged.extend_from_slice(&record);
+    }
+    damaged.extend_from_slice(&(index_offset as u64 - removed).to_le_bytes());
+    damaged.extend_from_slice(&(count - 1).to_le_bytes());
+    damaged.extend_from_slice(b"BKIX");
+    fs::write(&archive, damaged).unwrap();
+
+    let output = verify(&archive);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{stderr}");
+    assert!(stderr.contains("manifest lists 3 files"), "{stderr}");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("bad index:   0"), "{stdout}");
+}